@@ -1,4 +1,4 @@
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    tonic_build::compile_protos("proto/service.proto")?;
+    tonic_build::configure().compile_protos(&["proto/service.proto", "proto/pprof.proto"], &["proto"])?;
     Ok(())
-} 
\ No newline at end of file
+}
@@ -0,0 +1,1058 @@
+//! Embeddable continuous profiling agent
+//!
+//! Services link `profiling` as a library and start an agent once:
+//!
+//! ```no_run
+//! use profiling::agent::Agent;
+//!
+//! let agent = Agent::builder()
+//!     .server("http://[::1]:50051")
+//!     .service("checkout")
+//!     .label("version", "1.2.3")
+//!     .start()
+//!     .unwrap();
+//! // ... run the service ...
+//! agent.shutdown();
+//! ```
+//!
+//! Work can be tagged with labels for the duration of a block, and the
+//! resulting flame graphs split or filtered by them on the server:
+//!
+//! ```no_run
+//! profiling::agent::with_labels(&[("endpoint", "/checkout")], || {
+//!     // ... handle the request ...
+//! });
+//! ```
+//!
+//! See [`labels`](crate::labels) for how labels are attributed to samples.
+//!
+//! A sampler thread rotates a `ProfilerGuard` every interval and queues each
+//! window. With [`ProfileMode::Wall`] it rotates a
+//! [`WallClockGuard`](crate::wallclock::WallClockGuard) instead, which also
+//! samples threads that are blocked or sleeping. An uploader thread drains
+//! the queue over a single connection, sending the windows waiting in it
+//! together in one `HandleBatch` call and retrying calls that failed for
+//! transient reasons with exponential backoff. Windows too large for one
+//! message are streamed on their own. `shutdown` stops sampling, ships the
+//! final partial window and waits for the queue to flush.
+
+use crate::labels::Recording;
+use crate::metadata::{default_instance_id, ProfileMetadata};
+use crate::myservice::my_service_client::MyServiceClient;
+use crate::myservice::{BatchRequest, BatchResult, RequestV2};
+use crate::upload::{self, DEFAULT_CHUNK_SIZE};
+use crate::wallclock::{WallClockError, WallClockGuard};
+use pprof::protos::{Message, Profile};
+use pprof::ProfilerGuard;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use tonic::codec::CompressionEncoding;
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tonic::transport::Channel;
+
+pub use crate::labels::{with_labels, LabelGuard};
+
+/// Errors raised while starting the agent or uploading a profile
+#[derive(Debug)]
+pub enum AgentError {
+    /// The agent was built without a server or service name
+    Config(String),
+    /// The pprof profiler could not be started or report generation failed
+    Profiler(pprof::Error),
+    /// The wall-clock profiler could not be started
+    WallClock(WallClockError),
+    /// Connecting to the server failed
+    Transport(tonic::transport::Error),
+    /// The server rejected the upload
+    Rpc(Box<tonic::Status>),
+    /// A background thread or runtime could not be created
+    Io(std::io::Error),
+}
+
+impl fmt::Display for AgentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AgentError::Config(msg) => write!(f, "invalid agent configuration: {}", msg),
+            AgentError::Profiler(e) => write!(f, "profiler error: {}", e),
+            AgentError::WallClock(e) => write!(f, "wall-clock profiler error: {}", e),
+            AgentError::Transport(e) => write!(f, "transport error: {}", e),
+            AgentError::Rpc(status) => write!(f, "upload rejected: {}", status),
+            AgentError::Io(e) => write!(f, "io error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for AgentError {}
+
+impl AgentError {
+    /// Whether the upload may succeed if tried again: the server couldn't
+    /// be reached or was temporarily unable to take the profile
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            AgentError::Transport(_) => true,
+            AgentError::Rpc(status) => matches!(
+                status.code(),
+                tonic::Code::Unavailable | tonic::Code::DeadlineExceeded | tonic::Code::ResourceExhausted
+            ),
+            _ => false,
+        }
+    }
+}
+
+impl From<pprof::Error> for AgentError {
+    fn from(e: pprof::Error) -> Self {
+        AgentError::Profiler(e)
+    }
+}
+
+impl From<WallClockError> for AgentError {
+    fn from(e: WallClockError) -> Self {
+        AgentError::WallClock(e)
+    }
+}
+
+impl From<tonic::transport::Error> for AgentError {
+    fn from(e: tonic::transport::Error) -> Self {
+        AgentError::Transport(e)
+    }
+}
+
+impl From<tonic::Status> for AgentError {
+    fn from(status: tonic::Status) -> Self {
+        AgentError::Rpc(Box::new(status))
+    }
+}
+
+impl From<std::io::Error> for AgentError {
+    fn from(e: std::io::Error) -> Self {
+        AgentError::Io(e)
+    }
+}
+
+/// Connect to the profile server
+///
+/// Requests are zstd-compressed; compressed responses are accepted in
+/// either gzip or zstd.
+pub async fn connect(server: impl Into<String>) -> Result<MyServiceClient<Channel>, AgentError> {
+    let client = MyServiceClient::connect(server.into()).await?
+        .send_compressed(CompressionEncoding::Zstd)
+        .accept_compressed(CompressionEncoding::Zstd)
+        .accept_compressed(CompressionEncoding::Gzip);
+    Ok(client)
+}
+
+/// Upload a single profile with its metadata and return the stored profile ID
+///
+/// The profile is streamed in chunks through `UploadProfile`, so its size
+/// isn't bound by the gRPC message limit. Servers that predate that RPC get
+/// a single `HandleRequestV2` call instead.
+pub async fn upload_profile(
+    client: &mut MyServiceClient<Channel>,
+    profile: &Profile,
+    metadata: ProfileMetadata,
+) -> Result<String, AgentError> {
+    let metadata = metadata.with_profile_defaults(profile);
+    upload_encoded(client, profile.encode_to_vec(), metadata).await
+}
+
+/// Upload encoded pprof data like [`upload_profile`]
+async fn upload_encoded(
+    client: &mut MyServiceClient<Channel>,
+    data: Vec<u8>,
+    metadata: ProfileMetadata,
+) -> Result<String, AgentError> {
+    let data: Arc<[u8]> = data.into();
+    let chunks = upload::chunks(data.clone(), metadata.clone(), DEFAULT_CHUNK_SIZE);
+    let response = match client.upload_profile(tokio_stream::iter(chunks)).await {
+        Err(status) if status.code() == tonic::Code::Unimplemented => {
+            log::debug!("Server doesn't support streamed uploads, sending a single request");
+            let request = RequestV2 {
+                data: data.to_vec(),
+                metadata: Some(metadata.into()),
+            };
+            client.handle_request_v2(request).await?
+        }
+        response => response?,
+    };
+    Ok(String::from_utf8_lossy(&response.into_inner().result).to_string())
+}
+
+/// What a profiling window samples
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ProfileMode {
+    /// On-CPU time via pprof's `SIGPROF` sampling
+    #[default]
+    Cpu,
+    /// Wall-clock time of every thread, including blocked ones
+    Wall,
+}
+
+/// Builder for [`Agent`]
+#[derive(Debug, Clone)]
+pub struct AgentBuilder {
+    server: Option<String>,
+    service: Option<String>,
+    instance_id: Option<String>,
+    labels: BTreeMap<String, String>,
+    mode: ProfileMode,
+    frequency: i32,
+    interval: Duration,
+    batch_size: usize,
+    queue_capacity: usize,
+    max_retries: u32,
+    retry_backoff: Duration,
+    paused: bool,
+}
+
+impl Default for AgentBuilder {
+    fn default() -> Self {
+        AgentBuilder {
+            server: None,
+            service: None,
+            instance_id: None,
+            labels: BTreeMap::new(),
+            mode: ProfileMode::Cpu,
+            frequency: 100,
+            interval: Duration::from_secs(10),
+            batch_size: 8,
+            queue_capacity: 64,
+            max_retries: 3,
+            retry_backoff: Duration::from_millis(500),
+            paused: false,
+        }
+    }
+}
+
+impl AgentBuilder {
+    /// gRPC address of the profile server, e.g. `http://[::1]:50051`
+    pub fn server(mut self, url: impl Into<String>) -> Self {
+        self.server = Some(url.into());
+        self
+    }
+
+    /// Service name recorded with every profile
+    pub fn service(mut self, name: impl Into<String>) -> Self {
+        self.service = Some(name.into());
+        self
+    }
+
+    /// Instance identifier, defaults to the host or pod name
+    pub fn instance_id(mut self, id: impl Into<String>) -> Self {
+        self.instance_id = Some(id.into());
+        self
+    }
+
+    /// Add a label recorded with every profile
+    pub fn label(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.labels.insert(key.into(), value.into());
+        self
+    }
+
+    /// Profile on-CPU or wall-clock time, defaults to CPU
+    pub fn mode(mut self, mode: ProfileMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Sampling frequency in Hz, defaults to 100
+    pub fn frequency(mut self, frequency: i32) -> Self {
+        self.frequency = frequency;
+        self
+    }
+
+    /// Length of each profiling window, defaults to 10 seconds
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Maximum number of queued windows sent in one `HandleBatch` call,
+    /// defaults to 8
+    pub fn batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    /// Windows that may wait for upload before new ones are dropped,
+    /// defaults to 64
+    pub fn queue_capacity(mut self, capacity: usize) -> Self {
+        self.queue_capacity = capacity.max(1);
+        self
+    }
+
+    /// Retries of a window's upload before it is dropped, defaults to 3
+    ///
+    /// Only transport errors and `Unavailable`, `DeadlineExceeded` and
+    /// `ResourceExhausted` responses are retried; other failures drop the
+    /// window right away.
+    pub fn max_retries(mut self, retries: u32) -> Self {
+        self.max_retries = retries;
+        self
+    }
+
+    /// Delay before the first retry, doubled on each attempt
+    pub fn retry_backoff(mut self, backoff: Duration) -> Self {
+        self.retry_backoff = backoff;
+        self
+    }
+
+    /// Start without sampling until [`Agent::resume`] is called
+    pub fn paused(mut self, paused: bool) -> Self {
+        self.paused = paused;
+        self
+    }
+
+    /// Spawn the sampler and uploader threads
+    pub fn start(self) -> Result<Agent, AgentError> {
+        let server = self.server.ok_or_else(|| AgentError::Config("server is required".into()))?;
+        let service = self.service.ok_or_else(|| AgentError::Config("service is required".into()))?;
+        if self.interval.is_zero() {
+            return Err(AgentError::Config("interval must be non-zero".into()));
+        }
+
+        let metadata = ProfileMetadata {
+            service_name: service,
+            instance_id: self.instance_id.unwrap_or_else(default_instance_id),
+            labels: self.labels,
+            ..Default::default()
+        };
+
+        // Start the first window before returning so that work done right
+        // after `start` is profiled and profiler errors surface here
+        let first_window = if self.paused {
+            None
+        } else {
+            Some(WindowGuard::start(self.mode, self.frequency)?)
+        };
+
+        let control = Arc::new(Control {
+            state: Mutex::new(State {
+                paused: self.paused,
+                sampling: first_window.is_some(),
+                ..Default::default()
+            }),
+            cond: Condvar::new(),
+        });
+        let stats = Arc::new(Stats::default());
+        let (queue_tx, queue_rx) = mpsc::channel(self.queue_capacity);
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        let uploader = Uploader {
+            server,
+            batch_size: self.batch_size,
+            max_retries: self.max_retries,
+            retry_backoff: self.retry_backoff,
+            stats: stats.clone(),
+            client: None,
+        };
+        let uploader = thread::Builder::new()
+            .name("profiling-uploader".into())
+            .spawn(move || runtime.block_on(uploader.run(queue_rx)))?;
+
+        let sampler = Sampler {
+            mode: self.mode,
+            frequency: self.frequency,
+            interval: self.interval,
+            metadata,
+            control: control.clone(),
+            stats: stats.clone(),
+            queue: queue_tx,
+        };
+        let sampler = thread::Builder::new()
+            .name("profiling-sampler".into())
+            .spawn(move || sampler.run(first_window))?;
+
+        Ok(Agent {
+            control,
+            stats,
+            sampler: Some(sampler),
+            uploader: Some(uploader),
+        })
+    }
+}
+
+/// Counters describing the agent's upload activity
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AgentStats {
+    /// Windows shipped to the server
+    pub uploaded: u64,
+    /// Windows dropped because their upload failed or the queue was full
+    pub dropped: u64,
+    /// Profile ID assigned to the most recent upload
+    pub last_profile_id: Option<String>,
+}
+
+#[derive(Default)]
+struct Stats {
+    uploaded: AtomicU64,
+    dropped: AtomicU64,
+    last_profile_id: Mutex<Option<String>>,
+}
+
+impl Stats {
+    fn snapshot(&self) -> AgentStats {
+        AgentStats {
+            uploaded: self.uploaded.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            last_profile_id: self.last_profile_id.lock().unwrap().clone(),
+        }
+    }
+}
+
+#[derive(Default)]
+struct State {
+    paused: bool,
+    suspended: usize,
+    shutdown: bool,
+    /// A profiler guard owned by the sampler is alive
+    sampling: bool,
+}
+
+impl State {
+    fn should_sample(&self) -> bool {
+        !self.paused && self.suspended == 0 && !self.shutdown
+    }
+}
+
+struct Control {
+    state: Mutex<State>,
+    cond: Condvar,
+}
+
+impl Control {
+    fn update(&self, f: impl FnOnce(&mut State)) {
+        f(&mut self.state.lock().unwrap());
+        self.cond.notify_all();
+    }
+}
+
+/// Handle to a running agent
+///
+/// Dropping the handle without calling [`Agent::shutdown`] also stops the
+/// agent and flushes the last window.
+pub struct Agent {
+    control: Arc<Control>,
+    stats: Arc<Stats>,
+    sampler: Option<JoinHandle<()>>,
+    uploader: Option<JoinHandle<()>>,
+}
+
+impl Agent {
+    pub fn builder() -> AgentBuilder {
+        AgentBuilder::default()
+    }
+
+    /// Stop sampling; the current window is shipped
+    pub fn pause(&self) {
+        self.control.update(|state| state.paused = true);
+    }
+
+    /// Resume sampling with a fresh window
+    pub fn resume(&self) {
+        self.control.update(|state| state.paused = false);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.control.state.lock().unwrap().paused
+    }
+
+    /// Temporarily release the process-wide profiler
+    ///
+    /// pprof allows only one `ProfilerGuard` at a time, and likewise only one
+    /// `WallClockGuard` can run. This ends the current window, waits until
+    /// the sampler has dropped its guard and keeps it from starting a new one
+    /// until the returned guard is dropped.
+    pub fn suspend(&self) -> SuspendGuard<'_> {
+        let mut state = self.control.state.lock().unwrap();
+        state.suspended += 1;
+        self.control.cond.notify_all();
+        while state.sampling {
+            state = self.control.cond.wait(state).unwrap();
+        }
+        SuspendGuard { control: &self.control }
+    }
+
+    pub fn stats(&self) -> AgentStats {
+        self.stats.snapshot()
+    }
+
+    /// Stop sampling, ship the final window and wait for queued uploads
+    pub fn shutdown(mut self) -> AgentStats {
+        self.stop();
+        self.stats.snapshot()
+    }
+
+    fn stop(&mut self) {
+        self.control.update(|state| state.shutdown = true);
+        for handle in [self.sampler.take(), self.uploader.take()].into_iter().flatten() {
+            if handle.join().is_err() {
+                log::error!("Profiling agent thread panicked");
+            }
+        }
+    }
+}
+
+impl Drop for Agent {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Keeps the agent's sampler idle while alive, see [`Agent::suspend`]
+pub struct SuspendGuard<'a> {
+    control: &'a Control,
+}
+
+impl Drop for SuspendGuard<'_> {
+    fn drop(&mut self) {
+        self.control.update(|state| state.suspended -= 1);
+    }
+}
+
+/// A finished window waiting for upload
+struct Window {
+    /// Encoded pprof data
+    data: Vec<u8>,
+    metadata: ProfileMetadata,
+}
+
+impl Window {
+    fn new(profile: &Profile, metadata: ProfileMetadata) -> Self {
+        Window { data: profile.encode_to_vec(), metadata: metadata.with_profile_defaults(profile) }
+    }
+
+    fn request(&self) -> RequestV2 {
+        RequestV2 { data: self.data.clone(), metadata: Some(self.metadata.clone().into()) }
+    }
+}
+
+/// A running profiler of either [`ProfileMode`]
+pub enum WindowGuard {
+    Cpu(ProfilerGuard<'static>, Recording),
+    Wall(WallClockGuard),
+}
+
+impl WindowGuard {
+    /// Start sampling at `frequency` Hz
+    pub fn start(mode: ProfileMode, frequency: i32) -> Result<Self, AgentError> {
+        Ok(match mode {
+            ProfileMode::Cpu => WindowGuard::Cpu(ProfilerGuard::new(frequency)?, Recording::start()),
+            ProfileMode::Wall => WindowGuard::Wall(WallClockGuard::new(frequency)?),
+        })
+    }
+
+    /// Stop sampling and build the window's profile, `None` if it is empty
+    pub fn finish(self) -> Result<Option<Profile>, AgentError> {
+        match self {
+            WindowGuard::Cpu(guard, recording) => {
+                let report = guard.report().build()?;
+                if report.data.is_empty() {
+                    return Ok(None);
+                }
+                Ok(Some(recording.pprof(&report)?))
+            }
+            WindowGuard::Wall(guard) => {
+                let profile = guard.report();
+                Ok(if profile.sample.is_empty() { None } else { Some(profile) })
+            }
+        }
+    }
+}
+
+struct Sampler {
+    mode: ProfileMode,
+    frequency: i32,
+    interval: Duration,
+    metadata: ProfileMetadata,
+    control: Arc<Control>,
+    stats: Arc<Stats>,
+    queue: mpsc::Sender<Window>,
+}
+
+impl Sampler {
+    fn run(self, mut first_window: Option<WindowGuard>) {
+        loop {
+            let guard = match first_window.take() {
+                Some(guard) => Ok(guard),
+                None => {
+                    // Wait until sampling is allowed, then claim the profiler
+                    let mut state = self.control.state.lock().unwrap();
+                    while !state.should_sample() && !state.shutdown {
+                        state = self.control.cond.wait(state).unwrap();
+                    }
+                    if state.shutdown {
+                        return;
+                    }
+                    state.sampling = true;
+                    drop(state);
+                    WindowGuard::start(self.mode, self.frequency)
+                }
+            };
+
+            let profile = guard.and_then(|guard| {
+                self.wait_for_window_end();
+                guard.finish()
+            });
+
+            // The guard is dropped, let suspended callers proceed
+            self.control.update(|state| state.sampling = false);
+
+            match profile {
+                Ok(Some(profile)) => self.enqueue(profile),
+                Ok(None) => log::debug!("Skipping empty profiling window"),
+                Err(e) => {
+                    log::error!("Profiling window failed: {}", e);
+                    // Back off so a persistent failure doesn't spin
+                    let state = self.control.state.lock().unwrap();
+                    let _ = self.control.cond
+                        .wait_timeout_while(state, self.interval, |s| !s.shutdown)
+                        .unwrap();
+                }
+            }
+        }
+    }
+
+    /// Block until the interval elapses or sampling is paused, suspended or
+    /// shut down
+    fn wait_for_window_end(&self) {
+        let deadline = Instant::now() + self.interval;
+        let mut state = self.control.state.lock().unwrap();
+        while state.should_sample() {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            state = self.control.cond.wait_timeout(state, deadline - now).unwrap().0;
+        }
+    }
+
+    fn enqueue(&self, profile: Profile) {
+        let window = Window::new(&profile, self.metadata.clone());
+        match self.queue.try_send(window) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                log::warn!("Upload queue full, dropping profiling window");
+                self.stats.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(TrySendError::Closed(_)) => {
+                log::error!("Uploader stopped, dropping profiling window");
+                self.stats.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+/// Largest pprof data sent in one `HandleBatch` call, leaving room below
+/// the server's 4 MB message limit; larger windows are streamed alone
+const MAX_BATCH_BYTES: usize = 3 * 1024 * 1024;
+
+struct Uploader {
+    server: String,
+    batch_size: usize,
+    max_retries: u32,
+    retry_backoff: Duration,
+    stats: Arc<Stats>,
+    client: Option<MyServiceClient<Channel>>,
+}
+
+impl Uploader {
+    /// Upload windows until the sampler hangs up and the queue is empty
+    async fn run(mut self, mut queue: mpsc::Receiver<Window>) {
+        while let Some(first) = queue.recv().await {
+            let mut batch = vec![first];
+            while batch.len() < self.batch_size {
+                match queue.try_recv() {
+                    Ok(window) => batch.push(window),
+                    Err(_) => break,
+                }
+            }
+            self.upload_batch(batch).await;
+        }
+    }
+
+    /// Upload windows in as few calls as the message limit allows
+    async fn upload_batch(&mut self, batch: Vec<Window>) {
+        let (large, small): (Vec<_>, Vec<_>) = batch.into_iter()
+            .partition(|window| window.data.len() > MAX_BATCH_BYTES);
+        let mut call = Vec::new();
+        let mut call_bytes = 0;
+        for window in small {
+            if call_bytes + window.data.len() > MAX_BATCH_BYTES {
+                self.send_batch(std::mem::take(&mut call)).await;
+                call_bytes = 0;
+            }
+            call_bytes += window.data.len();
+            call.push(window);
+        }
+        if !call.is_empty() {
+            self.send_batch(call).await;
+        }
+        for window in large {
+            self.upload_window(&window).await;
+        }
+    }
+
+    /// Send windows in one `HandleBatch` call, retrying transient failures
+    /// of the call; windows the server rejects are dropped on their own
+    async fn send_batch(&mut self, windows: Vec<Window>) {
+        let request = BatchRequest { profiles: windows.iter().map(Window::request).collect() };
+        let mut attempt = 0;
+        loop {
+            let result = match self.client().await {
+                Ok(client) => client.handle_batch(request.clone()).await.map_err(AgentError::from),
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(response) => {
+                    self.record_results(&response.into_inner().results, windows.len());
+                    return;
+                }
+                Err(AgentError::Rpc(status)) if status.code() == tonic::Code::Unimplemented => {
+                    log::debug!("Server doesn't support batches, uploading windows one by one");
+                    for window in &windows {
+                        self.upload_window(window).await;
+                    }
+                    return;
+                }
+                Err(e) => match self.retry_delay(&e, &mut attempt) {
+                    Some(delay) => {
+                        log::warn!("Upload failed ({}), retrying in {:?}", e, delay);
+                        tokio::time::sleep(delay).await;
+                    }
+                    None => {
+                        log::error!("Upload failed after {} attempts, dropping {} profiling windows: {}",
+                            attempt + 1, windows.len(), e);
+                        self.stats.dropped.fetch_add(windows.len() as u64, Ordering::Relaxed);
+                        return;
+                    }
+                },
+            }
+        }
+    }
+
+    /// Count the outcome of each window of a batch
+    fn record_results(&self, results: &[BatchResult], windows: usize) {
+        for result in results.iter().take(windows) {
+            if result.profile_id.is_empty() {
+                log::error!("Server rejected profiling window: {}", result.error);
+                self.stats.dropped.fetch_add(1, Ordering::Relaxed);
+            } else {
+                self.record_upload(result.profile_id.clone());
+            }
+        }
+        if results.len() < windows {
+            log::error!("Server didn't report on {} profiling windows", windows - results.len());
+            self.stats.dropped.fetch_add((windows - results.len()) as u64, Ordering::Relaxed);
+        }
+    }
+
+    fn record_upload(&self, profile_id: String) {
+        log::debug!("Uploaded profiling window {}", profile_id);
+        self.stats.uploaded.fetch_add(1, Ordering::Relaxed);
+        *self.stats.last_profile_id.lock().unwrap() = Some(profile_id);
+    }
+
+    /// Upload one window on its own, retrying transient failures
+    async fn upload_window(&mut self, window: &Window) {
+        let mut attempt = 0;
+        loop {
+            let result = match self.client().await {
+                Ok(client) => upload_encoded(client, window.data.clone(), window.metadata.clone()).await,
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(profile_id) => {
+                    self.record_upload(profile_id);
+                    return;
+                }
+                Err(e) => match self.retry_delay(&e, &mut attempt) {
+                    Some(delay) => {
+                        log::warn!("Upload failed ({}), retrying in {:?}", e, delay);
+                        tokio::time::sleep(delay).await;
+                    }
+                    None => {
+                        log::error!("Upload failed after {} attempts, dropping profiling window: {}", attempt + 1, e);
+                        self.stats.dropped.fetch_add(1, Ordering::Relaxed);
+                        return;
+                    }
+                },
+            }
+        }
+    }
+
+    /// Delay before retrying an upload that failed with `e`, `None` to give
+    /// up on it
+    fn retry_delay(&mut self, e: &AgentError, attempt: &mut u32) -> Option<Duration> {
+        if e.is_retryable() {
+            // Reconnect, the connection may be broken
+            self.client = None;
+        }
+        if !e.is_retryable() || *attempt >= self.max_retries {
+            return None;
+        }
+        let delay = self.retry_backoff * 2u32.saturating_pow(*attempt);
+        *attempt += 1;
+        Some(delay)
+    }
+
+    /// The connection to the server, connecting if there is none
+    async fn client(&mut self) -> Result<&mut MyServiceClient<Channel>, AgentError> {
+        if self.client.is_none() {
+            self.client = Some(connect(self.server.clone()).await?);
+        }
+        Ok(self.client.as_mut().expect("connected above"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::myservice::my_service_server::{MyService, MyServiceServer};
+    use crate::myservice::{BatchResponse, Request as MyRequest, Response as MyResponse, UploadProfileChunk};
+    use crate::upload::UploadAssembler;
+    use std::collections::{HashSet, VecDeque};
+    use std::net::SocketAddr;
+    use tonic::{Code, Request, Response, Status, Streaming};
+
+    /// In-process profile server recording what it receives
+    #[derive(Clone, Default)]
+    struct MockServer {
+        /// Services of stored profiles, in order
+        stored: Arc<Mutex<Vec<String>>>,
+        /// Codes to fail the next `HandleBatch` calls with
+        failures: Arc<Mutex<VecDeque<Code>>>,
+        /// `HandleBatch` calls, including failed ones
+        batches: Arc<AtomicU64>,
+        /// Profiles stored through `UploadProfile`
+        streamed: Arc<AtomicU64>,
+        /// Client addresses of `HandleBatch` and `UploadProfile` calls
+        peers: Arc<Mutex<HashSet<Option<SocketAddr>>>>,
+        /// Answer `HandleBatch` and `UploadProfile` with `Unimplemented`,
+        /// like older servers
+        legacy: bool,
+    }
+
+    impl MockServer {
+        /// Store a profile, answering with its position as the profile ID;
+        /// profiles of the service `rejected` are refused
+        fn store(&self, metadata: ProfileMetadata) -> Option<String> {
+            if metadata.service_name == "rejected" {
+                return None;
+            }
+            let mut stored = self.stored.lock().unwrap();
+            stored.push(metadata.service_name);
+            Some(stored.len().to_string())
+        }
+
+        /// Serve on a free local port, returning the runtime it runs on and
+        /// its URL
+        fn serve(&self) -> (tokio::runtime::Runtime, String) {
+            let runtime = tokio::runtime::Runtime::new().unwrap();
+            let listener = runtime.block_on(tokio::net::TcpListener::bind("127.0.0.1:0")).unwrap();
+            let url = format!("http://{}", listener.local_addr().unwrap());
+            let service = MyServiceServer::new(self.clone()).accept_compressed(CompressionEncoding::Zstd);
+            runtime.spawn(tonic::transport::Server::builder()
+                .add_service(service)
+                .serve_with_incoming(tokio_stream::wrappers::TcpListenerStream::new(listener)));
+            (runtime, url)
+        }
+
+        fn stored(&self) -> Vec<String> {
+            self.stored.lock().unwrap().clone()
+        }
+    }
+
+    #[tonic::async_trait]
+    impl MyService for MockServer {
+        async fn handle_request(&self, _: Request<MyRequest>) -> Result<Response<MyResponse>, Status> {
+            Err(Status::unimplemented("not used"))
+        }
+
+        async fn handle_request_v2(&self, request: Request<RequestV2>) -> Result<Response<MyResponse>, Status> {
+            let metadata = request.into_inner().metadata.map(ProfileMetadata::from).unwrap_or_default();
+            let profile_id = self.store(metadata).ok_or_else(|| Status::invalid_argument("rejected"))?;
+            Ok(Response::new(MyResponse { result: profile_id.into_bytes() }))
+        }
+
+        async fn handle_batch(&self, request: Request<BatchRequest>) -> Result<Response<BatchResponse>, Status> {
+            if self.legacy {
+                return Err(Status::unimplemented("unknown method"));
+            }
+            self.batches.fetch_add(1, Ordering::Relaxed);
+            self.peers.lock().unwrap().insert(request.remote_addr());
+            if let Some(code) = self.failures.lock().unwrap().pop_front() {
+                return Err(Status::new(code, "injected failure"));
+            }
+            let results = request.into_inner().profiles.into_iter()
+                .map(|profile| match self.store(profile.metadata.map(ProfileMetadata::from).unwrap_or_default()) {
+                    Some(profile_id) => BatchResult { profile_id, error: String::new() },
+                    None => BatchResult { profile_id: String::new(), error: "rejected".to_string() },
+                })
+                .collect();
+            Ok(Response::new(BatchResponse { results }))
+        }
+
+        async fn upload_profile(
+            &self,
+            request: Request<Streaming<UploadProfileChunk>>,
+        ) -> Result<Response<MyResponse>, Status> {
+            if self.legacy {
+                return Err(Status::unimplemented("unknown method"));
+            }
+            self.peers.lock().unwrap().insert(request.remote_addr());
+            let mut stream = request.into_inner();
+            let mut upload = UploadAssembler::new(upload::DEFAULT_MAX_UPLOAD_SIZE);
+            while let Some(chunk) = stream.message().await? {
+                upload.push(chunk).map_err(|e| Status::invalid_argument(e.to_string()))?;
+            }
+            let (_, metadata) = upload.finish().map_err(|e| Status::invalid_argument(e.to_string()))?;
+            let profile_id = self.store(metadata).ok_or_else(|| Status::invalid_argument("rejected"))?;
+            self.streamed.fetch_add(1, Ordering::Relaxed);
+            Ok(Response::new(MyResponse { result: profile_id.into_bytes() }))
+        }
+    }
+
+    fn window(service: &str) -> Window {
+        let metadata = ProfileMetadata { service_name: service.to_string(), ..Default::default() };
+        Window::new(&Profile::default(), metadata)
+    }
+
+    /// Run an uploader over the given windows until it has drained them
+    fn upload_all(url: &str, windows: Vec<Window>, max_retries: u32, retry_backoff: Duration) -> AgentStats {
+        let (tx, rx) = mpsc::channel(windows.len().max(1));
+        for window in windows {
+            tx.try_send(window).unwrap();
+        }
+        drop(tx);
+        let stats = Arc::new(Stats::default());
+        let uploader = Uploader {
+            server: url.to_string(),
+            batch_size: 2,
+            max_retries,
+            retry_backoff,
+            stats: stats.clone(),
+            client: None,
+        };
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(uploader.run(rx));
+        stats.snapshot()
+    }
+
+    #[test]
+    fn start_requires_server_and_service() {
+        let err = Agent::builder().service("svc").start().err().unwrap();
+        assert!(matches!(err, AgentError::Config(_)));
+
+        let err = Agent::builder().server("http://[::1]:1").start().err().unwrap();
+        assert!(matches!(err, AgentError::Config(_)));
+    }
+
+    #[test]
+    fn uploads_queued_windows_in_batches_over_one_connection() {
+        let server = MockServer::default();
+        let (_runtime, url) = server.serve();
+        let services = ["a", "b", "c", "d", "e"];
+        let stats = upload_all(&url, services.iter().map(|s| window(s)).collect(), 0, Duration::ZERO);
+
+        assert_eq!((stats.uploaded, stats.dropped), (5, 0));
+        assert_eq!(stats.last_profile_id.as_deref(), Some("5"));
+        assert_eq!(server.stored(), services);
+        // Two windows per call
+        assert_eq!(server.batches.load(Ordering::Relaxed), 3);
+        assert_eq!(server.peers.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn retries_transient_failures_and_drops_only_what_failed() {
+        let server = MockServer::default();
+        let (_runtime, url) = server.serve();
+
+        // Two transient failures, retried after 20ms and then 40ms
+        server.failures.lock().unwrap().extend([Code::Unavailable, Code::ResourceExhausted]);
+        let started = Instant::now();
+        let stats = upload_all(&url, vec![window("a"), window("b")], 3, Duration::from_millis(20));
+        assert!(started.elapsed() >= Duration::from_millis(60));
+        assert_eq!((stats.uploaded, stats.dropped), (2, 0));
+        assert_eq!(server.batches.swap(0, Ordering::Relaxed), 3);
+
+        // A window the server rejects is dropped alone
+        let stats = upload_all(&url, vec![window("rejected"), window("c")], 0, Duration::ZERO);
+        assert_eq!((stats.uploaded, stats.dropped), (1, 1));
+        assert_eq!(server.batches.swap(0, Ordering::Relaxed), 1);
+
+        // A call rejected outright, and one out of retries, drop their own
+        // windows; the next call is still made
+        server.failures.lock().unwrap().extend([Code::InvalidArgument, Code::Unavailable, Code::Unavailable]);
+        let windows = ["d", "e", "f", "g", "h"].iter().map(|s| window(s)).collect();
+        let stats = upload_all(&url, windows, 1, Duration::ZERO);
+        assert_eq!((stats.uploaded, stats.dropped), (1, 4));
+        assert_eq!(server.batches.load(Ordering::Relaxed), 4);
+        assert_eq!(server.stored(), ["a", "b", "c", "h"]);
+    }
+
+    #[test]
+    fn streams_windows_too_large_for_a_batch() {
+        let server = MockServer::default();
+        let (_runtime, url) = server.serve();
+        let mut large = window("large");
+        large.data = vec![0; MAX_BATCH_BYTES + 1];
+        let stats = upload_all(&url, vec![window("small"), large], 0, Duration::ZERO);
+
+        assert_eq!((stats.uploaded, stats.dropped), (2, 0));
+        assert_eq!(server.stored(), ["small", "large"]);
+        assert_eq!(server.batches.load(Ordering::Relaxed), 1);
+        assert_eq!(server.streamed.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn falls_back_to_handle_request_v2() {
+        let server = MockServer { legacy: true, ..Default::default() };
+        let (runtime, url) = server.serve();
+        let metadata = ProfileMetadata { service_name: "legacy".into(), ..Default::default() };
+        let profile_id = runtime.block_on(async {
+            let mut client = connect(url.clone()).await.unwrap();
+            upload_profile(&mut client, &Profile::default(), metadata).await.unwrap()
+        });
+        assert_eq!(profile_id, "1");
+
+        // Batches are split up as well
+        let stats = upload_all(&url, vec![window("a"), window("b")], 0, Duration::ZERO);
+        assert_eq!((stats.uploaded, stats.dropped), (2, 0));
+        assert_eq!(server.stored(), ["legacy", "a", "b"]);
+    }
+
+    #[test]
+    fn shutdown_flushes_last_partial_window() {
+        let server = MockServer::default();
+        let (_runtime, url) = server.serve();
+        let agent = Agent::builder()
+            .server(url)
+            .service("flushed")
+            .interval(Duration::from_secs(3600))
+            .frequency(1000)
+            .start()
+            .unwrap();
+
+        // Burn CPU so that the window has samples
+        let started = Instant::now();
+        let mut x = 0u64;
+        while started.elapsed() < Duration::from_millis(300) {
+            x = std::hint::black_box(x.wrapping_mul(31).wrapping_add(7));
+        }
+
+        let stats = agent.shutdown();
+        assert_eq!((stats.uploaded, stats.dropped), (1, 0));
+        assert_eq!(server.stored(), ["flushed"]);
+    }
+}
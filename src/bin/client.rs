@@ -1,12 +1,8 @@
-use pprof::ProfilerGuard;
-use pprof::protos::Message;
-use profiling::myservice::my_service_client::MyServiceClient;
-use profiling::myservice::Request;
+use profiling::agent::Agent;
+use profiling::config::ClientConfig;
 use std::thread;
 use std::time::Duration;
 use std::collections::HashMap;
-use log;
-use env_logger;
 
 // Recursive tree-like computation
 fn binary_tree_sum(depth: u32) -> u64 {
@@ -111,29 +107,23 @@ fn aggregate_complex_results(data: Vec<(String, u64)>) -> HashMap<String, u64> {
     result
 }
 
-// Async work simulation with complex operations
-async fn async_complex_work() {
-    for i in 0..3 {
-        tokio::time::sleep(Duration::from_millis(50)).await;
-        let _ = vector_operations(1000);
-        let _ = hash_map_operations(500);
-        let _ = process_data_pipeline(100);
-        if i % 2 == 0 {
-            let _ = binary_tree_sum(10);
-        }
-    }
-}
-
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
 
-    let task_type = std::env::args()
-        .nth(1)
-        .unwrap_or_else(|| "mixed".to_string());
+    let config = ClientConfig::load()?;
+    let task_type = config.task_type;
 
     log::info!("Starting profiling for task type: {}", task_type);
-    let guard = ProfilerGuard::new(100).unwrap();
+    // One window covering the whole run, shipped on shutdown
+    let agent = Agent::builder()
+        .server(config.grpc_url)
+        .service(config.service_name)
+        .label("task_type", task_type.clone())
+        .label("version", env!("CARGO_PKG_VERSION"))
+        .mode(config.mode)
+        .interval(Duration::from_secs(3600))
+        .start()?;
 
     // Execute the requested task type
     match task_type.as_str() {
@@ -155,8 +145,10 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         },
         _ => {
             log::info!("Running mixed workload");
-            let handles: Vec<_> = (0..4).map(|i| {
-                thread::spawn(move || {
+            // Named threads so the profile can be split by its `thread` label
+            let workers = ["mixed-compute", "mixed-memory", "mixed-collections", "mixed-pipeline"];
+            let handles = workers.into_iter().enumerate().map(|(i, name)| {
+                thread::Builder::new().name(name.to_string()).spawn(move || {
                     match i {
                         0 => {
                             let _ = binary_tree_sum(15);
@@ -174,7 +166,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                         }
                     }
                 })
-            }).collect();
+            }).collect::<Result<Vec<_>, _>>()?;
 
             for handle in handles {
                 if let Err(e) = handle.join() {
@@ -184,34 +176,16 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
     }
 
-    // Get and send profile data
-    match guard.report().build() {
-        Ok(report) => {
-            match report.pprof() {
-                Ok(profile) => {
-                    let mut content = Vec::new();
-                    if let Err(e) = profile.encode(&mut content) {
-                        log::error!("Failed to encode profile: {}", e);
-                        return Err(e.into());
-                    }
-                    
-                    let mut client = MyServiceClient::connect("http://[::1]:50051").await?;
-                    let request = Request {
-                        data: content,
-                    };
-                    let response = client.handle_request(request).await?;
-                    println!("Profile ID: {}", String::from_utf8_lossy(&response.into_inner().result));
-                    Ok(())
-                }
-                Err(e) => {
-                    log::error!("Failed to generate pprof: {}", e);
-                    Err(e.into())
-                }
-            }
+    // Stop profiling and send the profile data
+    let stats = agent.shutdown();
+    match stats.last_profile_id {
+        Some(profile_id) if stats.dropped == 0 => {
+            println!("Profile ID: {}", profile_id);
+            Ok(())
         }
-        Err(e) => {
-            log::error!("Failed to build report: {}", e);
-            Err(e.into())
+        _ => {
+            log::error!("Failed to upload profile ({} dropped)", stats.dropped);
+            Err("Failed to upload profile".into())
         }
     }
-} 
\ No newline at end of file
+}
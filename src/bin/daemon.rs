@@ -1,23 +1,36 @@
-use pprof::ProfilerGuard;
-use pprof::protos::Message;
-use profiling::myservice::my_service_client::MyServiceClient;
-use profiling::myservice::Request;
+use profiling::agent::{self, upload_profile, Agent, ProfileMode, WindowGuard};
+use profiling::config::DaemonConfig;
+use profiling::heap::{HeapProfilerGuard, TrackingAllocator};
+use profiling::metadata::{default_instance_id, ProfileMetadata};
+use pprof::protos::Profile;
 use profiling::tasks::*;
+use std::collections::BTreeMap;
+use std::sync::Arc;
 use std::thread;
 use std::time::Duration;
 use tokio::sync::mpsc;
 use actix_web::{web, App, HttpServer, HttpResponse};
 use actix_cors::Cors;
 use serde::Deserialize;
-use log;
-use env_logger;
 use serde_json::json;
 
+/// Samples allocations while a memory or mixed task runs
+#[global_allocator]
+static ALLOC: TrackingAllocator = TrackingAllocator;
+
+/// Profiles uploaded for one task
+#[derive(Debug)]
+struct TaskProfiles {
+    profile_id: String,
+    heap_profile_id: Option<String>,
+}
+
 #[derive(Debug)]
 enum TaskMessage {
     Execute { 
         task_type: String,
-        response: tokio::sync::oneshot::Sender<String>,
+        mode: ProfileMode,
+        response: tokio::sync::oneshot::Sender<TaskProfiles>,
     },
     Shutdown,
 }
@@ -26,21 +39,26 @@ enum TaskMessage {
 struct TaskRequest {
     #[serde(rename = "type")]
     task_type: String,
+    /// Sample on-CPU (default) or wall-clock time while the task runs
+    #[serde(default)]
+    mode: ProfileMode,
 }
 
 struct TaskExecutor {
     rx: mpsc::Receiver<TaskMessage>,
+    agent: Arc<Agent>,
+    config: DaemonConfig,
 }
 
 impl TaskExecutor {
     async fn run(&mut self) {
         while let Some(msg) = self.rx.recv().await {
             match msg {
-                TaskMessage::Execute { task_type, response } => {
-                    log::info!("Executing task: {}", task_type);
-                    match self.execute_task(&task_type).await {
-                        Ok(profile_id) => {
-                            let _ = response.send(profile_id);
+                TaskMessage::Execute { task_type, mode, response } => {
+                    log::info!("Executing task: {} ({:?})", task_type, mode);
+                    match self.execute_task(&task_type, mode).await {
+                        Ok(profiles) => {
+                            let _ = response.send(profiles);
                         }
                         Err(e) => {
                             log::error!("Task execution failed: {}", e);
@@ -55,83 +73,169 @@ impl TaskExecutor {
         }
     }
 
-    async fn execute_task(&self, task_type: &str) -> Result<String, Box<dyn std::error::Error>> {
-        let guard = ProfilerGuard::new(100).unwrap();
+    async fn execute_task(&self, task_type: &str, mode: ProfileMode) -> Result<TaskProfiles, Box<dyn std::error::Error>> {
+        // Waiting for the profiler and the workload itself block, so run
+        // them on a blocking thread rather than the executor's task
+        let agent = self.agent.clone();
+        let task = task_type.to_string();
+        let heap_sample_interval = self.config.heap_sample_interval;
+        let (profile, heap_profile) = tokio::task::spawn_blocking(move || {
+            profile_task(&agent, &task, mode, heap_sample_interval)
+        }).await?.map_err(|e| e as Box<dyn std::error::Error>)?;
 
-        match task_type {
-            "cpu" => {
-                log::info!("Running CPU intensive task");
-                for _ in 0..2 {
-                    let _ = binary_tree_sum(15);
-                    let _ = fibonacci(30);
-                    let _ = heavy_computation(50_000);
-                }
-            },
-            "memory" => {
-                log::info!("Running memory intensive task");
-                for _ in 0..3 {
-                    let _ = memory_intensive();
-                    let _ = string_processing();
-                    thread::sleep(Duration::from_millis(50));
-                }
-            },
-            _ => {
-                log::info!("Running mixed workload");
-                let handles: Vec<_> = (0..4).map(|i| {
-                    thread::spawn(move || {
-                        match i {
-                            0 => {
-                                let _ = binary_tree_sum(15);
-                                let _ = fibonacci(30);
-                            },
-                            1 => {
-                                let _ = memory_intensive();
-                            },
-                            2 => {
-                                let _ = vector_operations(2000);
-                                let _ = hash_map_operations(1000);
-                            },
-                            _ => {
-                                let _ = process_data_pipeline(150);
-                            }
-                        }
-                    })
-                }).collect();
+        // Get profile ID from response
+        if let Some(profile) = profile {
+            let mut client = agent::connect(self.config.grpc_url.clone()).await?;
+            let metadata = ProfileMetadata {
+                service_name: self.config.service_name.clone(),
+                instance_id: default_instance_id(),
+                labels: BTreeMap::from([
+                    ("task_type".to_string(), task_type.to_string()),
+                    ("version".to_string(), env!("CARGO_PKG_VERSION").to_string()),
+                ]),
+                ..Default::default()
+            };
+            let profile_id = upload_profile(&mut client, &profile, metadata.clone()).await?;
+            let heap_profile_id = match heap_profile {
+                Some(heap_profile) => Some(upload_profile(&mut client, &heap_profile, metadata).await?),
+                None => None,
+            };
+            return Ok(TaskProfiles { profile_id, heap_profile_id });
+        }
+        
+        Err("Failed to generate profile".into())
+    }
+}
 
-                for handle in handles {
-                    if let Err(e) = handle.join() {
-                        log::error!("Thread panicked: {:?}", e);
-                    }
-                }
+/// Run a task while profiling it, returning its profile and, for memory and
+/// mixed tasks, its heap profile
+fn profile_task(
+    agent: &Agent,
+    task_type: &str,
+    mode: ProfileMode,
+    heap_sample_interval: usize,
+) -> Result<(Option<Profile>, Option<Profile>), Box<dyn std::error::Error + Send + Sync>> {
+    // Take the profiler over from continuous sampling for this task
+    let _suspended = agent.suspend();
+    let guard = WindowGuard::start(mode, 100)?;
+    let heap_guard = if task_type != "cpu" && heap_sample_interval > 0 {
+        Some(HeapProfilerGuard::new(heap_sample_interval)?)
+    } else {
+        None
+    };
+
+    match task_type {
+        "cpu" => {
+            log::info!("Running CPU intensive task");
+            for _ in 0..2 {
+                let _ = binary_tree_sum(15);
+                let _ = fibonacci(30);
+                let _ = heavy_computation(50_000);
             }
-        }
+        },
+        "memory" => {
+            log::info!("Running memory intensive task");
+            for _ in 0..3 {
+                let _ = memory_intensive();
+                let _ = string_processing();
+                thread::sleep(Duration::from_millis(50));
+            }
+        },
+        _ => {
+            log::info!("Running mixed workload");
+            // Named threads so the profile can be split by its `thread` label
+            let workers = ["mixed-compute", "mixed-memory", "mixed-collections", "mixed-pipeline"];
+            let handles = workers.into_iter().enumerate().map(|(i, name)| {
+                thread::Builder::new().name(name.to_string()).spawn(move || {
+                    match i {
+                        0 => {
+                            let _ = binary_tree_sum(15);
+                            let _ = fibonacci(30);
+                        },
+                        1 => {
+                            let _ = memory_intensive();
+                        },
+                        2 => {
+                            let _ = vector_operations(2000);
+                            let _ = hash_map_operations(1000);
+                        },
+                        _ => {
+                            let _ = process_data_pipeline(150);
+                        }
+                    }
+                })
+            }).collect::<Result<Vec<_>, _>>()?;
 
-        // Get profile ID from response
-        if let Ok(report) = guard.report().build() {
-            if let Ok(profile) = report.pprof() {
-                let mut content = Vec::new();
-                profile.encode(&mut content)?;
-                
-                let mut client = MyServiceClient::connect("http://[::1]:50051").await?;
-                let request = Request {
-                    data: content,
-                };
-                let response = client.handle_request(request).await?;
-                let profile_id = String::from_utf8_lossy(&response.into_inner().result).to_string();
-                return Ok(profile_id);
+            for handle in handles {
+                if let Err(e) = handle.join() {
+                    log::error!("Thread panicked: {:?}", e);
+                }
             }
         }
-        
-        Err("Failed to generate profile".into())
     }
+
+    // Stop sampling allocations before uploading
+    let heap_profile = heap_guard.map(|heap_guard| heap_guard.report());
+
+    Ok((guard.finish().ok().flatten(), heap_profile))
+}
+
+/// Report whether continuous sampling is currently running
+async fn continuous_status(agent: web::Data<Agent>) -> HttpResponse {
+    let stats = agent.stats();
+    HttpResponse::Ok().json(json!({
+        "paused": agent.is_paused(),
+        "uploaded": stats.uploaded,
+        "dropped": stats.dropped,
+        "lastProfileId": stats.last_profile_id,
+    }))
+}
+
+/// Pause continuous sampling; the current window is shipped first
+async fn pause_continuous(agent: web::Data<Agent>) -> HttpResponse {
+    agent.pause();
+    log::info!("Continuous sampling paused");
+    HttpResponse::Ok().json(json!({"paused": true}))
+}
+
+/// Resume continuous sampling with a fresh window
+async fn resume_continuous(agent: web::Data<Agent>) -> HttpResponse {
+    agent.resume();
+    log::info!("Continuous sampling resumed");
+    HttpResponse::Ok().json(json!({"paused": false}))
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
 
+    let config = DaemonConfig::load()?;
+
+    // Start always-on sampling in the background
+    log::info!(
+        "Continuous {:?} sampling every {:?} at {} Hz{}",
+        config.continuous_mode,
+        config.continuous_interval,
+        config.continuous_frequency,
+        if config.continuous_enabled { "" } else { " (paused)" }
+    );
+    let agent = Arc::new(
+        Agent::builder()
+            .server(config.grpc_url.clone())
+            .service(config.service_name.clone())
+            .label("mode", "continuous")
+            .label("version", env!("CARGO_PKG_VERSION"))
+            .mode(config.continuous_mode)
+            .frequency(config.continuous_frequency)
+            .interval(config.continuous_interval)
+            .paused(!config.continuous_enabled)
+            .start()?
+    );
+
     let (tx, rx) = mpsc::channel(32);
-    let mut executor = TaskExecutor { rx };
+    let http_addr = config.http_addr;
+    let mut executor = TaskExecutor { rx, agent: agent.clone(), config };
+    let agent_data = web::Data::from(agent);
 
     // Set up HTTP server to receive task requests
     let task_sender = tx.clone();
@@ -139,6 +243,10 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         let sender = task_sender.clone();
         App::new()
             .wrap(Cors::permissive())
+            .app_data(agent_data.clone())
+            .route("/continuous", web::get().to(continuous_status))
+            .route("/continuous/pause", web::post().to(pause_continuous))
+            .route("/continuous/resume", web::post().to(resume_continuous))
             .route("/task", web::post().to(move |task: web::Json<TaskRequest>| {
                 let tx = sender.clone();
                 async move {
@@ -146,17 +254,19 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                     
                     if let Err(e) = tx.send(TaskMessage::Execute { 
                         task_type: task.task_type.clone(),
+                        mode: task.mode,
                         response: response_tx,
                     }).await {
                         log::error!("Failed to send task: {}", e);
                         return HttpResponse::InternalServerError().finish();
                     }
 
-                    // Wait for task completion and profile ID
+                    // Wait for task completion and profile IDs
                     match response_rx.await {
-                        Ok(profile_id) => {
+                        Ok(profiles) => {
                             HttpResponse::Ok().json(json!({
-                                "profileId": profile_id
+                                "profileId": profiles.profile_id,
+                                "heapProfileId": profiles.heap_profile_id
                             }))
                         }
                         Err(_) => HttpResponse::InternalServerError().json(json!({
@@ -166,7 +276,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 }
             }))
     })
-    .bind("[::1]:3001")?
+    .bind(http_addr)?
     .run();
 
     // Handle shutdown
@@ -6,63 +6,94 @@
 //! 3. Store profiles in memory and on disk
 //! 4. Serve profile data via HTTP API
 //! 
-//! The server runs two services:
-//! - gRPC server on [::1]:50051 for receiving profiles
-//! - HTTP server on [::1]:3000 for serving processed profiles
+//! The server runs two services, with addresses taken from `ServerConfig`:
+//! - gRPC server (default [::1]:50051) for receiving profiles
+//! - HTTP server (default [::1]:3000) for serving processed profiles
 
-use tonic::{transport::Server, Request, Response, Status};
+use tonic::{codec::CompressionEncoding, transport::Server, Code, Request, Response, Status, Streaming};
 use profiling::myservice::my_service_server::{MyService, MyServiceServer};
-use profiling::myservice::{Request as MyRequest, Response as MyResponse};
-use pprof::protos::{Profile, Message};
+use profiling::proto::pprof_server::{Pprof, PprofServer};
+use profiling::proto::{SaveProfileRequest, SaveProfileResponse};
+use profiling::myservice::{
+    BatchRequest, BatchResponse, BatchResult, Request as MyRequest, RequestV2 as MyRequestV2, Response as MyResponse,
+    UploadProfileChunk,
+};
+use pprof::protos::{Profile, Message, Sample};
 use serde_json::json;
-use std::collections::{HashMap, HashSet};
-use std::fs::File;
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use actix_web::{web, App, HttpServer, HttpResponse};
 use actix_cors::Cors;
-use tokio::sync::RwLock;
-use log;
-use env_logger;
+use tokio::sync::{RwLock, Semaphore};
 use std::time::Instant;
-use ctrlc;
 use std::time::Duration;
 use serde::{Deserialize, Serialize};
-use std::io::Write;
 use actix_web::web::Json;
 use reqwest::Client;
-use profiling::storage;
+use profiling::storage::{self, ProfilePart, ProfileStorage, SharedStorage, StoredProfile};
+use profiling::agent::ProfileMode;
+use profiling::cache::ProfileCache;
+use profiling::config::ServerConfig;
+use profiling::dedup::DedupStorage;
+use profiling::export;
+use profiling::flamegraph::{self as flamegraph, DiffData, FlameGraphData, ProfileGraphs};
+use profiling::metadata::ProfileMetadata;
+use profiling::index::{IndexEntry, LabelMatcher, ProfileIndex, ProfileQuery, SortField, SortOrder};
+use profiling::ingest::IngestParams;
+use profiling::merge::{MergeError, ProfileMerger, MAX_MERGED_PROFILES};
+use profiling::retention::{self, RetentionPolicy};
+use profiling::timeseries::{timeline_sample_type, Point, TimeseriesQuery, TimeseriesStore};
+use profiling::upload::{self, UploadAssembler, UploadError};
 
-/// Store for holding processed profiles in memory
-/// Maps profile IDs to their JSON representations
-type ProfileStore = Arc<RwLock<HashMap<String, serde_json::Value>>>;
+/// Bounded cache of processed profiles in memory
+/// Maps profile IDs to their flame graphs, one per sample type; misses are
+/// loaded from storage with [`load_graphs`]
+type ProfileStore = Arc<ProfileCache>;
+
+/// Shared searchable index of stored profiles and their metadata
+type SharedIndex = Arc<RwLock<ProfileIndex>>;
 
 /// gRPC service implementation for receiving profiles
-#[derive(Default)]
+///
+/// Also shared with the HTTP server, whose `/ingest` endpoint goes through
+/// the same pipeline.
+#[derive(Clone)]
 pub struct MyServiceImpl {
     profiles: ProfileStore,
+    index: SharedIndex,
+    storage: SharedStorage,
+    timeseries: Arc<TimeseriesStore>,
+    /// Index file, appended to outside the index lock
+    index_path: PathBuf,
+    /// Size limit of a streamed upload
+    max_upload_bytes: u64,
+    /// One permit per streamed upload being received
+    upload_slots: Arc<Semaphore>,
 }
 
-#[tonic::async_trait]
-impl MyService for MyServiceImpl {
-    /// Handles incoming profile requests
+impl MyServiceImpl {
+    /// Decodes, processes and stores a profile
     /// 
     /// # Steps
-    /// 1. Decodes pprof data
+    /// 1. Decodes pprof data, inflating it if gzip-compressed
     /// 2. Processes profile into JSON
-    /// 3. Stores in memory and on disk
-    /// 4. Returns unique profile ID
-    async fn handle_request(
+    /// 3. Stores in memory and in the storage backend, together with its metadata
+    /// 4. Writes its samples as time-series rows
+    /// 5. Returns unique profile ID
+    async fn ingest_profile(
         &self,
-        request: Request<MyRequest>,
-    ) -> Result<Response<MyResponse>, Status> {
+        data: Vec<u8>,
+        metadata: ProfileMetadata,
+    ) -> Result<String, Status> {
         let start_time = Instant::now();
-        let data = request.into_inner().data;
         
         let process_result = tokio::time::timeout(
             Duration::from_secs(30),
             tokio::task::spawn_blocking(move || {
-                Profile::decode(&data[..]).map(|profile| {
-                    let flame_data = FlameGraphData::from_profile(&profile);
+                upload::decode_profile(&data).map(|profile| {
+                    let flame_data = ProfileGraphs::from_profile(&profile);
                     (profile, flame_data)
                 })
             })
@@ -71,36 +102,50 @@ impl MyService for MyServiceImpl {
         match process_result {
             Ok(Ok(Ok((profile, flame_data)))) => {
                 let profile_id = uuid::Uuid::new_v4().to_string();
+                let metadata = metadata.with_profile_defaults(&profile);
+
+                // Persist raw profile, processed data and metadata, its
+                // time-series rows and its index entry
+                let (storage, timeseries) = (self.storage.clone(), self.timeseries.clone());
+                let index_path = self.index_path.clone();
+                let entry = IndexEntry { id: profile_id.clone(), metadata };
+                let (entry, flame_data, stored_size) = tokio::task::spawn_blocking(move || {
+                    let stored = StoredProfile {
+                        raw: profile.encode_to_vec(),
+                        graphs: serde_json::to_vec(&flame_data)?,
+                        metadata: entry.metadata.clone(),
+                    };
+                    storage.put(&entry.id, &stored)?;
+
+                    // Rows are derived data, rebuilt at startup if missing
+                    if let Err(e) = timeseries.write(&entry.id, &profile, &entry.metadata) {
+                        log::warn!("Failed to write time-series rows of profile {}: {}", entry.id, e);
+                    }
+
+                    ProfileIndex::append(&index_path, &entry)?;
+                    Ok::<_, std::io::Error>((entry, flame_data, stored.graphs.len()))
+                })
+                .await
+                .map_err(|_| Status::internal("Profile processing failed"))?
+                .map_err(|e| Status::internal(e.to_string()))?;
+
+                // Cache processed data, recently uploaded profiles are the most viewed
+                self.profiles.insert(profile_id.clone(), Arc::new(flame_data), stored_size);
+
+                // Make the profile searchable
+                let metadata = entry.metadata.clone();
+                self.index.write().await.push(entry);
+
+                log::info!(
+                    "Profile ID: {}, service: {:?}, instance: {:?}, total time: {:?}",
+                    profile_id, metadata.service_name, metadata.instance_id, start_time.elapsed()
+                );
                 
-                // Create profile directory
-                storage::create_profile_dir(&profile_id)?;
-
-                // Store processed data
-                self.profiles.write().await.insert(profile_id.clone(), json!(flame_data));
-
-                // Save raw profile
-                let mut raw_file = File::create(storage::get_profile_path(&profile_id, "pb"))
-                    .map_err(|e| Status::internal(e.to_string()))?;
-                let mut buf = Vec::new();
-                profile.encode(&mut buf)
-                    .map_err(|e| Status::internal(e.to_string()))?;
-                raw_file.write_all(&buf)
-                    .map_err(|e| Status::internal(e.to_string()))?;
-
-                // Save processed data
-                let json_file = File::create(storage::get_profile_path(&profile_id, "json"))
-                    .map_err(|e| Status::internal(e.to_string()))?;
-                serde_json::to_writer(json_file, &flame_data)
-                    .map_err(|e| Status::internal(e.to_string()))?;
-
-                log::info!("Profile ID: {}, total time: {:?}", profile_id, start_time.elapsed());
-                
-                Ok(Response::new(MyResponse {
-                    result: profile_id.into_bytes()
-                }))
+                Ok(profile_id)
             }
-            Ok(Ok(Err(_))) => {
-                Err(Status::invalid_argument("Invalid profile data"))
+            Ok(Ok(Err(e))) => {
+                log::warn!("Rejected profile upload: {}", e);
+                Err(Status::invalid_argument(format!("Invalid profile data: {}", e)))
             }
             Ok(Err(_)) => {
                 Err(Status::internal("Profile processing failed"))
@@ -112,171 +157,1049 @@ impl MyService for MyServiceImpl {
     }
 }
 
+impl MyServiceImpl {
+    /// Compact and expire stored profiles according to `policy`
+    ///
+    /// Each compaction group is merged and stored before its inputs are
+    /// deleted, so a failure leaves the inputs in place. A group whose
+    /// profiles record different sample types is stored as one merged
+    /// profile per set of sample types. Unreadable inputs are skipped and
+    /// deleted along with the rest of their group.
+    async fn enforce_retention(&self, policy: &RetentionPolicy) -> std::io::Result<()> {
+        let start_time = Instant::now();
+        let entries = self.index.read().await.entries().to_vec();
+        let mut compacted = 0;
+        for group in retention::plan_compaction(policy, &entries, unix_nanos_now()) {
+            let storage = self.storage.clone();
+            let ids = group.ids.clone();
+            let (merged, count) = tokio::task::spawn_blocking(move || {
+                let (profiles, count) = compact_stored_profiles(&*storage, &ids);
+                let merged: Vec<_> = profiles.into_iter()
+                    .map(|profile| (ProfileGraphs::from_profile(&profile), profile))
+                    .collect();
+                (merged, count)
+            }).await.map_err(std::io::Error::other)?;
+            if merged.len() > 1 {
+                log::info!(
+                    "Compacting {} profiles of {} into {} with different sample types",
+                    count, group.metadata.service_name, merged.len()
+                );
+            }
+            for (graphs, profile) in merged {
+                let profile_id = uuid::Uuid::new_v4().to_string();
+                let stored = StoredProfile {
+                    raw: profile.encode_to_vec(),
+                    graphs: serde_json::to_vec(&graphs)?,
+                    metadata: group.metadata.clone(),
+                };
+                let stored_size = stored.graphs.len();
+                let (storage, timeseries) = (self.storage.clone(), self.timeseries.clone());
+                let index_path = self.index_path.clone();
+                let entry = IndexEntry { id: profile_id.clone(), metadata: group.metadata.clone() };
+                let entry = tokio::task::spawn_blocking(move || {
+                    storage.put(&entry.id, &stored)?;
+                    timeseries.write(&entry.id, &profile, &entry.metadata)?;
+                    ProfileIndex::append(&index_path, &entry)?;
+                    Ok::<_, std::io::Error>(entry)
+                }).await.map_err(std::io::Error::other)??;
+                self.profiles.insert(profile_id, Arc::new(graphs), stored_size);
+                self.index.write().await.push(entry);
+            }
+            self.remove_profiles(group.ids).await?;
+            compacted += count;
+        }
+
+        let entries = self.index.read().await.entries().to_vec();
+        let storage = self.storage.clone();
+        let ids: Vec<String> = entries.iter().map(|entry| entry.id.clone()).collect();
+        let sizes = tokio::task::spawn_blocking(move || {
+            ids.into_iter()
+                .filter_map(|id| storage.size(&id).ok().map(|size| (id, size)))
+                .collect::<HashMap<_, _>>()
+        }).await.map_err(std::io::Error::other)?;
+        let expired = retention::plan_expiry(policy, &entries, &sizes, unix_nanos_now());
+        let deleted = expired.len();
+        self.remove_profiles(expired).await?;
+
+        if compacted > 0 || deleted > 0 {
+            let storage = self.storage.clone();
+            let unused = tokio::task::spawn_blocking(move || storage.collect_garbage())
+                .await
+                .map_err(std::io::Error::other)??;
+            if unused > 0 {
+                log::info!("Removed {} symbol tables no longer in use", unused);
+            }
+            log::info!(
+                "Retention compacted {} profiles and deleted {} in {:?}",
+                compacted, deleted, start_time.elapsed()
+            );
+        }
+        Ok(())
+    }
+
+    /// Delete profiles from storage, their time-series rows, the index and
+    /// the in-memory store
+    async fn remove_profiles(&self, ids: Vec<String>) -> std::io::Result<()> {
+        if ids.is_empty() {
+            return Ok(());
+        }
+        let (storage, timeseries) = (self.storage.clone(), self.timeseries.clone());
+        let ids = tokio::task::spawn_blocking(move || {
+            for id in &ids {
+                storage.delete(id)?;
+                timeseries.delete(id)?;
+            }
+            Ok::<_, std::io::Error>(ids)
+        }).await.map_err(std::io::Error::other)??;
+
+        for id in &ids {
+            self.profiles.remove(id);
+        }
+        self.index.write().await.remove(&ids.into_iter().collect())
+    }
+}
+
+/// Current time in nanoseconds since the Unix epoch
+fn unix_nanos_now() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos() as i64)
+}
+
+/// gRPC status for a malformed streamed upload
+///
+/// `RESOURCE_EXHAUSTED` is kept for uploads refused while the server is
+/// busy, which clients retry; a profile over the size limit never fits.
+fn upload_status(error: UploadError) -> Status {
+    match error {
+        UploadError::SizeMismatch { .. } | UploadError::ChecksumMismatch { .. } => Status::data_loss(error.to_string()),
+        _ => Status::invalid_argument(error.to_string()),
+    }
+}
+
+#[tonic::async_trait]
+impl MyService for MyServiceImpl {
+    /// Handles incoming profile requests without metadata
+    /// 
+    /// The stored metadata only carries the time window recorded in the
+    /// profile itself.
+    async fn handle_request(
+        &self,
+        request: Request<MyRequest>,
+    ) -> Result<Response<MyResponse>, Status> {
+        let data = request.into_inner().data;
+        let profile_id = self.ingest_profile(data, ProfileMetadata::default()).await?;
+        Ok(Response::new(MyResponse {
+            result: profile_id.into_bytes()
+        }))
+    }
+
+    /// Handles incoming profile requests carrying service, instance,
+    /// label and timing metadata
+    async fn handle_request_v2(
+        &self,
+        request: Request<MyRequestV2>,
+    ) -> Result<Response<MyResponse>, Status> {
+        let request = request.into_inner();
+        let metadata = request.metadata.map(ProfileMetadata::from).unwrap_or_default();
+        let profile_id = self.ingest_profile(request.data, metadata).await?;
+        Ok(Response::new(MyResponse {
+            result: profile_id.into_bytes()
+        }))
+    }
+
+    /// Handles several profiles uploaded together, e.g. by the agent
+    ///
+    /// Each profile is ingested like `HandleRequestV2`; a rejected profile
+    /// is reported in its result and doesn't keep the others from being
+    /// stored.
+    async fn handle_batch(
+        &self,
+        request: Request<BatchRequest>,
+    ) -> Result<Response<BatchResponse>, Status> {
+        let mut results = Vec::new();
+        for profile in request.into_inner().profiles {
+            let metadata = profile.metadata.map(ProfileMetadata::from).unwrap_or_default();
+            results.push(match self.ingest_profile(profile.data, metadata).await {
+                Ok(profile_id) => BatchResult { profile_id, error: String::new() },
+                Err(status) => BatchResult { profile_id: String::new(), error: status.message().to_string() },
+            });
+        }
+        Ok(Response::new(BatchResponse { results }))
+    }
+
+    /// Handles chunked uploads of profiles too large for one message
+    ///
+    /// The profile is ingested like `HandleRequestV2` once the stream ends
+    /// with a commit whose size and checksum match the received data. Each
+    /// upload is buffered until then, so only `max_concurrent_uploads` are
+    /// received at once and further ones are refused.
+    async fn upload_profile(
+        &self,
+        request: Request<Streaming<UploadProfileChunk>>,
+    ) -> Result<Response<MyResponse>, Status> {
+        let _slot = self.upload_slots.clone().try_acquire_owned()
+            .map_err(|_| Status::resource_exhausted("too many uploads in progress, retry later"))?;
+        let mut stream = request.into_inner();
+        let mut upload = UploadAssembler::new(self.max_upload_bytes);
+        while let Some(chunk) = stream.message().await? {
+            upload.push(chunk).map_err(upload_status)?;
+        }
+        let (data, metadata) = upload.finish().map_err(upload_status)?;
+        log::info!("Received streamed upload of {} bytes", data.len());
+        let profile_id = self.ingest_profile(data, metadata).await?;
+        Ok(Response::new(MyResponse {
+            result: profile_id.into_bytes()
+        }))
+    }
+}
+
+#[tonic::async_trait]
+impl Pprof for MyServiceImpl {
+    /// Handles typed profile uploads
+    ///
+    /// The profile is re-encoded and ingested like `HandleRequestV2`; the
+    /// response acknowledges it with its ID and sample count.
+    async fn save_profile(
+        &self,
+        request: Request<SaveProfileRequest>,
+    ) -> Result<Response<SaveProfileResponse>, Status> {
+        let request = request.into_inner();
+        let profile = request.profile
+            .ok_or_else(|| Status::invalid_argument("Missing profile"))?;
+        let metadata = request.metadata.map(ProfileMetadata::from).unwrap_or_default();
+        let sample_count = profile.sample.len() as u64;
+        let profile_id = self.ingest_profile(prost::Message::encode_to_vec(&profile), metadata).await?;
+        Ok(Response::new(SaveProfileResponse { profile_id, sample_count }))
+    }
+}
+
+/// HTTP handler for profiles pushed in the style of Pyroscope's ingest API
+///
+/// # Arguments
+/// * `params` - Application name with type suffix and labels, time window
+///   and agent name, see [`IngestParams`]
+/// * `body` - Raw or gzip-compressed pprof data
+///
+/// # Returns
+/// The ID of the stored profile
+async fn ingest(
+    params: web::Query<IngestParams>,
+    body: web::Bytes,
+    service: web::Data<MyServiceImpl>,
+) -> HttpResponse {
+    let metadata = match params.metadata() {
+        Ok(metadata) => metadata,
+        Err(e) => return HttpResponse::BadRequest().json(json!({"error": e})),
+    };
+    if body.is_empty() {
+        return HttpResponse::BadRequest().json(json!({"error": "Empty profile body"}));
+    }
+    log::info!("HTTP ingest of {} bytes for {}", body.len(), metadata.service_name);
+
+    match service.ingest_profile(body.to_vec(), metadata).await {
+        Ok(profile_id) => HttpResponse::Ok().json(json!({"profileId": profile_id})),
+        Err(status) => {
+            let error = json!({"error": status.message()});
+            match status.code() {
+                Code::InvalidArgument => HttpResponse::BadRequest().json(error),
+                Code::DeadlineExceeded => HttpResponse::GatewayTimeout().json(error),
+                _ => HttpResponse::InternalServerError().json(error),
+            }
+        }
+    }
+}
+
+/// Query parameter selecting one of a profile's value columns by its
+/// sample type name, e.g. `cpu` or `samples`
+#[derive(Deserialize)]
+struct SampleTypeParams {
+    sample_type: Option<String>,
+}
+
+/// Response for a sample type the profile doesn't record
+fn unknown_sample_type(name: &str, available: &[&str]) -> HttpResponse {
+    HttpResponse::BadRequest().json(json!({
+        "error": format!("Unknown sample type: {}", name),
+        "sampleTypes": available
+    }))
+}
+
+/// Resolve the requested sample type to a value column of `profile`
+fn select_column(profile: &Profile, sample_type: Option<&str>) -> Result<usize, HttpResponse> {
+    flamegraph::sample_column(profile, sample_type).ok_or_else(|| {
+        let available: Vec<&str> = flamegraph::sample_types(profile).into_iter().map(|(ty, _)| ty).collect();
+        unknown_sample_type(sample_type.unwrap_or_default(), &available)
+    })
+}
+
+/// Query parameters narrowing a flame graph to the samples with some label
+/// values, or splitting it by one label
+#[derive(Deserialize)]
+struct SampleLabelParams {
+    /// Selectors on sample labels, e.g. `thread=mixed-memory,endpoint!=/health`
+    sample_labels: Option<String>,
+    /// Sample label to build one flame graph per value of, e.g. `thread`
+    group_by: Option<String>,
+}
+
+impl SampleLabelParams {
+    fn is_empty(&self) -> bool {
+        self.sample_labels.is_none() && self.group_by.is_none()
+    }
+
+    /// Parse `sample_labels`
+    ///
+    /// # Returns
+    /// * The selectors, or a ready 400 response for a malformed one
+    fn matchers(&self) -> Result<Vec<LabelMatcher>, HttpResponse> {
+        match self.sample_labels.as_deref().map(LabelMatcher::parse_list).transpose() {
+            Ok(matchers) => Ok(matchers.unwrap_or_default()),
+            Err(e) => Err(HttpResponse::BadRequest().json(json!({"error": e}))),
+        }
+    }
+}
+
+/// Flame graph of the samples selected by [`SampleLabelParams`]
+enum LabeledGraph {
+    Single(FlameGraphData),
+    /// One graph per value of the `group_by` label
+    Grouped(String, BTreeMap<String, FlameGraphData>),
+}
+
+impl LabeledGraph {
+    /// # Returns
+    /// * The graph or groups, or a ready 400 response for a bad selector
+    fn build(profile: &Profile, column: usize, params: &SampleLabelParams) -> Result<Self, HttpResponse> {
+        let matchers = params.matchers()?;
+        let keep = |sample: &Sample| {
+            matchers.iter()
+                .all(|m| m.matches_value(flamegraph::sample_label(profile, sample, &m.key).as_deref()))
+        };
+        Ok(match &params.group_by {
+            Some(key) => LabeledGraph::Grouped(key.clone(), flamegraph::group_by_label(profile, column, key, keep)),
+            None => LabeledGraph::Single(FlameGraphData::from_samples(profile, column, keep)),
+        })
+    }
+
+    /// Add the graph to `response` as `flameGraph`, or the groups as
+    /// `groupBy` and `groups`
+    fn insert_into(self, response: &mut serde_json::Map<String, serde_json::Value>) {
+        match self {
+            LabeledGraph::Single(graph) => {
+                response.insert("flameGraph".into(), json!(graph));
+            }
+            LabeledGraph::Grouped(key, groups) => {
+                response.insert("groupBy".into(), json!(key));
+                response.insert("groups".into(), json!(groups));
+            }
+        }
+    }
+}
+
 /// HTTP handler for retrieving processed profiles
 /// 
 /// # Arguments
 /// * `id` - Profile ID from URL path
+/// * `params` - Optional `sample_type`; defaults to the profile's default
+/// * `label_params` - Optional `sample_labels` filter and `group_by` label;
+///   the graph is then rebuilt from the raw profile
 /// * `profiles` - Shared store of processed profiles
 /// 
 /// # Returns
-/// * JSON response with profile data or 404 error
+/// * JSON flame graph for the sample type, or `{"groupBy", "groups"}` when
+///   grouping; 400 for an unknown sample type or bad selector, or 404 error
 async fn get_profile(
     id: web::Path<String>,
+    params: web::Query<SampleTypeParams>,
+    label_params: web::Query<SampleLabelParams>,
     profiles: web::Data<ProfileStore>,
+    storage: web::Data<SharedStorage>,
 ) -> HttpResponse {
     log::info!("HTTP GET request for profile ID: {}", id);
+
+    if !label_params.is_empty() {
+        let profile = match load_raw_profile(&storage, &id).await {
+            Ok(profile) => profile,
+            Err(response) => return response,
+        };
+        let column = match select_column(&profile, params.sample_type.as_deref()) {
+            Ok(column) => column,
+            Err(response) => return response,
+        };
+        return match LabeledGraph::build(&profile, column, &label_params) {
+            Ok(LabeledGraph::Single(graph)) => HttpResponse::Ok().json(graph),
+            Ok(LabeledGraph::Grouped(key, groups)) => HttpResponse::Ok().json(json!({
+                "groupBy": key,
+                "groups": groups,
+            })),
+            Err(response) => response,
+        };
+    }
     
-    if let Some(profile) = profiles.read().await.get(&*id) {
-        log::info!("Found profile {}, returning data", id);
-        HttpResponse::Ok().json(profile)
-    } else {
-        log::warn!("Profile {} not found", id);
-        HttpResponse::NotFound().json(json!({"error": "Profile not found"}))
+    match load_graphs(&profiles, &storage, &id).await {
+        Ok(profile) => {
+            log::info!("Found profile {}, returning data", id);
+            match profile.get(params.sample_type.as_deref()) {
+                Some(graph) => HttpResponse::Ok().json(graph),
+                None => unknown_sample_type(params.sample_type.as_deref().unwrap_or_default(), &profile.sample_types()),
+            }
+        }
+        Err(e) => graphs_error(&id, e),
     }
 }
 
-#[derive(Serialize, Debug, Clone)]
-struct FlameGraphNode {
-    id: String,
-    name: String,
-    value: u64,
-    children: Vec<FlameGraphNode>
+/// Read and decode a stored raw profile
+///
+/// # Returns
+/// * The profile, or a ready 404/500 response
+async fn load_raw_profile(storage: &SharedStorage, id: &str) -> Result<Profile, HttpResponse> {
+    let (storage, owned_id) = (storage.clone(), id.to_string());
+    let bytes = match web::block(move || storage.get(&owned_id, ProfilePart::Raw)).await {
+        Ok(Ok(bytes)) => bytes,
+        Ok(Err(e)) if e.kind() == std::io::ErrorKind::NotFound => {
+            log::warn!("Raw profile {} not found", id);
+            return Err(HttpResponse::NotFound().json(json!({"error": "Profile not found"})));
+        }
+        Ok(Err(e)) => {
+            log::error!("Failed to read raw profile {}: {}", id, e);
+            return Err(HttpResponse::InternalServerError().json(json!({"error": "Failed to read profile"})));
+        }
+        Err(e) => {
+            log::error!("Profile read task failed: {}", e);
+            return Err(HttpResponse::InternalServerError().json(json!({"error": "Failed to read profile"})));
+        }
+    };
+    Profile::decode(&bytes[..]).map_err(|e| {
+        log::error!("Stored profile {} is corrupt: {}", id, e);
+        HttpResponse::InternalServerError().json(json!({"error": "Stored profile is corrupt"}))
+    })
 }
 
-#[derive(Serialize, Debug, Clone)]
-struct FlameGraphData {
-    name: String,
-    value: u64,
-    children: Vec<FlameGraphNode>
+/// HTTP handler listing the sample labels of a profile
+///
+/// # Returns
+/// * `{"labels": {key: [values]}}`, or 404 error
+async fn get_profile_labels(id: web::Path<String>, storage: web::Data<SharedStorage>) -> HttpResponse {
+    match load_raw_profile(&storage, &id).await {
+        Ok(profile) => HttpResponse::Ok().json(json!({
+            "labels": flamegraph::sample_label_values(&profile)
+        })),
+        Err(response) => response,
+    }
 }
 
-impl FlameGraphData {
-    fn from_profile(profile: &Profile) -> Self {
-        let mut function_samples: HashMap<u64, u64> = HashMap::new();
-        let mut function_children: HashMap<u64, HashSet<u64>> = HashMap::new();
-        
-        // Create location to function ID lookup
-        let location_to_function: HashMap<u64, u64> = profile.location.iter()
-            .filter_map(|loc| {
-                loc.line.first().map(|line| (loc.id, line.function_id))
-            })
-            .collect();
-
-        // Process samples and maintain full call stacks
-        for sample in &profile.sample {
-            // Process entire call stack
-            let stack: Vec<_> = sample.location_id.iter()
-                .filter_map(|&loc_id| location_to_function.get(&loc_id))
-                .copied()
-                .collect();
-
-            // Update sample counts
-            for &func_id in &stack {
-                *function_samples.entry(func_id).or_default() += sample.value[0] as u64;
+/// HTTP handler serving the raw profile as gzip-compressed pprof
+///
+/// The format matches Go's `net/http/pprof` endpoints, so the URL can be
+/// passed straight to `go tool pprof`.
+async fn get_raw_profile(id: web::Path<String>, storage: web::Data<SharedStorage>) -> HttpResponse {
+    let profile = match load_raw_profile(&storage, &id).await {
+        Ok(profile) => profile,
+        Err(response) => return response,
+    };
+    match export::to_gzipped_pprof(&profile) {
+        Ok(body) => HttpResponse::Ok()
+            .content_type("application/octet-stream")
+            .insert_header(("Content-Disposition", format!("attachment; filename=\"{}.pb.gz\"", id)))
+            .body(body),
+        Err(e) => {
+            log::error!("Failed to compress profile {}: {}", id, e);
+            HttpResponse::InternalServerError().json(json!({"error": "Failed to compress profile"}))
+        }
+    }
+}
+
+/// HTTP handler serving folded stacks, as read by `flamegraph.pl` and `inferno`
+async fn get_collapsed_profile(
+    id: web::Path<String>,
+    params: web::Query<SampleTypeParams>,
+    storage: web::Data<SharedStorage>,
+) -> HttpResponse {
+    let profile = match load_raw_profile(&storage, &id).await {
+        Ok(profile) => profile,
+        Err(response) => return response,
+    };
+    match select_column(&profile, params.sample_type.as_deref()) {
+        Ok(column) => HttpResponse::Ok()
+            .content_type("text/plain; charset=utf-8")
+            .body(export::to_collapsed(&profile, column)),
+        Err(response) => response,
+    }
+}
+
+/// HTTP handler serving the profile in speedscope's file format
+async fn get_speedscope_profile(id: web::Path<String>, storage: web::Data<SharedStorage>) -> HttpResponse {
+    match load_raw_profile(&storage, &id).await {
+        Ok(profile) => HttpResponse::Ok()
+            .insert_header(("Content-Disposition", format!("attachment; filename=\"{}.speedscope.json\"", id)))
+            .json(export::to_speedscope(&profile, &id)),
+        Err(response) => response,
+    }
+}
+
+/// HTTP handler rendering an SVG flame graph on the server
+async fn get_flamegraph_svg(
+    id: web::Path<String>,
+    params: web::Query<SampleTypeParams>,
+    storage: web::Data<SharedStorage>,
+) -> HttpResponse {
+    let profile = match load_raw_profile(&storage, &id).await {
+        Ok(profile) => profile,
+        Err(response) => return response,
+    };
+    let column = match select_column(&profile, params.sample_type.as_deref()) {
+        Ok(column) => column,
+        Err(response) => return response,
+    };
+    let title = format!("Profile {}", id);
+    match tokio::task::spawn_blocking(move || export::to_svg(&profile, column, &title)).await {
+        Ok(Ok(svg)) => HttpResponse::Ok().content_type("image/svg+xml").body(svg),
+        Ok(Err(e)) => {
+            log::error!("Failed to render flame graph for {}: {}", id, e);
+            HttpResponse::InternalServerError().json(json!({"error": "Failed to render flame graph"}))
+        }
+        Err(e) => {
+            log::error!("Flame graph task failed: {}", e);
+            HttpResponse::InternalServerError().json(json!({"error": "Failed to render flame graph"}))
+        }
+    }
+}
+
+/// Query parameters for comparing two profiles
+#[derive(Deserialize)]
+struct DiffParams {
+    base: String,
+    target: String,
+    /// Scale the base profile to the target's total sample count
+    #[serde(default)]
+    normalize: bool,
+    /// Sample type to compare; each profile's default if not given
+    sample_type: Option<String>,
+}
+
+/// HTTP handler for differential flame graphs
+/// 
+/// # Returns
+/// * JSON call tree where each node has base, target and delta values,
+///   400 if either profile lacks the sample type or 404 if either profile
+///   is missing
+async fn diff_profiles(
+    params: web::Query<DiffParams>,
+    profiles: web::Data<ProfileStore>,
+    storage: web::Data<SharedStorage>,
+) -> HttpResponse {
+    log::info!("HTTP GET diff of {} against {}", params.target, params.base);
+
+    let (base_graphs, target_graphs) = match (
+        load_graphs(&profiles, &storage, &params.base).await,
+        load_graphs(&profiles, &storage, &params.target).await,
+    ) {
+        (Ok(base), Ok(target)) => (base, target),
+        (Err(e), _) => return graphs_error(&params.base, e),
+        (_, Err(e)) => return graphs_error(&params.target, e),
+    };
+    fn lookup<'a>(graphs: &'a ProfileGraphs, sample_type: Option<&str>) -> Result<&'a FlameGraphData, HttpResponse> {
+        graphs.get(sample_type)
+            .ok_or_else(|| unknown_sample_type(sample_type.unwrap_or_default(), &graphs.sample_types()))
+    }
+    let sample_type = params.sample_type.as_deref();
+    let (base, target) = match (lookup(&base_graphs, sample_type), lookup(&target_graphs, sample_type)) {
+        (Ok(base), Ok(target)) => (base, target),
+        (Err(response), _) | (_, Err(response)) => return response,
+    };
+
+    HttpResponse::Ok().json(DiffData::from_flame_graphs(base, target, params.normalize))
+}
+
+/// Query parameters for listing profiles
+/// 
+/// `labels` is a comma separated selector list such as `version=2,region!=eu`;
+/// `from` and `to` are Unix milliseconds.
+#[derive(Deserialize)]
+struct ListProfilesParams {
+    service: Option<String>,
+    #[serde(rename = "type")]
+    profile_type: Option<String>,
+    labels: Option<String>,
+    from: Option<i64>,
+    to: Option<i64>,
+    #[serde(default)]
+    sort: SortField,
+    #[serde(default)]
+    order: SortOrder,
+    #[serde(default)]
+    offset: usize,
+    limit: Option<usize>,
+}
+
+/// HTTP handler for listing and searching stored profiles
+/// 
+/// # Returns
+/// * JSON page of matching profiles with their metadata, or 400 for a
+///   malformed label selector
+async fn list_profiles(
+    params: web::Query<ListProfilesParams>,
+    index: web::Data<SharedIndex>,
+) -> HttpResponse {
+    let params = params.into_inner();
+    let labels = match params.labels.as_deref().map(LabelMatcher::parse_list).transpose() {
+        Ok(labels) => labels.unwrap_or_default(),
+        Err(e) => return HttpResponse::BadRequest().json(json!({"error": e})),
+    };
+
+    let query = ProfileQuery {
+        service: params.service,
+        profile_type: params.profile_type,
+        labels,
+        from: params.from,
+        to: params.to,
+        sort: params.sort,
+        order: params.order,
+        offset: params.offset,
+        limit: params.limit,
+    };
+    HttpResponse::Ok().json(index.read().await.query(&query))
+}
+
+/// Query parameters for aggregating a service's profiles
+///
+/// `from` and `to` are Unix milliseconds and default to the last hour;
+/// `format` is `json` (flame graph) or `pb` (merged pprof download).
+#[derive(Deserialize)]
+struct AggregateParams {
+    from: Option<i64>,
+    to: Option<i64>,
+    #[serde(rename = "type", default = "default_aggregate_type")]
+    profile_type: String,
+    labels: Option<String>,
+    sample_type: Option<String>,
+    #[serde(default)]
+    format: AggregateFormat,
+}
+
+fn default_aggregate_type() -> String {
+    "cpu".to_string()
+}
+
+#[derive(Deserialize, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum AggregateFormat {
+    #[default]
+    Json,
+    Pb,
+}
+
+/// Read and merge the raw profiles with the given IDs
+///
+/// Profiles that can't be read are logged and skipped; mismatched sample
+/// types fail the whole merge.
+fn merge_stored_profiles(storage: &dyn ProfileStorage, ids: &[String]) -> Result<(Profile, usize), MergeError> {
+    let mut merger = ProfileMerger::new();
+    let mut merged = 0;
+    for id in ids {
+        let profile = storage.get(id, ProfilePart::Raw)
+            .map_err(|e| e.to_string())
+            .and_then(|bytes| Profile::decode(&bytes[..]).map_err(|e| e.to_string()));
+        match profile {
+            Ok(profile) => {
+                merger.add(&profile)?;
+                merged += 1;
             }
+            Err(e) => log::warn!("Skipping profile {} in aggregate: {}", id, e),
+        }
+    }
+    Ok((merger.finish(), merged))
+}
 
-            // Build parent-child relationships from the stack
-            for window in stack.windows(2) {
-                let [caller_id, callee_id] = window else { continue };
-                function_children.entry(*caller_id)
-                    .or_default()
-                    .insert(*callee_id);
+/// Read the raw profiles with the given IDs and merge those that record
+/// the same sample types
+///
+/// Profiles that can't be read are logged and skipped. Returns one merged
+/// profile per set of sample types, in order of first appearance, and the
+/// number of profiles merged.
+fn compact_stored_profiles(storage: &dyn ProfileStorage, ids: &[String]) -> (Vec<Profile>, usize) {
+    let mut mergers: Vec<ProfileMerger> = Vec::new();
+    let mut merged = 0;
+    for id in ids {
+        let profile = storage.get(id, ProfilePart::Raw)
+            .map_err(|e| e.to_string())
+            .and_then(|bytes| Profile::decode(&bytes[..]).map_err(|e| e.to_string()));
+        match profile {
+            Ok(profile) => {
+                // A mismatch is detected before the merger changes
+                if !mergers.iter_mut().any(|merger| merger.add(&profile).is_ok()) {
+                    let mut merger = ProfileMerger::new();
+                    merger.add(&profile).expect("an empty merger accepts any profile");
+                    mergers.push(merger);
+                }
+                merged += 1;
             }
+            Err(e) => log::warn!("Skipping profile {} in compaction: {}", id, e),
         }
+    }
+    (mergers.into_iter().map(ProfileMerger::finish).collect(), merged)
+}
 
-        // Find root functions (those never called by others)
-        let called_functions: HashSet<_> = function_children.values()
-            .flat_map(|children| children.iter())
-            .copied()
-            .collect();
-
-        let root_functions: Vec<_> = profile.function.iter()
-            .filter(|f| !called_functions.contains(&f.id))
-            .map(|f| f.id)
-            .collect();
-
-        // Build tree
-        let total_value = profile.sample.iter().map(|s| s.value[0] as u64).sum();
-        let children = root_functions.iter()
-            .filter_map(|&id| {
-                let func = profile.function.iter().find(|f| f.id == id)?;
-                let name = profile.string_table.get(func.name as usize)
-                    .unwrap_or(&"unknown".to_string()).to_string();
-                let value = *function_samples.get(&id).unwrap_or(&0);
-
-                Some(FlameGraphNode {
-                    id: id.to_string(),
-                    name,
-                    value,
-                    children: build_children(id, profile, &function_samples, &function_children),
-                })
-            })
-            .collect();
+/// HTTP handler merging every profile of a service within a time range
+///
+/// # Returns
+/// * JSON flame graph of the merged profile, split into `groups` with
+///   `group_by`, or the merged pprof as a download with `format=pb`
+/// * 400 for a malformed selector or profiles that can't be merged
+async fn aggregate_profiles(
+    service: web::Path<String>,
+    params: web::Query<AggregateParams>,
+    label_params: web::Query<SampleLabelParams>,
+    index: web::Data<SharedIndex>,
+    storage: web::Data<SharedStorage>,
+) -> HttpResponse {
+    let params = params.into_inner();
+    let labels = match params.labels.as_deref().map(LabelMatcher::parse_list).transpose() {
+        Ok(labels) => labels.unwrap_or_default(),
+        Err(e) => return HttpResponse::BadRequest().json(json!({"error": e})),
+    };
 
-        FlameGraphData {
-            name: "root".to_string(),
-            value: total_value,
-            children
+    let to = params.to.unwrap_or_else(|| {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as i64)
+    });
+    let from = params.from.unwrap_or(to - 3_600_000);
+    let query = ProfileQuery {
+        service: Some(service.clone()),
+        profile_type: Some(params.profile_type.clone()),
+        labels,
+        from: Some(from),
+        to: Some(to),
+        order: SortOrder::Asc,
+        ..Default::default()
+    };
+    let ids: Vec<String> = index.read().await.matching(&query)
+        .into_iter()
+        .map(|entry| entry.id.clone())
+        .collect();
+    if ids.len() > MAX_MERGED_PROFILES {
+        return HttpResponse::BadRequest().json(json!({
+            "error": format!("{} profiles match, at most {} can be aggregated; use a shorter range",
+                ids.len(), MAX_MERGED_PROFILES)
+        }));
+    }
+    log::info!("Aggregating {} {} profiles of {}", ids.len(), params.profile_type, service);
+
+    let storage = storage.get_ref().clone();
+    let (profile, merged) = match tokio::task::spawn_blocking(move || merge_stored_profiles(&*storage, &ids)).await {
+        Ok(Ok(result)) => result,
+        Ok(Err(e)) => return HttpResponse::BadRequest().json(json!({"error": e.to_string()})),
+        Err(e) => {
+            log::error!("Aggregation task failed: {}", e);
+            return HttpResponse::InternalServerError().json(json!({"error": "Aggregation failed"}));
         }
+    };
+
+    if params.format == AggregateFormat::Pb {
+        return HttpResponse::Ok()
+            .content_type("application/octet-stream")
+            .insert_header((
+                "Content-Disposition",
+                format!("attachment; filename=\"{}-{}-{}.pb\"", service, from, to),
+            ))
+            .body(profile.encode_to_vec());
     }
+
+    let column = match select_column(&profile, params.sample_type.as_deref()) {
+        Ok(column) => column,
+        Err(response) => return response,
+    };
+    let graph = match LabeledGraph::build(&profile, column, &label_params) {
+        Ok(graph) => graph,
+        Err(response) => return response,
+    };
+    let mut response = serde_json::Map::new();
+    response.insert("service".into(), json!(*service));
+    response.insert("type".into(), json!(params.profile_type));
+    response.insert("from".into(), json!(from));
+    response.insert("to".into(), json!(to));
+    response.insert("profiles".into(), json!(merged));
+    graph.insert_into(&mut response);
+    HttpResponse::Ok().json(response)
 }
 
-fn build_children(
-    func_id: u64,
-    profile: &Profile,
-    function_samples: &HashMap<u64, u64>,
-    function_children: &HashMap<u64, HashSet<u64>>,
-) -> Vec<FlameGraphNode> {
-    let mut result = Vec::new();
-    let mut stack = vec![(func_id, 0)];  // (func_id, depth)
-    let mut visited = HashSet::new();
+/// Load a single persisted profile from storage
+/// 
+/// Prefers the processed flame graphs; if they are missing, unreadable or
+/// in the format from before per sample type graphs, the raw profile is
+/// decoded and processed again.
+///
+/// # Returns
+/// * The graphs and the size of their JSON encoding; `NotFound` if the
+///   profile doesn't exist
+fn load_profile(storage: &dyn ProfileStorage, profile_id: &str) -> std::io::Result<(ProfileGraphs, usize)> {
+    let json_err = match storage.get(profile_id, ProfilePart::Graphs) {
+        Ok(bytes) => match serde_json::from_slice(&bytes) {
+            Ok(value) => return Ok((value, bytes.len())),
+            Err(e) => e.to_string(),
+        },
+        Err(e) => e.to_string(),
+    };
 
-    while let Some((current_id, depth)) = stack.pop() {
-        // Prevent infinite recursion and limit depth
-        if depth > 50 || !visited.insert(current_id) {
-            continue;
-        }
+    let bytes = storage.get(profile_id, ProfilePart::Raw)?;
+    let profile = Profile::decode(&bytes[..]).map_err(|e| std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("profile.json: {}, profile.pb: {}", json_err, e),
+    ))?;
+    log::info!("Rebuilt flame graph for profile {} from raw data", profile_id);
+    let graphs = ProfileGraphs::from_profile(&profile);
+    let size = serde_json::to_vec(&graphs)?.len();
+    Ok((graphs, size))
+}
 
-        if let Some(children) = function_children.get(&current_id) {
-            for &child_id in children {
-                if let Some(func) = profile.function.iter().find(|f| f.id == child_id) {
-                    let name = profile.string_table.get(func.name as usize)
-                        .unwrap_or(&"unknown".to_string()).to_string();
-                    let value = *function_samples.get(&child_id).unwrap_or(&0);
-
-                    let node = FlameGraphNode {
-                        id: child_id.to_string(),
-                        name,
-                        value,
-                        children: Vec::new(),  // Will be filled in next iterations
-                    };
+/// Processed graphs of a profile from the cache, loading them from storage
+/// on a miss
+async fn load_graphs(
+    profiles: &ProfileCache,
+    storage: &SharedStorage,
+    profile_id: &str,
+) -> std::io::Result<Arc<ProfileGraphs>> {
+    if let Some(graphs) = profiles.get(profile_id) {
+        return Ok(graphs);
+    }
+    let (storage, id) = (storage.clone(), profile_id.to_string());
+    let (graphs, size) = web::block(move || load_profile(&*storage, &id))
+        .await
+        .map_err(std::io::Error::other)??;
+    let graphs = Arc::new(graphs);
+    profiles.insert(profile_id.to_string(), graphs.clone(), size);
+    Ok(graphs)
+}
 
-                    result.push(node);
-                    stack.push((child_id, depth + 1));
-                }
-            }
+/// Response for a profile whose graphs couldn't be loaded: 404 if it
+/// doesn't exist, 500 otherwise
+fn graphs_error(profile_id: &str, error: std::io::Error) -> HttpResponse {
+    if error.kind() == std::io::ErrorKind::NotFound {
+        log::warn!("Profile {} not found", profile_id);
+        HttpResponse::NotFound().json(json!({"error": format!("Profile {} not found", profile_id)}))
+    } else {
+        log::error!("Failed to load profile {}: {}", profile_id, error);
+        HttpResponse::InternalServerError().json(json!({"error": "Failed to read profile"}))
+    }
+}
+
+/// Query parameters for a time series over stored samples
+///
+/// `from` and `to` are Unix milliseconds and default to the last day, with
+/// `from` rounded down to a whole `step`; `step` is the bucket width in
+/// seconds and defaults to a minute.
+#[derive(Deserialize)]
+struct TimeseriesParams {
+    function: Option<String>,
+    service: Option<String>,
+    #[serde(rename = "type", default = "default_aggregate_type")]
+    profile_type: String,
+    labels: Option<String>,
+    sample_type: Option<String>,
+    from: Option<i64>,
+    to: Option<i64>,
+    step: Option<i64>,
+}
+
+/// Buckets of a time series with the range they cover
+struct Timeseries {
+    from: i64,
+    to: i64,
+    step: i64,
+    /// Number of profiles the rows were read from
+    profiles: usize,
+    points: Vec<Point>,
+}
+
+/// Select profiles from the index and sum their columnar rows per bucket
+///
+/// # Returns
+/// * The time series, or a ready 400/500 response
+async fn read_timeseries(
+    params: &TimeseriesParams,
+    index: &SharedIndex,
+    timeseries: &Arc<TimeseriesStore>,
+) -> Result<Timeseries, HttpResponse> {
+    let labels = match params.labels.as_deref().map(LabelMatcher::parse_list).transpose() {
+        Ok(labels) => labels.unwrap_or_default(),
+        Err(e) => return Err(HttpResponse::BadRequest().json(json!({"error": e}))),
+    };
+    let to = params.to.unwrap_or_else(|| unix_nanos_now() / 1_000_000);
+    let step = params.step.unwrap_or(60);
+    let query = TimeseriesQuery::from_millis(
+        params.function.clone(),
+        params.sample_type.clone(),
+        params.from.unwrap_or(to - 86_400_000),
+        to,
+        step,
+    );
+    let query = match query {
+        Ok(query) => query,
+        Err(e) => return Err(HttpResponse::BadRequest().json(json!({"error": e}))),
+    };
+    // Rounded down to a whole step
+    let from = query.from / 1_000_000;
+
+    let ids: Vec<String> = index.read().await
+        .matching(&ProfileQuery {
+            service: params.service.clone(),
+            profile_type: Some(params.profile_type.clone()),
+            labels,
+            from: Some(from),
+            to: Some(to),
+            ..Default::default()
+        })
+        .into_iter()
+        .map(|entry| entry.id.clone())
+        .collect();
+
+    let timeseries = timeseries.clone();
+    let profiles = ids.len();
+    match web::block(move || timeseries.query(&ids, &query)).await {
+        Ok(Ok(points)) => Ok(Timeseries { from, to, step, profiles, points }),
+        Ok(Err(e)) => {
+            log::error!("Time-series query failed: {}", e);
+            Err(HttpResponse::InternalServerError().json(json!({"error": "Failed to read time series"})))
+        }
+        Err(e) => {
+            log::error!("Time-series task failed: {}", e);
+            Err(HttpResponse::InternalServerError().json(json!({"error": "Failed to read time series"})))
         }
     }
+}
+
+/// HTTP handler summing sample values per time bucket
+///
+/// Profiles are selected from the index, then only their columnar rows are
+/// read; a `function` limits the sum to stacks passing through it.
+///
+/// # Returns
+/// * JSON with one point per bucket, `timestamp` in Unix milliseconds
+/// * 400 for a malformed selector or too many buckets
+async fn query_timeseries(
+    params: web::Query<TimeseriesParams>,
+    index: web::Data<SharedIndex>,
+    timeseries: web::Data<Arc<TimeseriesStore>>,
+) -> HttpResponse {
+    let series = match read_timeseries(&params, &index, &timeseries).await {
+        Ok(series) => series,
+        Err(response) => return response,
+    };
+    HttpResponse::Ok().json(json!({
+        "function": params.function,
+        "service": params.service,
+        "type": params.profile_type,
+        "sampleType": params.sample_type,
+        "from": series.from,
+        "to": series.to,
+        "step": series.step,
+        "profiles": series.profiles,
+        "points": series.points,
+    }))
+}
+
+/// HTTP handler returning a service's samples per time bucket, for a
+/// sparkline of its activity
+///
+/// Takes the same parameters as `/api/query/timeseries` except `service`.
+/// CPU and wall-clock timelines count samples unless `sample_type` says
+/// otherwise; other profile types sum their default sample type.
+///
+/// # Returns
+/// * JSON with one point per bucket and the `total` over the whole range;
+///   with `function`, only samples whose stacks pass through it count
+/// * 400 for a malformed selector or too many buckets
+async fn service_timeline(
+    service: web::Path<String>,
+    params: web::Query<TimeseriesParams>,
+    index: web::Data<SharedIndex>,
+    timeseries: web::Data<Arc<TimeseriesStore>>,
+) -> HttpResponse {
+    let mut params = params.into_inner();
+    params.service = Some(service.into_inner());
+    params.sample_type = timeline_sample_type(&params.profile_type, params.sample_type.take());
+    let series = match read_timeseries(&params, &index, &timeseries).await {
+        Ok(series) => series,
+        Err(response) => return response,
+    };
+    let total = profiling::timeseries::total(&series.points);
+    HttpResponse::Ok().json(json!({
+        "service": params.service,
+        "type": params.profile_type,
+        "function": params.function,
+        "sampleType": params.sample_type,
+        "from": series.from,
+        "to": series.to,
+        "step": series.step,
+        "profiles": series.profiles,
+        "total": total,
+        "points": series.points,
+    }))
+}
 
-    result
+/// HTTP handler reporting the profile cache's hit/miss counters and size
+async fn cache_stats(profiles: web::Data<ProfileStore>) -> HttpResponse {
+    HttpResponse::Ok().json(profiles.stats())
 }
 
 #[derive(Deserialize, Serialize)]
 struct TaskRequest {
     #[serde(rename = "type")]
     task_type: String,
+    /// Profile on-CPU or wall-clock time, forwarded to the daemon
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    mode: Option<ProfileMode>,
+    /// Base URL of the daemon to run on; one of the configured targets
+    #[serde(default, skip_serializing)]
+    daemon: Option<String>,
+}
+
+/// Task daemons that `run_task` can forward to
+struct DaemonTargets {
+    urls: Vec<String>,
+    next: AtomicUsize,
+}
+
+impl DaemonTargets {
+    /// Pick the requested daemon, or the next one in round-robin order
+    fn select(&self, requested: Option<&str>) -> Option<&str> {
+        match requested {
+            Some(url) => {
+                let url = url.trim_end_matches('/');
+                self.urls.iter().map(String::as_str).find(|u| *u == url)
+            }
+            None => {
+                let idx = self.next.fetch_add(1, Ordering::Relaxed) % self.urls.len();
+                Some(&self.urls[idx])
+            }
+        }
+    }
+}
+
+/// HTTP handler listing the configured task daemons
+async fn list_daemons(daemons: web::Data<DaemonTargets>) -> HttpResponse {
+    HttpResponse::Ok().json(json!({"daemons": daemons.urls}))
 }
 
 // New HTTP handler for running tasks
 async fn run_task(
     task_req: Json<TaskRequest>,
-    profiles: web::Data<ProfileStore>,
+    daemons: web::Data<DaemonTargets>,
 ) -> HttpResponse {
     log::info!("Received task request: {}", task_req.task_type);
+
+    let Some(daemon_url) = daemons.select(task_req.daemon.as_deref()) else {
+        return HttpResponse::BadRequest().json(json!({
+            "error": format!("Unknown daemon: {}", task_req.daemon.as_deref().unwrap_or_default())
+        }));
+    };
+    log::info!("Forwarding task to daemon {}", daemon_url);
     
     // Forward request to daemon
     let client = Client::new();
-    match client.post("http://[::1]:3001/task")
+    match client.post(format!("{}/task", daemon_url))
         .json(&task_req)
         .send()
         .await
@@ -288,7 +1211,9 @@ async fn run_task(
                     if let Some(profile_id) = json.get("profileId").and_then(|v| v.as_str()) {
                         HttpResponse::Ok().json(json!({
                             "status": "Task completed",
-                            "profileId": profile_id
+                            "profileId": profile_id,
+                            "heapProfileId": json.get("heapProfileId"),
+                            "daemon": daemon_url
                         }))
                     } else {
                         HttpResponse::Ok().json(json!({
@@ -326,8 +1251,8 @@ async fn health_check() -> HttpResponse {
 /// Main entry point
 /// 
 /// Sets up:
-/// 1. Logging
-/// 2. Shared profile store
+/// 1. Logging and configuration
+/// 2. Shared profile store and index, loaded from the data directory
 /// 3. gRPC server for receiving profiles
 /// 4. HTTP server for serving profiles
 /// 5. Graceful shutdown handling
@@ -336,26 +1261,111 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Initialize logger
     env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
 
-    // Initialize data directory
-    storage::init_data_dir()?;
+    let config = ServerConfig::load()?;
 
-    let profiles: ProfileStore = Arc::new(RwLock::new(HashMap::new()));
-    let grpc_profiles = profiles.clone();
+    // Open the storage backend inside the data directory; deduplicated
+    // profiles stay readable even once deduplication is turned off
+    let storage: SharedStorage = Arc::new(DedupStorage::new(
+        storage::open(config.storage_backend, &config.data_dir)?,
+        config.dedup_symbols,
+    ));
+    log::info!(
+        "Storing profiles in {} with the {:?} backend, symbol deduplication {}",
+        config.data_dir.display(), config.storage_backend,
+        if config.dedup_symbols { "on" } else { "off" }
+    );
+
+    // Profiles persisted by previous runs are loaded into the cache on demand
+    let profiles: ProfileStore = Arc::new(ProfileCache::new(config.cache_max_bytes));
+
+    let index: SharedIndex = Arc::new(RwLock::new(ProfileIndex::load(&*storage, &config.data_dir)?));
+    log::info!("Indexed {} profiles", index.read().await.len());
+
+    // Profiles stored before time-series rows existed get them in the background
+    let timeseries = Arc::new(TimeseriesStore::new(&config.data_dir)?);
+    let missing: Vec<_> = index.read().await.entries().iter()
+        .filter(|entry| !timeseries.contains(&entry.id))
+        .cloned()
+        .collect();
+    if !missing.is_empty() {
+        let (storage, timeseries) = (storage.clone(), timeseries.clone());
+        tokio::task::spawn_blocking(move || {
+            let mut written = 0;
+            for entry in &missing {
+                let result = storage.get(&entry.id, ProfilePart::Raw)
+                    .and_then(|bytes| Profile::decode(&bytes[..]).map_err(std::io::Error::other))
+                    .and_then(|profile| timeseries.write(&entry.id, &profile, &entry.metadata));
+                match result {
+                    Ok(()) => written += 1,
+                    Err(e) => log::warn!("Failed to write time-series rows of profile {}: {}", entry.id, e),
+                }
+            }
+            log::info!("Wrote time-series rows of {} existing profiles", written);
+        });
+    }
+
+    let service = MyServiceImpl {
+        profiles: profiles.clone(),
+        index: index.clone(),
+        storage: storage.clone(),
+        timeseries: timeseries.clone(),
+        max_upload_bytes: config.max_upload_bytes,
+        index_path: ProfileIndex::path(&config.data_dir),
+        upload_slots: Arc::new(Semaphore::new(config.max_concurrent_uploads)),
+    };
+    let grpc_service = service.clone();
 
     // Start gRPC server
-    let grpc_addr = "[::1]:50051".parse().unwrap();
+    let grpc_addr = config.grpc_addr;
     log::info!("gRPC server listening on {}", grpc_addr);
     
     let grpc_server = tokio::spawn(async move {
         Server::builder()
-            .add_service(MyServiceServer::new(MyServiceImpl { profiles: grpc_profiles }))
+            .add_service(
+                MyServiceServer::new(grpc_service.clone())
+                .accept_compressed(CompressionEncoding::Gzip)
+                .accept_compressed(CompressionEncoding::Zstd)
+                .send_compressed(CompressionEncoding::Zstd)
+                .send_compressed(CompressionEncoding::Gzip)
+            )
+            .add_service(
+                PprofServer::new(grpc_service)
+                .accept_compressed(CompressionEncoding::Gzip)
+                .accept_compressed(CompressionEncoding::Zstd)
+                .send_compressed(CompressionEncoding::Zstd)
+                .send_compressed(CompressionEncoding::Gzip)
+            )
             .serve(grpc_addr)
             .await
             .unwrap()
     });
 
     // Start HTTP server
-    log::info!("HTTP server listening on [::1]:3000");
+    log::info!("HTTP server listening on {}", config.http_addr);
+    log::info!("Forwarding tasks to daemons: {}", config.daemon_urls.join(", "));
+    let daemons = web::Data::new(DaemonTargets {
+        urls: config.daemon_urls,
+        next: AtomicUsize::new(0),
+    });
+    // Compact and expire old profiles in the background
+    if config.retention.is_enabled() {
+        let service = service.clone();
+        let policy = config.retention.clone();
+        let every = config.retention_interval;
+        log::info!("Applying retention policy every {:?}: {:?}", every, policy);
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(every);
+            loop {
+                ticker.tick().await;
+                if let Err(e) = service.enforce_retention(&policy).await {
+                    log::error!("Retention run failed: {}", e);
+                }
+            }
+        });
+    }
+
+    let max_upload_bytes = usize::try_from(config.max_upload_bytes).unwrap_or(usize::MAX);
+    let service = web::Data::new(service);
     let http_server = HttpServer::new(move || {
         App::new()
             .wrap(
@@ -366,11 +1376,34 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                     .max_age(3600)
             )
             .app_data(web::Data::new(profiles.clone()))
+            .app_data(web::Data::new(index.clone()))
+            .app_data(daemons.clone())
+            .app_data(service.clone())
+            .app_data(web::Data::new(storage.clone()))
+            .app_data(web::Data::new(timeseries.clone()))
             .route("/health", web::get().to(health_check))
+            .route("/api/profiles", web::get().to(list_profiles))
+            .route("/api/profiles/diff", web::get().to(diff_profiles))
             .route("/api/profiles/{id}", web::get().to(get_profile))
+            .route("/api/profiles/{id}/labels", web::get().to(get_profile_labels))
+            .route("/api/profiles/{id}/raw", web::get().to(get_raw_profile))
+            .route("/api/profiles/{id}/collapsed", web::get().to(get_collapsed_profile))
+            .route("/api/profiles/{id}/speedscope", web::get().to(get_speedscope_profile))
+            .route("/api/profiles/{id}/flamegraph.svg", web::get().to(get_flamegraph_svg))
+            .route("/api/services/{service}/aggregate", web::get().to(aggregate_profiles))
+            .route("/api/services/{service}/timeline", web::get().to(service_timeline))
+            .route("/api/query/timeseries", web::get().to(query_timeseries))
             .route("/api/tasks/run", web::post().to(run_task))
+            .route("/api/daemons", web::get().to(list_daemons))
+            .route("/api/cache/stats", web::get().to(cache_stats))
+            .service(
+                web::resource("/ingest")
+                    // Pushed profiles can be far larger than actix's 256 KiB default
+                    .app_data(web::PayloadConfig::new(max_upload_bytes))
+                    .route(web::post().to(ingest))
+            )
     })
-    .bind("[::1]:3000")?
+    .bind(config.http_addr)?
     .workers(1)
     .shutdown_timeout(5)
     .run();
@@ -385,7 +1418,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         if let Some(tx) = tx_clone.lock().unwrap().take() {
             let _ = tx.send(());
         }
-    }).map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+    }).map_err(std::io::Error::other)?;
 
     tokio::select! {
         _ = grpc_server => log::info!("gRPC server terminated"),
@@ -0,0 +1,170 @@
+//! Bounded in-memory cache of processed profiles
+//!
+//! Flame graphs are kept in least-recently-used order until their total
+//! size exceeds a byte budget, then the least recently used are evicted.
+//! Sizes are those of the graphs' JSON encoding, as stored on disk, which
+//! tracks their size in memory closely enough for a budget. Callers load
+//! misses from storage and insert them.
+
+use crate::flamegraph::ProfileGraphs;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Default byte budget of the server's cache
+pub const DEFAULT_MAX_BYTES: usize = 256 * 1024 * 1024;
+
+/// Counters and occupancy of a [`ProfileCache`]
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub entries: usize,
+    pub bytes: usize,
+    pub max_bytes: usize,
+}
+
+struct Entry {
+    graphs: Arc<ProfileGraphs>,
+    size: usize,
+    /// Key of the entry in `Inner::order`
+    last_used: u64,
+}
+
+#[derive(Default)]
+struct Inner {
+    entries: HashMap<String, Entry>,
+    /// IDs by last use, least recent first
+    order: BTreeMap<u64, String>,
+    bytes: usize,
+    clock: u64,
+}
+
+impl Inner {
+    fn touch(&mut self, id: &str) -> Option<Arc<ProfileGraphs>> {
+        self.clock += 1;
+        let entry = self.entries.get_mut(id)?;
+        let id = self.order.remove(&entry.last_used)?;
+        entry.last_used = self.clock;
+        self.order.insert(self.clock, id);
+        Some(entry.graphs.clone())
+    }
+
+    fn remove(&mut self, id: &str) -> bool {
+        match self.entries.remove(id) {
+            Some(entry) => {
+                self.order.remove(&entry.last_used);
+                self.bytes -= entry.size;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// LRU cache of flame graphs by profile ID, bounded in bytes
+pub struct ProfileCache {
+    inner: Mutex<Inner>,
+    max_bytes: usize,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+}
+
+impl ProfileCache {
+    pub fn new(max_bytes: usize) -> Self {
+        ProfileCache {
+            inner: Mutex::new(Inner::default()),
+            max_bytes,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+        }
+    }
+
+    /// Look up a profile's graphs, marking them most recently used
+    pub fn get(&self, id: &str) -> Option<Arc<ProfileGraphs>> {
+        let graphs = self.inner.lock().unwrap().touch(id);
+        let counter = if graphs.is_some() { &self.hits } else { &self.misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+        graphs
+    }
+
+    /// Add or replace a profile's graphs, evicting the least recently used
+    /// until the cache is within budget
+    ///
+    /// Graphs larger than the whole budget aren't cached.
+    pub fn insert(&self, id: String, graphs: Arc<ProfileGraphs>, size: usize) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.remove(&id);
+        if size > self.max_bytes {
+            return;
+        }
+        while inner.bytes + size > self.max_bytes {
+            let Some((_, oldest)) = inner.order.pop_first() else { break };
+            if let Some(entry) = inner.entries.remove(&oldest) {
+                inner.bytes -= entry.size;
+            }
+            self.evictions.fetch_add(1, Ordering::Relaxed);
+        }
+        inner.clock += 1;
+        let last_used = inner.clock;
+        inner.order.insert(last_used, id.clone());
+        inner.entries.insert(id, Entry { graphs, size, last_used });
+        inner.bytes += size;
+    }
+
+    /// Drop a profile's graphs, e.g. once the profile is deleted
+    pub fn remove(&self, id: &str) {
+        self.inner.lock().unwrap().remove(id);
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let inner = self.inner.lock().unwrap();
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+            entries: inner.entries.len(),
+            bytes: inner.bytes,
+            max_bytes: self.max_bytes,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pprof::protos::Profile;
+
+    #[test]
+    fn evicts_least_recently_used_within_budget() {
+        let graphs = Arc::new(ProfileGraphs::from_profile(&Profile::default()));
+        let cache = ProfileCache::new(100);
+        cache.insert("a".into(), graphs.clone(), 40);
+        cache.insert("b".into(), graphs.clone(), 40);
+        assert!(cache.get("a").is_some());
+
+        // "b" is now the least recently used and makes room for "c"
+        cache.insert("c".into(), graphs.clone(), 40);
+        assert!(cache.get("b").is_none());
+        assert!(cache.get("a").is_some() && cache.get("c").is_some());
+
+        // Too large to cache at all
+        cache.insert("d".into(), graphs.clone(), 101);
+        assert!(cache.get("d").is_none());
+
+        cache.remove("a");
+        assert_eq!(cache.stats(), CacheStats {
+            hits: 3,
+            misses: 2,
+            evictions: 1,
+            entries: 1,
+            bytes: 40,
+            max_bytes: 100,
+        });
+    }
+}
@@ -0,0 +1,459 @@
+//! Configuration shared by the server, daemon and client
+//!
+//! Every setting is resolved from, in order of precedence:
+//! 1. Command line flag
+//! 2. Environment variable
+//! 3. The binary's section of the TOML file given by `--config` or
+//!    `PROFILING_CONFIG`
+//! 4. Built-in default
+//!
+//! Example file:
+//!
+//! ```toml
+//! [server]
+//! grpc_addr = "0.0.0.0:50051"
+//! http_addr = "0.0.0.0:3000"
+//! daemon_urls = ["http://daemon-a:3001", "http://daemon-b:3001"]
+//! data_dir = "/var/lib/profiling"
+//! storage_backend = "sled"
+//! compact_hourly_after_hours = 6
+//! compact_daily_after_hours = 48
+//! retention_max_age_hours = 720
+//!
+//! [daemon]
+//! http_addr = "0.0.0.0:3001"
+//! grpc_url = "http://server:50051"
+//! continuous_interval_secs = 10
+//!
+//! [client]
+//! grpc_url = "http://server:50051"
+//! ```
+
+use crate::agent::ProfileMode;
+use crate::retention::RetentionPolicy;
+use crate::storage::StorageBackend;
+use clap::{Args, Parser};
+use serde::Deserialize;
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+const DEFAULT_GRPC_URL: &str = "http://[::1]:50051";
+
+/// Errors raised while loading configuration
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The config file could not be read
+    Io(PathBuf, std::io::Error),
+    /// The config file is not valid TOML or has unknown keys
+    Parse(PathBuf, toml::de::Error),
+    /// A setting has an unusable value
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, e) => write!(f, "failed to read {}: {}", path.display(), e),
+            ConfigError::Parse(path, e) => write!(f, "failed to parse {}: {}", path.display(), e),
+            ConfigError::Invalid(msg) => write!(f, "invalid configuration: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Contents of the optional TOML config file
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfigFile {
+    pub server: ServerSettings,
+    pub daemon: DaemonSettings,
+    pub client: ClientSettings,
+}
+
+impl ConfigFile {
+    /// Read the file at `path`, or return an empty config if no path is set
+    pub fn load(path: Option<&Path>) -> Result<Self, ConfigError> {
+        let Some(path) = path else {
+            return Ok(ConfigFile::default());
+        };
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| ConfigError::Io(path.to_path_buf(), e))?;
+        toml::from_str(&contents).map_err(|e| ConfigError::Parse(path.to_path_buf(), e))
+    }
+}
+
+/// Server settings as given on the command line, in the environment or in
+/// the `[server]` section of the config file
+#[derive(Args, Deserialize, Debug, Default, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerSettings {
+    /// Address the gRPC ingestion endpoint listens on [default: [::1]:50051]
+    #[arg(long, env = "GRPC_ADDR")]
+    pub grpc_addr: Option<SocketAddr>,
+
+    /// Address the HTTP API listens on [default: [::1]:3000]
+    #[arg(long, env = "HTTP_ADDR")]
+    pub http_addr: Option<SocketAddr>,
+
+    /// Task daemon base URLs, comma separated [default: http://[::1]:3001]
+    #[arg(long = "daemon-url", env = "DAEMON_URLS", value_delimiter = ',')]
+    pub daemon_urls: Option<Vec<String>>,
+
+    /// Directory profiles and the index are stored in [default: data]
+    #[arg(long, env = "DATA_DIR")]
+    pub data_dir: Option<PathBuf>,
+
+    /// Where profiles are stored: fs or sled [default: fs]
+    #[arg(long, env = "STORAGE_BACKEND")]
+    pub storage_backend: Option<StorageBackend>,
+
+    /// Delete profiles that ended more than this many hours ago [default: never]
+    #[arg(long, env = "RETENTION_MAX_AGE_HOURS")]
+    pub retention_max_age_hours: Option<u64>,
+
+    /// Delete the oldest profiles while all of them take up more bytes [default: no limit]
+    #[arg(long, env = "RETENTION_MAX_BYTES")]
+    pub retention_max_bytes: Option<u64>,
+
+    /// Keep at most this many profiles per service [default: no limit]
+    #[arg(long, env = "RETENTION_MAX_PER_SERVICE")]
+    pub retention_max_per_service: Option<usize>,
+
+    /// Merge profiles older than this many hours into hourly aggregates [default: never]
+    #[arg(long, env = "COMPACT_HOURLY_AFTER_HOURS")]
+    pub compact_hourly_after_hours: Option<u64>,
+
+    /// Merge profiles older than this many hours into daily aggregates [default: never]
+    #[arg(long, env = "COMPACT_DAILY_AFTER_HOURS")]
+    pub compact_daily_after_hours: Option<u64>,
+
+    /// Seconds between retention runs [default: 300]
+    #[arg(long, env = "RETENTION_INTERVAL_SECS")]
+    pub retention_interval_secs: Option<u64>,
+
+    /// Bytes of processed profiles kept in memory [default: 268435456]
+    #[arg(long, env = "CACHE_MAX_BYTES")]
+    pub cache_max_bytes: Option<usize>,
+
+    /// Store symbol tables once per service instead of in every profile [default: true]
+    #[arg(long, env = "DEDUP_SYMBOLS")]
+    pub dedup_symbols: Option<bool>,
+
+    /// Largest profile accepted through streamed gRPC uploads and /ingest [default: 67108864]
+    #[arg(long, env = "MAX_UPLOAD_BYTES")]
+    pub max_upload_bytes: Option<u64>,
+
+    /// Streamed uploads received at once; more are refused until one finishes [default: 4]
+    #[arg(long, env = "MAX_CONCURRENT_UPLOADS")]
+    pub max_concurrent_uploads: Option<usize>,
+}
+
+/// Daemon settings as given on the command line, in the environment or in
+/// the `[daemon]` section of the config file
+#[derive(Args, Deserialize, Debug, Default, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct DaemonSettings {
+    /// Address the task HTTP endpoint listens on [default: [::1]:3001]
+    #[arg(long, env = "HTTP_ADDR")]
+    pub http_addr: Option<SocketAddr>,
+
+    /// gRPC URL of the profile server [default: http://[::1]:50051]
+    #[arg(long, env = "GRPC_URL")]
+    pub grpc_url: Option<String>,
+
+    /// Service name recorded with profiles [default: profiling-daemon]
+    #[arg(long, env = "SERVICE_NAME")]
+    pub service_name: Option<String>,
+
+    /// Start continuous sampling immediately [default: true]
+    #[arg(long, env = "CONTINUOUS_ENABLED")]
+    pub continuous_enabled: Option<bool>,
+
+    /// Length of each continuous profiling window in seconds [default: 10]
+    #[arg(long, env = "CONTINUOUS_INTERVAL_SECS")]
+    pub continuous_interval_secs: Option<u64>,
+
+    /// Continuous sampling frequency in Hz [default: 100]
+    #[arg(long, env = "CONTINUOUS_FREQUENCY")]
+    pub continuous_frequency: Option<i32>,
+
+    /// Sample on-CPU or wall-clock time continuously [default: cpu]
+    #[arg(long, env = "CONTINUOUS_MODE")]
+    pub continuous_mode: Option<ProfileMode>,
+
+    /// Bytes allocated between heap samples during memory tasks, 0 to
+    /// disable heap profiling [default: 524288]
+    #[arg(long, env = "HEAP_SAMPLE_INTERVAL")]
+    pub heap_sample_interval: Option<usize>,
+}
+
+/// Client settings as given on the command line, in the environment or in
+/// the `[client]` section of the config file
+#[derive(Args, Deserialize, Debug, Default, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientSettings {
+    /// gRPC URL of the profile server [default: http://[::1]:50051]
+    #[arg(long, env = "GRPC_URL")]
+    pub grpc_url: Option<String>,
+
+    /// Service name recorded with profiles [default: profiling-client]
+    #[arg(long, env = "SERVICE_NAME")]
+    pub service_name: Option<String>,
+
+    /// Profile on-CPU or wall-clock time [default: cpu]
+    #[arg(long, env = "PROFILE_MODE")]
+    pub mode: Option<ProfileMode>,
+}
+
+#[derive(Parser, Debug)]
+#[command(name = "server", about = "Profile ingestion and query server")]
+pub struct ServerCli {
+    /// Path to a TOML config file
+    #[arg(long, env = "PROFILING_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    #[command(flatten)]
+    pub settings: ServerSettings,
+}
+
+#[derive(Parser, Debug)]
+#[command(name = "daemon", about = "Task daemon with continuous profiling")]
+pub struct DaemonCli {
+    /// Path to a TOML config file
+    #[arg(long, env = "PROFILING_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    #[command(flatten)]
+    pub settings: DaemonSettings,
+}
+
+#[derive(Parser, Debug)]
+#[command(name = "client", about = "Profile a sample workload once and upload it")]
+pub struct ClientCli {
+    /// Path to a TOML config file
+    #[arg(long, env = "PROFILING_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// Workload to run: cpu, memory or mixed
+    #[arg(default_value = "mixed")]
+    pub task_type: String,
+
+    #[command(flatten)]
+    pub settings: ClientSettings,
+}
+
+/// Resolved server configuration
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    pub grpc_addr: SocketAddr,
+    pub http_addr: SocketAddr,
+    pub daemon_urls: Vec<String>,
+    /// Directory profiles and the index are stored in
+    pub data_dir: PathBuf,
+    pub storage_backend: StorageBackend,
+    /// Compaction and deletion of old profiles
+    pub retention: RetentionPolicy,
+    /// How often the retention policy is applied
+    pub retention_interval: Duration,
+    /// Byte budget of the in-memory profile cache
+    pub cache_max_bytes: usize,
+    /// Whether new profiles share symbol tables with others of their service
+    pub dedup_symbols: bool,
+    /// Size limit of an uploaded profile
+    pub max_upload_bytes: u64,
+    /// Streamed uploads buffered at the same time
+    pub max_concurrent_uploads: usize,
+}
+
+impl ServerConfig {
+    /// Parse the process arguments, environment and config file
+    pub fn load() -> Result<Self, ConfigError> {
+        let cli = ServerCli::parse();
+        let file = ConfigFile::load(cli.config.as_deref())?;
+        Self::resolve(cli.settings, file.server)
+    }
+
+    pub fn resolve(cli: ServerSettings, file: ServerSettings) -> Result<Self, ConfigError> {
+        let daemon_urls: Vec<String> = cli.daemon_urls.or(file.daemon_urls)
+            .unwrap_or_else(|| vec!["http://[::1]:3001".to_string()])
+            .into_iter()
+            .map(|url| url.trim().trim_end_matches('/').to_string())
+            .filter(|url| !url.is_empty())
+            .collect();
+        if daemon_urls.is_empty() {
+            return Err(ConfigError::Invalid("at least one daemon URL is required".into()));
+        }
+
+        let hours = |name: &str, value: Option<u64>| match value {
+            Some(0) => Err(ConfigError::Invalid(format!("{} must be positive", name))),
+            value => Ok(value.map(|hours| Duration::from_secs(hours * 3600))),
+        };
+        let retention = RetentionPolicy {
+            max_age: hours("retention_max_age_hours", cli.retention_max_age_hours.or(file.retention_max_age_hours))?,
+            max_bytes: cli.retention_max_bytes.or(file.retention_max_bytes),
+            max_per_service: cli.retention_max_per_service.or(file.retention_max_per_service),
+            hourly_after: hours("compact_hourly_after_hours", cli.compact_hourly_after_hours.or(file.compact_hourly_after_hours))?,
+            daily_after: hours("compact_daily_after_hours", cli.compact_daily_after_hours.or(file.compact_daily_after_hours))?,
+        };
+        if retention.max_per_service == Some(0) {
+            return Err(ConfigError::Invalid("retention_max_per_service must be positive".into()));
+        }
+        let interval_secs = cli.retention_interval_secs.or(file.retention_interval_secs).unwrap_or(300);
+        if interval_secs == 0 {
+            return Err(ConfigError::Invalid("retention_interval_secs must be positive".into()));
+        }
+        let max_upload_bytes = cli.max_upload_bytes.or(file.max_upload_bytes)
+            .unwrap_or(crate::upload::DEFAULT_MAX_UPLOAD_SIZE);
+        if max_upload_bytes == 0 {
+            return Err(ConfigError::Invalid("max_upload_bytes must be positive".into()));
+        }
+        let max_concurrent_uploads = cli.max_concurrent_uploads.or(file.max_concurrent_uploads).unwrap_or(4);
+        if max_concurrent_uploads == 0 {
+            return Err(ConfigError::Invalid("max_concurrent_uploads must be positive".into()));
+        }
+
+        Ok(ServerConfig {
+            grpc_addr: cli.grpc_addr.or(file.grpc_addr)
+                .unwrap_or_else(|| "[::1]:50051".parse().unwrap()),
+            http_addr: cli.http_addr.or(file.http_addr)
+                .unwrap_or_else(|| "[::1]:3000".parse().unwrap()),
+            daemon_urls,
+            data_dir: cli.data_dir.or(file.data_dir)
+                .unwrap_or_else(|| PathBuf::from(crate::storage::DEFAULT_DATA_DIR)),
+            storage_backend: cli.storage_backend.or(file.storage_backend).unwrap_or_default(),
+            retention,
+            retention_interval: Duration::from_secs(interval_secs),
+            cache_max_bytes: cli.cache_max_bytes.or(file.cache_max_bytes)
+                .unwrap_or(crate::cache::DEFAULT_MAX_BYTES),
+            dedup_symbols: cli.dedup_symbols.or(file.dedup_symbols).unwrap_or(true),
+            max_upload_bytes,
+            max_concurrent_uploads,
+        })
+    }
+}
+
+/// Resolved daemon configuration
+#[derive(Debug, Clone)]
+pub struct DaemonConfig {
+    pub http_addr: SocketAddr,
+    pub grpc_url: String,
+    pub service_name: String,
+    /// Start continuous sampling immediately instead of waiting for a resume
+    pub continuous_enabled: bool,
+    /// Length of each continuous profiling window
+    pub continuous_interval: Duration,
+    /// Continuous sampling frequency in Hz
+    pub continuous_frequency: i32,
+    /// Whether continuous windows sample CPU or wall-clock time
+    pub continuous_mode: ProfileMode,
+    /// Bytes allocated between heap samples; 0 disables heap profiling
+    pub heap_sample_interval: usize,
+}
+
+impl DaemonConfig {
+    /// Parse the process arguments, environment and config file
+    pub fn load() -> Result<Self, ConfigError> {
+        let cli = DaemonCli::parse();
+        let file = ConfigFile::load(cli.config.as_deref())?;
+        Self::resolve(cli.settings, file.daemon)
+    }
+
+    pub fn resolve(cli: DaemonSettings, file: DaemonSettings) -> Result<Self, ConfigError> {
+        let interval_secs = cli.continuous_interval_secs.or(file.continuous_interval_secs).unwrap_or(10);
+        let frequency = cli.continuous_frequency.or(file.continuous_frequency).unwrap_or(100);
+        if interval_secs == 0 {
+            return Err(ConfigError::Invalid("continuous_interval_secs must be positive".into()));
+        }
+        if frequency <= 0 {
+            return Err(ConfigError::Invalid("continuous_frequency must be positive".into()));
+        }
+
+        Ok(DaemonConfig {
+            http_addr: cli.http_addr.or(file.http_addr)
+                .unwrap_or_else(|| "[::1]:3001".parse().unwrap()),
+            grpc_url: cli.grpc_url.or(file.grpc_url)
+                .unwrap_or_else(|| DEFAULT_GRPC_URL.to_string()),
+            service_name: cli.service_name.or(file.service_name)
+                .unwrap_or_else(|| "profiling-daemon".to_string()),
+            continuous_enabled: cli.continuous_enabled.or(file.continuous_enabled).unwrap_or(true),
+            continuous_interval: Duration::from_secs(interval_secs),
+            continuous_frequency: frequency,
+            continuous_mode: cli.continuous_mode.or(file.continuous_mode).unwrap_or_default(),
+            heap_sample_interval: cli.heap_sample_interval.or(file.heap_sample_interval)
+                .unwrap_or(crate::heap::DEFAULT_SAMPLE_INTERVAL),
+        })
+    }
+}
+
+/// Resolved client configuration
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    pub task_type: String,
+    pub grpc_url: String,
+    pub service_name: String,
+    pub mode: ProfileMode,
+}
+
+impl ClientConfig {
+    /// Parse the process arguments, environment and config file
+    pub fn load() -> Result<Self, ConfigError> {
+        let cli = ClientCli::parse();
+        let file = ConfigFile::load(cli.config.as_deref())?;
+        Ok(Self::resolve(cli.task_type, cli.settings, file.client))
+    }
+
+    pub fn resolve(task_type: String, cli: ClientSettings, file: ClientSettings) -> Self {
+        ClientConfig {
+            task_type,
+            grpc_url: cli.grpc_url.or(file.grpc_url)
+                .unwrap_or_else(|| DEFAULT_GRPC_URL.to_string()),
+            service_name: cli.service_name.or(file.service_name)
+                .unwrap_or_else(|| "profiling-client".to_string()),
+            mode: cli.mode.or(file.mode).unwrap_or_default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cli_overrides_file_which_overrides_defaults() {
+        let file: ConfigFile = toml::from_str(r#"
+            [server]
+            http_addr = "0.0.0.0:8080"
+            daemon_urls = ["http://a:3001/", "http://b:3001"]
+            storage_backend = "sled"
+            dedup_symbols = false
+            max_upload_bytes = 1048576
+        "#).unwrap();
+        let cli = ServerSettings {
+            http_addr: Some("127.0.0.1:9000".parse().unwrap()),
+            ..Default::default()
+        };
+
+        let config = ServerConfig::resolve(cli, file.server).unwrap();
+        assert_eq!(config.http_addr, "127.0.0.1:9000".parse().unwrap());
+        assert_eq!(config.grpc_addr, "[::1]:50051".parse().unwrap());
+        assert_eq!(config.daemon_urls, vec!["http://a:3001", "http://b:3001"]);
+        assert_eq!(config.storage_backend, StorageBackend::Sled);
+        assert!(!config.dedup_symbols);
+        assert_eq!(config.max_upload_bytes, 1024 * 1024);
+        assert_eq!(config.max_concurrent_uploads, 4);
+    }
+
+    #[test]
+    fn rejects_unknown_keys_and_bad_values() {
+        assert!(toml::from_str::<ConfigFile>("[server]\nport = 1").is_err());
+
+        let file = DaemonSettings { continuous_interval_secs: Some(0), ..Default::default() };
+        assert!(DaemonConfig::resolve(DaemonSettings::default(), file).is_err());
+
+        let file = ServerSettings { retention_max_age_hours: Some(0), ..Default::default() };
+        assert!(ServerConfig::resolve(ServerSettings::default(), file).is_err());
+    }
+}
@@ -0,0 +1,641 @@
+//! Content-addressed deduplication of profile symbols
+//!
+//! Services in continuous mode upload a profile every few seconds, and
+//! while they run the same code every one of them repeats the same
+//! functions, mappings, locations and their strings. [`DedupStorage`] keeps
+//! those symbols once per service and stores only the per-window sample
+//! data with each profile:
+//!
+//! 1. [`split`] spreads a profile's locations over a fixed number of shards
+//!    by a hash of their content, each shard taking the functions and
+//!    mappings its locations use. Every shard becomes a canonical
+//!    [`Profile`] that depends only on its content: strings sorted,
+//!    duplicates merged and IDs assigned in sorted order. Samples keep their
+//!    labels and values in a profile of their own, with its own string
+//!    table, and refer to the locations as numbered across all shards.
+//! 2. Each shard is stored as a blob keyed by the SHA-256 of the service
+//!    name and its encoding. Windows with the same symbols share all blobs,
+//!    and a window that adds one function only stores the shard holding its
+//!    locations anew. The raw part of the profile becomes a header naming
+//!    the keys, followed by the encoded samples.
+//! 3. Reading the raw part [`join`]s the parts back into standard pprof.
+//!    Raw parts without the header, e.g. stored before deduplication was
+//!    enabled, are returned as they are.
+//!
+//! Blobs are never deleted along with a profile, as others may still share
+//! them; [`ProfileStorage::collect_garbage`] removes the ones no profile
+//! refers to any more.
+
+use crate::storage::{ProfilePart, ProfileStorage, SharedStorage, StoredProfile};
+use crate::upload;
+use pprof::protos::{Function, Label, Line, Location, Mapping, Message, Profile, Sample, ValueType};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::io;
+use std::sync::RwLock;
+
+/// Start of a raw part stored without its symbols
+///
+/// `p` would be a varint tag for field 14 and `o` an invalid wire type, so
+/// no pprof data starts with this.
+const HEADER: &[u8] = b"pprof-dedup/1 ";
+
+/// Number of blobs a profile's symbols are spread over
+///
+/// Each location goes to the blob picked by a hash of its content, so a
+/// window that adds or changes a few functions only has new blobs for the
+/// locations using them and shares the rest with earlier windows.
+const SYMBOL_SHARDS: usize = 16;
+
+/// A profile split into shared symbols and per-window samples
+#[derive(Debug, Clone, PartialEq)]
+pub struct SplitProfile {
+    /// Content address of each of `symbols`
+    pub keys: Vec<String>,
+    /// Functions, mappings, locations and the strings they use, spread
+    /// over up to [`SYMBOL_SHARDS`] profiles
+    pub symbols: Vec<Profile>,
+    /// Everything else, with sample locations referring to `symbols` as
+    /// [`join`] numbers them
+    pub samples: Profile,
+}
+
+/// Interns strings into a table whose first entry is the empty string
+struct StringTable {
+    strings: Vec<String>,
+    indices: HashMap<String, i64>,
+}
+
+impl StringTable {
+    fn new() -> Self {
+        StringTable { strings: vec![String::new()], indices: HashMap::from([(String::new(), 0)]) }
+    }
+
+    fn intern(&mut self, s: &str) -> i64 {
+        if let Some(&idx) = self.indices.get(s) {
+            return idx;
+        }
+        let idx = self.strings.len() as i64;
+        self.strings.push(s.to_string());
+        self.indices.insert(s.to_string(), idx);
+        idx
+    }
+}
+
+/// Symbols that are stored in one blob, by their ID in the profile
+#[derive(Default)]
+struct Shard<'a> {
+    mappings: BTreeMap<u64, &'a Mapping>,
+    functions: BTreeMap<u64, &'a Function>,
+    locations: Vec<&'a Location>,
+}
+
+impl Shard<'_> {
+    fn is_empty(&self) -> bool {
+        self.mappings.is_empty() && self.functions.is_empty() && self.locations.is_empty()
+    }
+}
+
+/// Split a profile into canonical symbols and its samples
+///
+/// # Arguments
+/// * `service` - Service the profile belongs to; symbols are only shared
+///   between profiles of the same service
+pub fn split(service: &str, profile: &Profile) -> SplitProfile {
+    let string_at = |idx: i64| profile.string_table.get(idx as usize).map(String::as_str).unwrap_or("");
+    let mappings: HashMap<u64, &Mapping> = profile.mapping.iter().map(|m| (m.id, m)).collect();
+    let functions: HashMap<u64, &Function> = profile.function.iter().map(|f| (f.id, f)).collect();
+
+    let mut shards: Vec<Shard> = (0..SYMBOL_SHARDS).map(|_| Shard::default()).collect();
+    for location in &profile.location {
+        let shard = &mut shards[location_shard(location, &functions, string_at)];
+        shard.locations.push(location);
+        if let Some(&mapping) = mappings.get(&location.mapping_id) {
+            shard.mappings.insert(mapping.id, mapping);
+        }
+        for line in &location.line {
+            if let Some(&function) = functions.get(&line.function_id) {
+                shard.functions.insert(function.id, function);
+            }
+        }
+    }
+    // Symbols no location refers to are kept with the first shard
+    let used_mappings: HashSet<u64> = shards.iter().flat_map(|shard| shard.mappings.keys().copied()).collect();
+    let used_functions: HashSet<u64> = shards.iter().flat_map(|shard| shard.functions.keys().copied()).collect();
+    shards[0].mappings.extend(profile.mapping.iter().filter(|m| !used_mappings.contains(&m.id)).map(|m| (m.id, m)));
+    shards[0].functions.extend(profile.function.iter().filter(|f| !used_functions.contains(&f.id)).map(|f| (f.id, f)));
+
+    // Sample locations are numbered across shards the way `join` does
+    let mut keys = Vec::new();
+    let mut symbols = Vec::new();
+    let mut location_ids = HashMap::new();
+    let mut offset = 0;
+    for shard in shards.iter().filter(|shard| !shard.is_empty()) {
+        let (canonical, ids) = canonical_symbols(profile, shard);
+        location_ids.extend(ids.into_iter().map(|(old, new)| (old, new + offset)));
+        offset += canonical.location.len() as u64;
+
+        let mut content = service.as_bytes().to_vec();
+        content.push(0);
+        canonical.encode(&mut content).expect("Vec has unbounded capacity");
+        keys.push(upload::sha256_hex(&content));
+        symbols.push(canonical);
+    }
+
+    let mut strings = StringTable::new();
+    let mut intern = |idx: i64| strings.intern(string_at(idx));
+    let mut samples = Profile {
+        sample_type: profile.sample_type.iter()
+            .map(|t| ValueType { ty: intern(t.ty), unit: intern(t.unit) })
+            .collect(),
+        period_type: profile.period_type.as_ref().map(|t| ValueType { ty: intern(t.ty), unit: intern(t.unit) }),
+        comment: profile.comment.iter().map(|&idx| intern(idx)).collect(),
+        drop_frames: intern(profile.drop_frames),
+        keep_frames: intern(profile.keep_frames),
+        default_sample_type: intern(profile.default_sample_type),
+        time_nanos: profile.time_nanos,
+        duration_nanos: profile.duration_nanos,
+        period: profile.period,
+        ..Default::default()
+    };
+    samples.sample = profile.sample.iter()
+        .map(|sample| Sample {
+            location_id: sample.location_id.iter().filter_map(|id| location_ids.get(id).copied()).collect(),
+            value: sample.value.clone(),
+            label: sample.label.iter()
+                .map(|label| Label {
+                    key: intern(label.key),
+                    str: intern(label.str),
+                    num: label.num,
+                    num_unit: intern(label.num_unit),
+                })
+                .collect(),
+        })
+        .collect();
+    samples.string_table = strings.strings;
+
+    SplitProfile { keys, symbols, samples }
+}
+
+/// Shard of a location, from its address and lines rather than its IDs
+fn location_shard<'a>(
+    location: &Location,
+    functions: &HashMap<u64, &Function>,
+    string_at: impl Fn(i64) -> &'a str,
+) -> usize {
+    let mut hasher = Sha256::new();
+    hasher.update(location.address.to_le_bytes());
+    for line in &location.line {
+        if let Some(function) = functions.get(&line.function_id) {
+            for s in [string_at(function.name), string_at(function.system_name), string_at(function.filename)] {
+                hasher.update(s.as_bytes());
+                hasher.update([0]);
+            }
+        }
+        hasher.update(line.line.to_le_bytes());
+    }
+    hasher.finalize()[0] as usize % SYMBOL_SHARDS
+}
+
+/// Canonical form of one shard's symbols, which depends only on their
+/// content, and the ID each of its locations gets in it
+fn canonical_symbols(profile: &Profile, shard: &Shard) -> (Profile, HashMap<u64, u64>) {
+    let string_at = |idx: i64| profile.string_table.get(idx as usize).map(String::as_str).unwrap_or("");
+
+    // Sorted, so that equal sets of strings get equal indices
+    let mut names: BTreeSet<&str> = BTreeSet::from([""]);
+    for function in shard.functions.values() {
+        names.extend([string_at(function.name), string_at(function.system_name), string_at(function.filename)]);
+    }
+    for mapping in shard.mappings.values() {
+        names.extend([string_at(mapping.filename), string_at(mapping.build_id)]);
+    }
+    let string_idx: HashMap<&str, i64> = names.iter().enumerate().map(|(idx, &s)| (s, idx as i64)).collect();
+    let symbol_string = |idx: i64| string_idx[string_at(idx)];
+
+    // Keys sort by content, as canonical string indices do; IDs are then
+    // assigned in key order
+    let mut mappings = BTreeMap::new();
+    let mut mapping_keys = HashMap::new();
+    for mapping in shard.mappings.values() {
+        let filename = symbol_string(mapping.filename);
+        let build_id = symbol_string(mapping.build_id);
+        let key = (
+            mapping.memory_start, mapping.memory_limit, mapping.file_offset, filename, build_id,
+            mapping.has_functions, mapping.has_filenames, mapping.has_line_numbers, mapping.has_inline_frames,
+        );
+        mapping_keys.insert(mapping.id, key);
+        mappings.insert(key, Mapping { id: 0, filename, build_id, ..(*mapping).clone() });
+    }
+    let mapping_key_ids = assign_ids(&mut mappings, |mapping, id| mapping.id = id);
+    let mapping_id = |old: u64| mapping_keys.get(&old).map(|key| mapping_key_ids[key]).unwrap_or(0);
+
+    let mut functions = BTreeMap::new();
+    let mut function_ids = HashMap::new();
+    for function in shard.functions.values() {
+        let key = (
+            symbol_string(function.name),
+            symbol_string(function.system_name),
+            symbol_string(function.filename),
+            function.start_line,
+        );
+        function_ids.insert(function.id, key);
+        functions.insert(key, Function {
+            id: 0,
+            name: key.0,
+            system_name: key.1,
+            filename: key.2,
+            start_line: key.3,
+        });
+    }
+    let function_key_ids = assign_ids(&mut functions, |function, id| function.id = id);
+    let function_id = |old: u64| function_ids.get(&old).map(|key| function_key_ids[key]).unwrap_or(0);
+
+    let mut locations = BTreeMap::new();
+    let mut location_keys = HashMap::new();
+    for location in &shard.locations {
+        let lines: Vec<(u64, i64)> = location.line.iter()
+            .map(|line| (function_id(line.function_id), line.line))
+            .collect();
+        let key = (mapping_id(location.mapping_id), location.address, lines, location.is_folded);
+        location_keys.insert(location.id, key.clone());
+        locations.insert(key.clone(), Location {
+            id: 0,
+            mapping_id: key.0,
+            address: key.1,
+            line: key.2.iter().map(|&(function_id, line)| Line { function_id, line }).collect(),
+            is_folded: key.3,
+        });
+    }
+    let location_key_ids = assign_ids(&mut locations, |location, id| location.id = id);
+
+    let symbols = Profile {
+        mapping: mappings.into_values().collect(),
+        location: locations.into_values().collect(),
+        function: functions.into_values().collect(),
+        string_table: names.into_iter().map(str::to_string).collect(),
+        ..Default::default()
+    };
+    let location_ids = location_keys.into_iter().map(|(old, key)| (old, location_key_ids[&key])).collect();
+    (symbols, location_ids)
+}
+
+/// Number entries from 1 in key order, returning each key's ID
+fn assign_ids<K: Ord + Clone + std::hash::Hash, V>(
+    entries: &mut BTreeMap<K, V>,
+    mut set_id: impl FnMut(&mut V, u64),
+) -> HashMap<K, u64> {
+    entries.iter_mut()
+        .enumerate()
+        .map(|(idx, (key, value))| {
+            set_id(value, idx as u64 + 1);
+            (key.clone(), idx as u64 + 1)
+        })
+        .collect()
+}
+
+/// Rebuild a standard pprof profile from the parts made by [`split`]
+///
+/// The symbol shards are concatenated in order, each one's IDs and string
+/// indices following those of the shards before it.
+pub fn join(symbols: &[Profile], samples: &Profile) -> Profile {
+    let mut string_table = vec![String::new()];
+    let (mut mapping, mut function, mut location) = (Vec::new(), Vec::new(), Vec::new());
+    for shard in symbols {
+        // Shards share the empty string, and canonical IDs count from 1
+        let strings = string_table.len() as i64 - 1;
+        let string = |idx: i64| if idx > 0 { idx + strings } else { 0 };
+        let (mappings, functions, locations) = (mapping.len() as u64, function.len() as u64, location.len() as u64);
+        let offset = |id: u64, by: u64| if id > 0 { id + by } else { 0 };
+
+        string_table.extend(shard.string_table.iter().skip(1).cloned());
+        mapping.extend(shard.mapping.iter().map(|m| Mapping {
+            id: m.id + mappings,
+            filename: string(m.filename),
+            build_id: string(m.build_id),
+            ..m.clone()
+        }));
+        function.extend(shard.function.iter().map(|f| Function {
+            id: f.id + functions,
+            name: string(f.name),
+            system_name: string(f.system_name),
+            filename: string(f.filename),
+            start_line: f.start_line,
+        }));
+        location.extend(shard.location.iter().map(|l| Location {
+            id: l.id + locations,
+            mapping_id: offset(l.mapping_id, mappings),
+            line: l.line.iter()
+                .map(|line| Line { function_id: offset(line.function_id, functions), line: line.line })
+                .collect(),
+            ..l.clone()
+        }));
+    }
+
+    // Sample strings follow the symbols', except the shared empty string
+    let offset = string_table.len() as i64 - 1;
+    let string = |idx: i64| if idx > 0 { idx + offset } else { 0 };
+    let value_type = |t: &ValueType| ValueType { ty: string(t.ty), unit: string(t.unit) };
+    string_table.extend(samples.string_table.iter().skip(1).cloned());
+    Profile {
+        sample_type: samples.sample_type.iter().map(value_type).collect(),
+        sample: samples.sample.iter()
+            .map(|sample| Sample {
+                label: sample.label.iter()
+                    .map(|label| Label {
+                        key: string(label.key),
+                        str: string(label.str),
+                        num: label.num,
+                        num_unit: string(label.num_unit),
+                    })
+                    .collect(),
+                ..sample.clone()
+            })
+            .collect(),
+        mapping,
+        location,
+        function,
+        string_table,
+        drop_frames: string(samples.drop_frames),
+        keep_frames: string(samples.keep_frames),
+        time_nanos: samples.time_nanos,
+        duration_nanos: samples.duration_nanos,
+        period_type: samples.period_type.as_ref().map(value_type),
+        period: samples.period,
+        comment: samples.comment.iter().map(|&idx| string(idx)).collect(),
+        default_sample_type: string(samples.default_sample_type),
+    }
+}
+
+/// Raw part referring to the symbols stored under `keys`
+pub fn encode_samples(keys: &[String], samples: &Profile) -> Vec<u8> {
+    let keys = keys.join(",");
+    let mut raw = Vec::with_capacity(HEADER.len() + keys.len() + 1 + samples.encoded_len());
+    raw.extend_from_slice(HEADER);
+    raw.extend_from_slice(keys.as_bytes());
+    raw.push(b'\n');
+    samples.encode(&mut raw).expect("Vec has unbounded capacity");
+    raw
+}
+
+/// Symbols keys and encoded samples of a deduplicated raw part, or `None`
+/// for standard pprof data
+pub fn decode_header(raw: &[u8]) -> Option<(Vec<&str>, &[u8])> {
+    let rest = raw.strip_prefix(HEADER)?;
+    let end = rest.iter().position(|&b| b == b'\n')?;
+    let keys = std::str::from_utf8(&rest[..end]).ok()?;
+    let keys = keys.split(',').filter(|key| !key.is_empty()).collect();
+    Some((keys, &rest[end + 1..]))
+}
+
+fn invalid_data(e: impl std::fmt::Display) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+/// Storage that keeps symbols shared between profiles as blobs of another
+/// backend
+pub struct DedupStorage {
+    inner: SharedStorage,
+    /// Whether new profiles are split; stored ones are always reconstructed
+    enabled: bool,
+    /// Held for writing by garbage collection, so that a blob isn't deleted
+    /// between a profile storing it and referring to it
+    gc: RwLock<()>,
+}
+
+impl DedupStorage {
+    pub fn new(inner: SharedStorage, enabled: bool) -> Self {
+        DedupStorage { inner, enabled, gc: RwLock::new(()) }
+    }
+}
+
+impl ProfileStorage for DedupStorage {
+    /// Raw data that doesn't decode as pprof is stored as it is
+    fn put(&self, id: &str, profile: &StoredProfile) -> io::Result<()> {
+        let decoded = match self.enabled {
+            true => upload::decode_profile(&profile.raw).ok(),
+            false => None,
+        };
+        let Some(decoded) = decoded else {
+            return self.inner.put(id, profile);
+        };
+
+        let split = split(&profile.metadata.service_name, &decoded);
+        let _guard = self.gc.read().unwrap();
+        for (key, symbols) in split.keys.iter().zip(&split.symbols) {
+            self.inner.put_blob(key, &symbols.encode_to_vec())?;
+        }
+        self.inner.put(id, &StoredProfile {
+            raw: encode_samples(&split.keys, &split.samples),
+            graphs: profile.graphs.clone(),
+            metadata: profile.metadata.clone(),
+        })
+    }
+
+    fn get(&self, id: &str, part: ProfilePart) -> io::Result<Vec<u8>> {
+        let data = self.inner.get(id, part)?;
+        let Some((keys, samples)) = decode_header(&data).filter(|_| part == ProfilePart::Raw) else {
+            return Ok(data);
+        };
+        let symbols = keys.into_iter()
+            .map(|key| Profile::decode(&self.inner.get_blob(key)?[..]).map_err(invalid_data))
+            .collect::<io::Result<Vec<_>>>()?;
+        let samples = Profile::decode(samples).map_err(invalid_data)?;
+        Ok(join(&symbols, &samples).encode_to_vec())
+    }
+
+    fn metadata(&self, id: &str) -> io::Result<crate::metadata::ProfileMetadata> {
+        self.inner.metadata(id)
+    }
+
+    fn list(&self) -> io::Result<Vec<String>> {
+        self.inner.list()
+    }
+
+    /// Shared symbols aren't counted
+    fn size(&self, id: &str) -> io::Result<u64> {
+        self.inner.size(id)
+    }
+
+    fn delete(&self, id: &str) -> io::Result<()> {
+        self.inner.delete(id)
+    }
+
+    fn put_blob(&self, key: &str, data: &[u8]) -> io::Result<()> {
+        self.inner.put_blob(key, data)
+    }
+
+    fn get_blob(&self, key: &str) -> io::Result<Vec<u8>> {
+        self.inner.get_blob(key)
+    }
+
+    fn list_blobs(&self) -> io::Result<Vec<String>> {
+        self.inner.list_blobs()
+    }
+
+    fn delete_blob(&self, key: &str) -> io::Result<()> {
+        self.inner.delete_blob(key)
+    }
+
+    fn collect_garbage(&self) -> io::Result<usize> {
+        let _guard = self.gc.write().unwrap();
+        let mut referenced = HashSet::new();
+        for id in self.inner.list()? {
+            let raw = match self.inner.get(&id, ProfilePart::Raw) {
+                Ok(raw) => raw,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            if let Some((keys, _)) = decode_header(&raw) {
+                referenced.extend(keys.into_iter().map(str::to_string));
+            }
+        }
+
+        let mut deleted = 0;
+        for key in self.inner.list_blobs()? {
+            if !referenced.contains(&key) {
+                self.inner.delete_blob(&key)?;
+                deleted += 1;
+            }
+        }
+        Ok(deleted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::export;
+    use crate::metadata::ProfileMetadata;
+    use crate::storage::FsStorage;
+    use std::sync::Arc;
+
+    /// CPU profile of `main;work` and `main;idle`, with a thread label,
+    /// whose tables are laid out in the given order
+    fn profile(reversed: bool, work: i64) -> Profile {
+        let mut strings: Vec<String> = ["", "samples", "count", "main", "work", "idle", "thread", "worker-1"]
+            .into_iter().map(String::from).collect();
+        let mut function: Vec<Function> = (1..=3)
+            .map(|id| Function { id, name: id as i64 + 2, ..Default::default() })
+            .collect();
+        let mut location: Vec<Location> = (1..=3)
+            .map(|id| Location { id, line: vec![Line { function_id: id, line: 0 }], ..Default::default() })
+            .collect();
+        if reversed {
+            // Same symbols under other IDs and string indices
+            strings.swap(3, 5);
+            function = vec![
+                Function { id: 7, name: 3, ..Default::default() },
+                Function { id: 8, name: 4, ..Default::default() },
+                Function { id: 9, name: 5, ..Default::default() },
+            ];
+            location = vec![
+                Location { id: 4, line: vec![Line { function_id: 7, line: 0 }], ..Default::default() },
+                Location { id: 5, line: vec![Line { function_id: 8, line: 0 }], ..Default::default() },
+                Location { id: 6, line: vec![Line { function_id: 9, line: 0 }], ..Default::default() },
+            ];
+        }
+        let (main, work_loc, idle) = if reversed { (6, 5, 4) } else { (1, 2, 3) };
+        let label = vec![Label { key: 6, str: 7, ..Default::default() }];
+        Profile {
+            sample_type: vec![ValueType { ty: 1, unit: 2 }],
+            sample: vec![
+                Sample { location_id: vec![work_loc, main], value: vec![work], label: label.clone() },
+                Sample { location_id: vec![idle, main], value: vec![1], label },
+            ],
+            location,
+            function,
+            string_table: strings,
+            time_nanos: 42,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn splits_into_canonical_symbols_and_joins_back() {
+        let a = split("api", &profile(false, 5));
+        let b = split("api", &profile(true, 9));
+        assert_eq!(a.keys, b.keys);
+        assert_eq!(a.symbols, b.symbols);
+        assert!(split("db", &profile(false, 5)).keys.iter().all(|key| !a.keys.contains(key)));
+
+        for (original, split) in [(profile(false, 5), &a), (profile(true, 9), &b)] {
+            let joined = join(&split.symbols, &split.samples);
+            assert_eq!(export::to_collapsed(&joined, 0), export::to_collapsed(&original, 0));
+            assert_eq!(crate::flamegraph::sample_label(&joined, &joined.sample[0], "thread").as_deref(), Some("worker-1"));
+            assert_eq!(joined.time_nanos, 42);
+        }
+
+        let raw = encode_samples(&a.keys, &a.samples);
+        let keys: Vec<&str> = a.keys.iter().map(String::as_str).collect();
+        assert_eq!(decode_header(&raw), Some((keys, &a.samples.encode_to_vec()[..])));
+        assert_eq!(decode_header(&profile(false, 5).encode_to_vec()), None);
+    }
+
+    #[test]
+    fn storage_shares_symbols_and_collects_unused_ones() {
+        let dir = std::env::temp_dir().join(format!("profiling-dedup-{}", uuid::Uuid::new_v4()));
+        let storage = DedupStorage::new(Arc::new(FsStorage::new(&dir).unwrap()), true);
+        let stored = |reversed, work| StoredProfile {
+            raw: profile(reversed, work).encode_to_vec(),
+            graphs: b"{}".to_vec(),
+            metadata: ProfileMetadata { service_name: "api".into(), ..Default::default() },
+        };
+        storage.put("a", &stored(false, 5)).unwrap();
+        storage.put("b", &stored(true, 9)).unwrap();
+        storage.put("plain", &StoredProfile { raw: b"not pprof".to_vec(), ..Default::default() }).unwrap();
+        let shards = split("api", &profile(false, 5)).keys.len();
+        assert_eq!(storage.list_blobs().unwrap().len(), shards);
+        assert_eq!(storage.list().unwrap(), vec!["a", "b", "plain"]);
+
+        let raw = storage.get("b", ProfilePart::Raw).unwrap();
+        let restored = upload::decode_profile(&raw).unwrap();
+        assert_eq!(export::to_collapsed(&restored, 0), export::to_collapsed(&profile(true, 9), 0));
+        assert_eq!(storage.get("plain", ProfilePart::Raw).unwrap(), b"not pprof");
+
+        storage.delete("a").unwrap();
+        assert_eq!(storage.collect_garbage().unwrap(), 0);
+        storage.delete("b").unwrap();
+        assert_eq!(storage.collect_garbage().unwrap(), shards);
+        assert!(storage.list_blobs().unwrap().is_empty());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// Profile with one location and function per name, each sampled once
+    fn profile_of(names: &[&str]) -> Profile {
+        let mut string_table = vec![String::new(), "samples".into(), "count".into()];
+        string_table.extend(names.iter().map(|name| name.to_string()));
+        let ids = 1..=names.len() as u64;
+        Profile {
+            sample_type: vec![ValueType { ty: 1, unit: 2 }],
+            sample: ids.clone().map(|id| Sample { location_id: vec![id], value: vec![1], ..Default::default() }).collect(),
+            location: ids.clone()
+                .map(|id| Location { id, line: vec![Line { function_id: id, line: 0 }], ..Default::default() })
+                .collect(),
+            function: ids.map(|id| Function { id, name: id as i64 + 2, ..Default::default() }).collect(),
+            string_table,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn windows_differing_by_one_function_share_most_symbols() {
+        let names: Vec<String> = (0..200).map(|i| format!("module::function_{}", i)).collect();
+        let mut names: Vec<&str> = names.iter().map(String::as_str).collect();
+        let before = split("api", &profile_of(&names));
+        names.push("module::new_function");
+        let after = split("api", &profile_of(&names));
+
+        // Only the shard holding the new function's location changes
+        let new: Vec<_> = after.keys.iter().zip(&after.symbols)
+            .filter(|(key, _)| !before.keys.contains(key))
+            .collect();
+        assert_eq!(new.len(), 1);
+        let new_bytes: usize = new.iter().map(|(_, symbols)| symbols.encoded_len()).sum();
+        let total_bytes: usize = after.symbols.iter().map(|symbols| symbols.encoded_len()).sum();
+        assert!(new_bytes * 8 < total_bytes, "{} of {} bytes are new", new_bytes, total_bytes);
+
+        let joined = join(&after.symbols, &after.samples);
+        assert_eq!(export::to_collapsed(&joined, 0), export::to_collapsed(&profile_of(&names), 0));
+    }
+}
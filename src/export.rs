@@ -0,0 +1,287 @@
+//! Export of stored profiles to formats read by other tools
+//!
+//! - gzip-compressed pprof, as served by Go's `net/http/pprof` and expected
+//!   by `go tool pprof`
+//! - folded stacks (`a;b;c 42`), the input format of `flamegraph.pl` and
+//!   `inferno`
+//! - speedscope's JSON file format
+//! - an SVG flame graph rendered by the `pprof` crate's `flamegraph` feature
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use pprof::protos::{Message, Profile, Sample};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Write};
+
+/// One resolved frame of a location, see [`ProfileFrames`]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) struct Frame<'a> {
+    pub(crate) name: &'a str,
+    pub(crate) filename: &'a str,
+    pub(crate) line: i64,
+}
+
+/// Resolves sample stacks to function names through the profile's tables
+pub(crate) struct ProfileFrames<'a> {
+    profile: &'a Profile,
+    /// Frames of each location, innermost (inlined) first as in `Location.line`
+    locations: HashMap<u64, Vec<Frame<'a>>>,
+}
+
+impl<'a> ProfileFrames<'a> {
+    pub(crate) fn new(profile: &'a Profile) -> Self {
+        let string = |idx: i64| profile.string_table.get(idx as usize).map(String::as_str).unwrap_or("");
+        let functions: HashMap<u64, (&str, &str)> = profile.function.iter()
+            .map(|f| (f.id, (string(f.name), string(f.filename))))
+            .collect();
+        let locations = profile.location.iter()
+            .map(|loc| {
+                let frames = loc.line.iter()
+                    .map(|line| {
+                        let (name, filename) = functions.get(&line.function_id).copied().unwrap_or(("unknown", ""));
+                        Frame { name, filename, line: line.line }
+                    })
+                    .collect();
+                (loc.id, frames)
+            })
+            .collect();
+        ProfileFrames { profile, locations }
+    }
+
+    /// A sample's frames, root first
+    pub(crate) fn stack(&self, sample: &Sample) -> Vec<&Frame<'a>> {
+        sample.location_id.iter().rev()
+            .filter_map(|id| self.locations.get(id))
+            .flat_map(|frames| frames.iter().rev())
+            .collect()
+    }
+
+    fn string(&self, idx: i64) -> &'a str {
+        self.profile.string_table.get(idx as usize).map(String::as_str).unwrap_or("")
+    }
+}
+
+/// Encode the profile and gzip it, matching what Go's pprof endpoints serve
+pub fn to_gzipped_pprof(profile: &Profile) -> io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(&profile.encode_to_vec())?;
+    encoder.finish()
+}
+
+/// Folded stacks, one `root;...;leaf value` line per distinct stack
+///
+/// Values come from the given sample type column. Lines are sorted by stack
+/// so the output is stable.
+pub fn to_collapsed(profile: &Profile, column: usize) -> String {
+    let frames = ProfileFrames::new(profile);
+    let mut stacks: BTreeMap<String, i64> = BTreeMap::new();
+    for sample in &profile.sample {
+        let value = sample.value.get(column).copied().unwrap_or_default();
+        if value == 0 {
+            continue;
+        }
+        let stack = frames.stack(sample).iter()
+            .map(|frame| frame.name.replace(';', ":"))
+            .collect::<Vec<_>>()
+            .join(";");
+        *stacks.entry(stack).or_default() += value;
+    }
+
+    let mut out = String::new();
+    for (stack, value) in stacks {
+        out.push_str(&stack);
+        out.push(' ');
+        out.push_str(&value.to_string());
+        out.push('\n');
+    }
+    out
+}
+
+/// speedscope file, see <https://www.speedscope.app/file-format-schema.json>
+#[derive(Serialize, Debug)]
+pub struct SpeedscopeFile {
+    #[serde(rename = "$schema")]
+    pub schema: &'static str,
+    pub name: String,
+    pub exporter: String,
+    #[serde(rename = "activeProfileIndex")]
+    pub active_profile_index: usize,
+    pub shared: SpeedscopeShared,
+    pub profiles: Vec<SpeedscopeProfile>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct SpeedscopeShared {
+    pub frames: Vec<SpeedscopeFrame>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct SpeedscopeFrame {
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub line: Option<i64>,
+}
+
+/// A `sampled` profile: each sample is a list of frame indices, root first
+#[derive(Serialize, Debug)]
+pub struct SpeedscopeProfile {
+    #[serde(rename = "type")]
+    pub profile_type: &'static str,
+    pub name: String,
+    pub unit: &'static str,
+    #[serde(rename = "startValue")]
+    pub start_value: i64,
+    #[serde(rename = "endValue")]
+    pub end_value: i64,
+    pub samples: Vec<Vec<usize>>,
+    pub weights: Vec<i64>,
+}
+
+/// Map a pprof unit to one speedscope understands
+fn speedscope_unit(unit: &str) -> &'static str {
+    match unit {
+        "nanoseconds" => "nanoseconds",
+        "microseconds" => "microseconds",
+        "milliseconds" => "milliseconds",
+        "seconds" => "seconds",
+        "bytes" => "bytes",
+        _ => "none",
+    }
+}
+
+/// Convert to speedscope's format with one profile per sample type
+pub fn to_speedscope(profile: &Profile, name: &str) -> SpeedscopeFile {
+    let frames = ProfileFrames::new(profile);
+    let mut frame_index: HashMap<&Frame, usize> = HashMap::new();
+    let mut shared = Vec::new();
+    let stacks: Vec<Vec<usize>> = profile.sample.iter()
+        .map(|sample| {
+            frames.stack(sample).into_iter()
+                .map(|frame| {
+                    *frame_index.entry(frame).or_insert_with(|| {
+                        shared.push(SpeedscopeFrame {
+                            name: frame.name.to_string(),
+                            file: (!frame.filename.is_empty()).then(|| frame.filename.to_string()),
+                            line: (frame.line > 0).then_some(frame.line),
+                        });
+                        shared.len() - 1
+                    })
+                })
+                .collect()
+        })
+        .collect();
+
+    let profiles = profile.sample_type.iter().enumerate()
+        .map(|(column, sample_type)| {
+            let weights: Vec<i64> = profile.sample.iter()
+                .map(|sample| sample.value.get(column).copied().unwrap_or_default())
+                .collect();
+            SpeedscopeProfile {
+                profile_type: "sampled",
+                name: format!("{} {}", name, frames.string(sample_type.ty)),
+                unit: speedscope_unit(frames.string(sample_type.unit)),
+                start_value: 0,
+                end_value: weights.iter().sum(),
+                samples: stacks.clone(),
+                weights,
+            }
+        })
+        .collect();
+
+    SpeedscopeFile {
+        schema: "https://www.speedscope.app/file-format-schema.json",
+        name: name.to_string(),
+        exporter: format!("profiling@{}", env!("CARGO_PKG_VERSION")),
+        active_profile_index: 0,
+        shared: SpeedscopeShared { frames: shared },
+        profiles,
+    }
+}
+
+/// Render an SVG flame graph of the folded stacks with the renderer the
+/// `pprof` crate's `flamegraph` feature re-exports
+pub fn to_svg(profile: &Profile, column: usize, title: &str) -> io::Result<Vec<u8>> {
+    let collapsed = to_collapsed(profile, column);
+    let mut options = pprof::flamegraph::Options::default();
+    options.title = title.to_string();
+    let unit = profile.sample_type.get(column)
+        .and_then(|ty| profile.string_table.get(ty.unit as usize))
+        .filter(|unit| !unit.is_empty());
+    if let Some(unit) = unit {
+        options.count_name = unit.clone();
+    }
+    let mut svg = Vec::new();
+    pprof::flamegraph::from_lines(&mut options, collapsed.lines(), &mut svg)
+        .map_err(io::Error::other)?;
+    Ok(svg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use pprof::protos::{Function, Line, Location, ValueType};
+    use std::io::Read;
+
+    /// `main -> work` (3) twice and `main -> idle` (1), two value columns
+    fn fixture() -> Profile {
+        let strings = ["", "samples", "count", "cpu", "nanoseconds", "main", "work", "idle", "main.rs"];
+        let function = (5..=7)
+            .map(|name| Function { id: name as u64, name, filename: 8, ..Default::default() })
+            .collect();
+        let location = (5..=7)
+            .map(|id| Location { id, line: vec![Line { function_id: id, line: id as i64 }], ..Default::default() })
+            .collect();
+        let sample = |location_id: Vec<u64>, value: i64| Sample {
+            location_id,
+            value: vec![value, value * 1000],
+            ..Default::default()
+        };
+        Profile {
+            string_table: strings.iter().map(|s| s.to_string()).collect(),
+            sample_type: vec![ValueType { ty: 1, unit: 2 }, ValueType { ty: 3, unit: 4 }],
+            function,
+            location,
+            sample: vec![sample(vec![6, 5], 2), sample(vec![7, 5], 1), sample(vec![6, 5], 1)],
+            period: 10_000_000,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn collapses_identical_stacks() {
+        assert_eq!(to_collapsed(&fixture(), 0), "main;idle 1\nmain;work 3\n");
+        assert_eq!(to_collapsed(&fixture(), 1), "main;idle 1000\nmain;work 3000\n");
+    }
+
+    #[test]
+    fn gzip_round_trips() {
+        let profile = fixture();
+        let gz = to_gzipped_pprof(&profile).unwrap();
+        let mut raw = Vec::new();
+        GzDecoder::new(&gz[..]).read_to_end(&mut raw).unwrap();
+        assert_eq!(Profile::decode(&raw[..]).unwrap(), profile);
+    }
+
+    #[test]
+    fn speedscope_has_a_profile_per_sample_type() {
+        let file = to_speedscope(&fixture(), "test");
+        assert_eq!(file.shared.frames.len(), 3);
+        assert_eq!(file.profiles.len(), 2);
+        assert_eq!(file.profiles[0].unit, "none");
+        assert_eq!(file.profiles[0].end_value, 4);
+        assert_eq!(file.profiles[1].unit, "nanoseconds");
+        assert_eq!(file.profiles[1].end_value, 4000);
+        // Root first: main, then work
+        assert_eq!(file.profiles[0].samples[0], vec![0, 1]);
+    }
+
+    #[test]
+    fn renders_svg() {
+        let svg = String::from_utf8(to_svg(&fixture(), 1, "test").unwrap()).unwrap();
+        assert!(svg.contains("<svg") && svg.contains("work"));
+    }
+}
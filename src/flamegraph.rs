@@ -0,0 +1,519 @@
+//! Flame graph construction from pprof profiles
+//!
+//! Samples in a pprof profile carry their full call stack, leaf first. The
+//! flame graph is a prefix tree over those stacks read root first, where each
+//! node's value is the total of every sample whose stack passes through that
+//! exact call path. This matches what `pprof`'s own flamegraph renderer draws.
+//!
+//! A profile can record several values per sample, such as a sample count
+//! and CPU nanoseconds, described by `profile.sample_type`. Each value
+//! column yields its own flame graph, collected in [`ProfileGraphs`].
+//!
+//! Samples may also carry labels, such as the `thread` they ran on. A graph
+//! can be built from the samples matching a filter only, or one graph per
+//! value of a label with [`group_by_label`].
+
+use pprof::protos::{Profile, Sample};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FlameGraphNode {
+    pub id: String,
+    pub name: String,
+    pub value: u64,
+    pub children: Vec<FlameGraphNode>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FlameGraphData {
+    pub name: String,
+    pub value: u64,
+    /// Sample type the values are taken from, e.g. `cpu`
+    #[serde(default)]
+    pub sample_type: String,
+    /// Unit of the values, e.g. `nanoseconds`
+    #[serde(default)]
+    pub unit: String,
+    pub children: Vec<FlameGraphNode>,
+}
+
+/// Type and unit names of each value column, from the string table
+pub fn sample_types(profile: &Profile) -> Vec<(&str, &str)> {
+    let string = |idx: i64| profile.string_table.get(idx as usize).map(String::as_str).unwrap_or("");
+    profile.sample_type.iter()
+        .map(|t| (string(t.ty), string(t.unit)))
+        .collect()
+}
+
+/// Column shown by default: the profile's `default_sample_type` if set,
+/// otherwise the last column, as `go tool pprof` does
+pub fn default_sample_column(profile: &Profile) -> usize {
+    let types = sample_types(profile);
+    let default = profile.string_table.get(profile.default_sample_type as usize)
+        .filter(|_| profile.default_sample_type != 0);
+    default.and_then(|name| types.iter().position(|(ty, _)| ty == name))
+        .unwrap_or(types.len().saturating_sub(1))
+}
+
+/// Column of the named sample type, or the default column if no name is given
+pub fn sample_column(profile: &Profile, sample_type: Option<&str>) -> Option<usize> {
+    match sample_type {
+        None => Some(default_sample_column(profile)),
+        Some(name) => sample_types(profile).iter().position(|(ty, _)| *ty == name),
+    }
+}
+
+/// Value of the sample label `key`; numeric labels are formatted as numbers
+pub fn sample_label(profile: &Profile, sample: &Sample, key: &str) -> Option<String> {
+    let string = |idx: i64| profile.string_table.get(idx as usize).map(String::as_str).unwrap_or("");
+    sample.label.iter()
+        .find(|label| string(label.key) == key)
+        .map(|label| if label.str != 0 { string(label.str).to_string() } else { label.num.to_string() })
+}
+
+/// Every sample label key with the values it takes in the profile
+pub fn sample_label_values(profile: &Profile) -> BTreeMap<String, BTreeSet<String>> {
+    let mut values: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+    for sample in &profile.sample {
+        for label in &sample.label {
+            let key = profile.string_table.get(label.key as usize).cloned().unwrap_or_default();
+            if let Some(value) = sample_label(profile, sample, &key) {
+                values.entry(key).or_default().insert(value);
+            }
+        }
+    }
+    values
+}
+
+/// One flame graph per value of the sample label `key`, built from the
+/// samples `keep` accepts
+///
+/// Samples without the label are grouped under an empty value.
+pub fn group_by_label(
+    profile: &Profile,
+    column: usize,
+    key: &str,
+    keep: impl Fn(&Sample) -> bool,
+) -> BTreeMap<String, FlameGraphData> {
+    let values: BTreeSet<String> = profile.sample.iter()
+        .filter(|sample| keep(sample))
+        .map(|sample| sample_label(profile, sample, key).unwrap_or_default())
+        .collect();
+    values.into_iter()
+        .map(|value| {
+            let graph = FlameGraphData::from_samples(profile, column, |sample| {
+                keep(sample) && sample_label(profile, sample, key).unwrap_or_default() == value
+            });
+            (value, graph)
+        })
+        .collect()
+}
+
+/// Intermediate tree node, children are indices into the builder arena
+struct TreeNode {
+    function_id: u64,
+    name: String,
+    value: u64,
+    children: HashMap<String, usize>,
+}
+
+impl FlameGraphData {
+    /// Builds a call tree for the default sample type, see
+    /// [`default_sample_column`]
+    pub fn from_profile(profile: &Profile) -> Self {
+        Self::from_profile_column(profile, default_sample_column(profile))
+    }
+
+    /// Builds a call tree from every sample's stack, summing
+    /// `value[column]` along each distinct root-to-leaf path
+    pub fn from_profile_column(profile: &Profile, column: usize) -> Self {
+        Self::from_samples(profile, column, |_| true)
+    }
+
+    /// Like [`FlameGraphData::from_profile_column`], but only from the
+    /// samples `keep` accepts
+    pub fn from_samples(profile: &Profile, column: usize, keep: impl Fn(&Sample) -> bool) -> Self {
+        let function_names: HashMap<u64, &str> = profile.function.iter()
+            .map(|f| {
+                let name = profile.string_table.get(f.name as usize)
+                    .map(String::as_str)
+                    .unwrap_or("unknown");
+                (f.id, name)
+            })
+            .collect();
+
+        // A location expands to one frame per line; inlined functions come
+        // first, so the last line is the outermost caller.
+        let location_frames: HashMap<u64, Vec<u64>> = profile.location.iter()
+            .map(|loc| (loc.id, loc.line.iter().rev().map(|line| line.function_id).collect()))
+            .collect();
+
+        // Index 0 is the synthetic root
+        let mut arena = vec![TreeNode {
+            function_id: 0,
+            name: "root".to_string(),
+            value: 0,
+            children: HashMap::new(),
+        }];
+
+        for sample in profile.sample.iter().filter(|sample| keep(sample)) {
+            let value = sample.value.get(column).copied().unwrap_or_default().max(0) as u64;
+            arena[0].value += value;
+
+            let mut current = 0;
+            for func_id in sample.location_id.iter().rev()
+                .filter_map(|loc_id| location_frames.get(loc_id))
+                .flatten()
+            {
+                let name = function_names.get(func_id).copied().unwrap_or("unknown");
+                let next = match arena[current].children.get(name) {
+                    Some(&idx) => idx,
+                    None => {
+                        let idx = arena.len();
+                        arena.push(TreeNode {
+                            function_id: *func_id,
+                            name: name.to_string(),
+                            value: 0,
+                            children: HashMap::new(),
+                        });
+                        arena[current].children.insert(name.to_string(), idx);
+                        idx
+                    }
+                };
+                arena[next].value += value;
+                current = next;
+            }
+        }
+
+        let (sample_type, unit) = sample_types(profile).get(column).copied().unwrap_or_default();
+        FlameGraphData {
+            name: "root".to_string(),
+            value: arena[0].value,
+            sample_type: sample_type.to_string(),
+            unit: unit.to_string(),
+            children: build_children(&arena, 0),
+        }
+    }
+}
+
+/// Flame graphs of one profile keyed by sample type
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ProfileGraphs {
+    /// Sample type returned when the caller doesn't pick one
+    pub default_sample_type: String,
+    pub graphs: BTreeMap<String, FlameGraphData>,
+}
+
+impl ProfileGraphs {
+    /// Build one flame graph per value column
+    ///
+    /// Profiles without sample types get a single graph of the first column,
+    /// keyed by an empty name.
+    pub fn from_profile(profile: &Profile) -> Self {
+        let columns = profile.sample_type.len().max(1);
+        let graphs: BTreeMap<String, FlameGraphData> = (0..columns)
+            .map(|column| FlameGraphData::from_profile_column(profile, column))
+            .map(|graph| (graph.sample_type.clone(), graph))
+            .collect();
+        let default_sample_type = sample_types(profile).get(default_sample_column(profile))
+            .map(|(ty, _)| ty.to_string())
+            .unwrap_or_default();
+        ProfileGraphs {
+            default_sample_type,
+            graphs,
+        }
+    }
+
+    /// The graph for `sample_type`, or the default graph
+    pub fn get(&self, sample_type: Option<&str>) -> Option<&FlameGraphData> {
+        self.graphs.get(sample_type.unwrap_or(&self.default_sample_type))
+    }
+
+    pub fn sample_types(&self) -> Vec<&str> {
+        self.graphs.keys().map(String::as_str).collect()
+    }
+}
+
+/// Converts the children of an arena node into nested flame graph nodes,
+/// ordered by name so the output is stable across runs
+fn build_children(arena: &[TreeNode], idx: usize) -> Vec<FlameGraphNode> {
+    let mut children: Vec<_> = arena[idx].children.values()
+        .map(|&child| {
+            let node = &arena[child];
+            FlameGraphNode {
+                id: node.function_id.to_string(),
+                name: node.name.clone(),
+                value: node.value,
+                children: build_children(arena, child),
+            }
+        })
+        .collect();
+    children.sort_by(|a, b| a.name.cmp(&b.name));
+    children
+}
+
+/// A call path present in either side of a differential flame graph
+///
+/// `value` is `base + target` so that children still nest inside their
+/// parent when drawn; `delta` is `target - base`, positive for regressions.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct DiffNode {
+    pub id: String,
+    pub name: String,
+    pub value: f64,
+    pub base: f64,
+    pub target: f64,
+    pub delta: f64,
+    pub children: Vec<DiffNode>,
+}
+
+impl DiffNode {
+    fn new(id: String, name: String, base: f64, target: f64, children: Vec<DiffNode>) -> Self {
+        DiffNode {
+            id,
+            name,
+            value: base + target,
+            base,
+            target,
+            delta: target - base,
+            children,
+        }
+    }
+}
+
+/// Differential flame graph comparing a base profile with a target profile
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct DiffData {
+    pub name: String,
+    pub value: f64,
+    pub base: f64,
+    pub target: f64,
+    pub delta: f64,
+    /// Whether base values were scaled to the target's total
+    pub normalized: bool,
+    pub children: Vec<DiffNode>,
+}
+
+impl DiffData {
+    /// Merge two call trees by call path
+    ///
+    /// With `normalize`, base values are scaled so that both sides have the
+    /// same total, which compares the *share* of time each path takes rather
+    /// than raw sample counts.
+    pub fn from_flame_graphs(base: &FlameGraphData, target: &FlameGraphData, normalize: bool) -> Self {
+        let scale = if normalize && base.value > 0 {
+            target.value as f64 / base.value as f64
+        } else {
+            1.0
+        };
+        let root = DiffNode::new(
+            String::new(),
+            "root".to_string(),
+            base.value as f64 * scale,
+            target.value as f64,
+            diff_children(&base.children, &target.children, scale),
+        );
+
+        DiffData {
+            name: root.name,
+            value: root.value,
+            base: root.base,
+            target: root.target,
+            delta: root.delta,
+            normalized: normalize,
+            children: root.children,
+        }
+    }
+}
+
+fn diff_children(base: &[FlameGraphNode], target: &[FlameGraphNode], scale: f64) -> Vec<DiffNode> {
+    let mut by_name: BTreeMap<&str, (Option<&FlameGraphNode>, Option<&FlameGraphNode>)> = BTreeMap::new();
+    for node in base {
+        by_name.entry(&node.name).or_default().0 = Some(node);
+    }
+    for node in target {
+        by_name.entry(&node.name).or_default().1 = Some(node);
+    }
+
+    by_name.into_iter()
+        .map(|(name, (base, target))| {
+            let id = target.or(base).map(|n| n.id.clone()).unwrap_or_default();
+            DiffNode::new(
+                id,
+                name.to_string(),
+                base.map_or(0.0, |n| n.value as f64 * scale),
+                target.map_or(0.0, |n| n.value as f64),
+                diff_children(
+                    base.map_or(&[], |n| &n.children[..]),
+                    target.map_or(&[], |n| &n.children[..]),
+                    scale,
+                ),
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pprof::protos::{Function, Label, Line, Location, ValueType};
+
+    /// Profile with `main -> a -> c` (3), `main -> b -> c` (2), `main -> a` (1)
+    /// and a location holding `inlined` inlined into `b` (4)
+    fn fixture() -> Profile {
+        let names = ["", "main", "a", "b", "c", "inlined"];
+        let function = (1..names.len() as u64)
+            .map(|id| Function { id, name: id as i64, ..Default::default() })
+            .collect();
+        let location = vec![
+            Location { id: 10, line: vec![Line { function_id: 1, ..Default::default() }], ..Default::default() },
+            Location { id: 20, line: vec![Line { function_id: 2, ..Default::default() }], ..Default::default() },
+            Location { id: 30, line: vec![Line { function_id: 3, ..Default::default() }], ..Default::default() },
+            Location { id: 40, line: vec![Line { function_id: 4, ..Default::default() }], ..Default::default() },
+            Location {
+                id: 50,
+                line: vec![
+                    Line { function_id: 5, ..Default::default() },
+                    Line { function_id: 3, ..Default::default() },
+                ],
+                ..Default::default()
+            },
+        ];
+        let sample = |location_id: Vec<u64>, value: i64| Sample {
+            location_id,
+            value: vec![value, value * 10],
+            ..Default::default()
+        };
+
+        Profile {
+            string_table: names.iter().map(|s| s.to_string()).collect(),
+            function,
+            location,
+            sample: vec![
+                sample(vec![40, 20, 10], 3),
+                sample(vec![40, 30, 10], 2),
+                sample(vec![20, 10], 1),
+                sample(vec![50, 10], 4),
+            ],
+            ..Default::default()
+        }
+    }
+
+    fn node(name: &str, id: u64, value: u64, children: Vec<FlameGraphNode>) -> FlameGraphNode {
+        FlameGraphNode { id: id.to_string(), name: name.to_string(), value, children }
+    }
+
+    #[test]
+    fn builds_prefix_tree_per_call_path() {
+        let data = FlameGraphData::from_profile(&fixture());
+
+        let expected = FlameGraphData {
+            name: "root".to_string(),
+            value: 10,
+            sample_type: String::new(),
+            unit: String::new(),
+            children: vec![node("main", 1, 10, vec![
+                node("a", 2, 4, vec![node("c", 4, 3, vec![])]),
+                node("b", 3, 6, vec![
+                    node("c", 4, 2, vec![]),
+                    node("inlined", 5, 4, vec![]),
+                ]),
+            ])],
+        };
+        assert_eq!(data, expected);
+    }
+
+    #[test]
+    fn builds_a_graph_per_sample_type() {
+        let mut profile = fixture();
+        let types = ["samples", "count", "cpu", "nanoseconds"];
+        let offset = profile.string_table.len() as i64;
+        profile.string_table.extend(types.iter().map(|s| s.to_string()));
+        profile.sample_type = vec![
+            ValueType { ty: offset, unit: offset + 1 },
+            ValueType { ty: offset + 2, unit: offset + 3 },
+        ];
+
+        // The last column is the default when none is named
+        assert_eq!(sample_column(&profile, None), Some(1));
+        assert_eq!(sample_column(&profile, Some("samples")), Some(0));
+        assert_eq!(sample_column(&profile, Some("alloc_space")), None);
+
+        let graphs = ProfileGraphs::from_profile(&profile);
+        assert_eq!(graphs.sample_types(), vec!["cpu", "samples"]);
+        let cpu = graphs.get(None).unwrap();
+        assert_eq!((cpu.sample_type.as_str(), cpu.unit.as_str(), cpu.value), ("cpu", "nanoseconds", 100));
+        assert_eq!(graphs.get(Some("samples")).unwrap().value, 10);
+
+        profile.default_sample_type = offset;
+        assert_eq!(ProfileGraphs::from_profile(&profile).default_sample_type, "samples");
+    }
+
+    #[test]
+    fn groups_and_filters_by_sample_label() {
+        let mut profile = fixture();
+        let offset = profile.string_table.len() as i64;
+        profile.string_table.extend(["thread", "worker-1", "worker-2"].iter().map(|s| s.to_string()));
+        let thread = |value: i64| Label { key: offset, str: value, ..Default::default() };
+        profile.sample[0].label = vec![thread(offset + 1)];
+        profile.sample[1].label = vec![thread(offset + 2)];
+        profile.sample[3].label = vec![thread(offset + 1)];
+
+        assert_eq!(sample_label(&profile, &profile.sample[1], "thread").as_deref(), Some("worker-2"));
+        assert_eq!(sample_label(&profile, &profile.sample[2], "thread"), None);
+        let values = sample_label_values(&profile);
+        assert_eq!(values["thread"].iter().collect::<Vec<_>>(), vec!["worker-1", "worker-2"]);
+
+        let groups = group_by_label(&profile, 0, "thread", |_| true);
+        let totals: Vec<(&str, u64)> = groups.iter().map(|(value, graph)| (value.as_str(), graph.value)).collect();
+        assert_eq!(totals, vec![("", 1), ("worker-1", 7), ("worker-2", 2)]);
+
+        let worker = FlameGraphData::from_samples(&profile, 0, |sample| {
+            sample_label(&profile, sample, "thread").as_deref() == Some("worker-2")
+        });
+        assert_eq!(worker.children, vec![node("main", 1, 2, vec![
+            node("b", 3, 2, vec![node("c", 4, 2, vec![])]),
+        ])]);
+    }
+
+    #[test]
+    fn empty_profile_has_only_root() {
+        let data = FlameGraphData::from_profile(&Profile::default());
+        assert_eq!(data.value, 0);
+        assert!(data.children.is_empty());
+    }
+
+    #[test]
+    fn diff_merges_paths_from_both_sides() {
+        let base = FlameGraphData {
+            name: "root".to_string(),
+            value: 10,
+            sample_type: String::new(),
+            unit: String::new(),
+            children: vec![node("main", 1, 10, vec![node("a", 2, 10, vec![])])],
+        };
+        let target = FlameGraphData {
+            name: "root".to_string(),
+            value: 20,
+            sample_type: String::new(),
+            unit: String::new(),
+            children: vec![node("main", 1, 20, vec![
+                node("a", 2, 5, vec![]),
+                node("b", 3, 15, vec![]),
+            ])],
+        };
+
+        let diff = DiffData::from_flame_graphs(&base, &target, false);
+        assert_eq!(diff.delta, 10.0);
+        let main = &diff.children[0];
+        assert_eq!((main.base, main.target), (10.0, 20.0));
+        let names: Vec<_> = main.children.iter().map(|c| (c.name.as_str(), c.delta)).collect();
+        assert_eq!(names, vec![("a", -5.0), ("b", 15.0)]);
+
+        // Normalized, "main" takes the whole profile on both sides
+        let diff = DiffData::from_flame_graphs(&base, &target, true);
+        assert_eq!(diff.children[0].delta, 0.0);
+        assert_eq!(diff.children[0].children[0].base, 20.0);
+    }
+}
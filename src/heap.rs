@@ -0,0 +1,395 @@
+//! Sampling heap profiler
+//!
+//! [`TrackingAllocator`] wraps the system allocator. While a
+//! [`HeapProfilerGuard`] is running it records the call stack of roughly one
+//! allocation per `sample_interval` bytes allocated on each thread, and
+//! remembers which sampled allocations are still live. The guard's report is
+//! a pprof profile with `alloc_objects`, `alloc_space` and `inuse_space`
+//! values, the same shape as Go's heap profiles, so the server ingests and
+//! displays it like any CPU profile.
+//!
+//! The allocator only takes effect once the binary installs it:
+//!
+//! ```no_run
+//! use profiling::heap::{HeapProfilerGuard, TrackingAllocator};
+//!
+//! #[global_allocator]
+//! static ALLOC: TrackingAllocator = TrackingAllocator;
+//!
+//! let guard = HeapProfilerGuard::new(512 * 1024).unwrap();
+//! let data: Vec<String> = (0..10_000).map(|i| i.to_string()).collect();
+//! let profile = guard.report();
+//! # drop(data);
+//! ```
+
+use crate::symbolize::ProfileBuilder;
+use pprof::protos::Profile;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+/// Go's default sampling rate
+pub const DEFAULT_SAMPLE_INTERVAL: usize = 512 * 1024;
+
+/// Deepest stack recorded per sample
+const MAX_DEPTH: usize = 64;
+
+/// Set once the tracking allocator has served an allocation
+static INSTALLED: AtomicBool = AtomicBool::new(false);
+/// Set while a guard is running
+static RUNNING: AtomicBool = AtomicBool::new(false);
+static SAMPLE_INTERVAL: AtomicUsize = AtomicUsize::new(DEFAULT_SAMPLE_INTERVAL);
+/// Number of sampled allocations not yet freed, so frees skip the lock
+/// when there is nothing to look up
+static LIVE: AtomicUsize = AtomicUsize::new(0);
+static STATE: Mutex<Option<HeapState>> = Mutex::new(None);
+
+thread_local! {
+    /// Set while this thread is inside the profiler, so the profiler's own
+    /// allocations are neither sampled nor able to deadlock on `STATE`
+    static BUSY: Cell<bool> = const { Cell::new(false) };
+    /// Bytes left to allocate on this thread before the next sample
+    static UNTIL_SAMPLE: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Errors raised when starting the heap profiler
+#[derive(Debug, Clone, PartialEq)]
+pub enum HeapError {
+    /// `TrackingAllocator` is not the global allocator
+    NotInstalled,
+    /// Another `HeapProfilerGuard` is running
+    AlreadyRunning,
+}
+
+impl fmt::Display for HeapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HeapError::NotInstalled => write!(f, "TrackingAllocator is not the global allocator"),
+            HeapError::AlreadyRunning => write!(f, "heap profiler is already running"),
+        }
+    }
+}
+
+impl std::error::Error for HeapError {}
+
+/// Totals for one allocation stack, scaled to estimate all allocations
+#[derive(Debug, Clone, Copy, Default)]
+struct StackStats {
+    alloc_objects: i64,
+    alloc_space: i64,
+    inuse_space: i64,
+}
+
+struct HeapState {
+    start: SystemTime,
+    interval: usize,
+    stacks: Vec<(Vec<usize>, StackStats)>,
+    stack_ids: HashMap<Vec<usize>, usize>,
+    /// Sampled allocations still live: address to stack and scaled bytes
+    live: HashMap<usize, (usize, i64)>,
+}
+
+impl HeapState {
+    fn record(&mut self, ips: &[usize], ptr: usize, objects: i64, bytes: i64) {
+        let idx = match self.stack_ids.get(ips) {
+            Some(&idx) => idx,
+            None => {
+                self.stacks.push((ips.to_vec(), StackStats::default()));
+                self.stack_ids.insert(ips.to_vec(), self.stacks.len() - 1);
+                self.stacks.len() - 1
+            }
+        };
+        let stats = &mut self.stacks[idx].1;
+        stats.alloc_objects += objects;
+        stats.alloc_space += bytes;
+        stats.inuse_space += bytes;
+        if self.live.insert(ptr, (idx, bytes)).is_none() {
+            LIVE.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn free(&mut self, ptr: usize) {
+        if let Some((idx, bytes)) = self.live.remove(&ptr) {
+            self.stacks[idx].1.inuse_space -= bytes;
+            LIVE.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Run `f` with this thread marked as inside the profiler
+///
+/// Returns `None` if the thread already is, or is being torn down.
+fn exclusive<T>(f: impl FnOnce() -> T) -> Option<T> {
+    BUSY.try_with(|busy| {
+        if busy.replace(true) {
+            return None;
+        }
+        let result = f();
+        busy.set(false);
+        Some(result)
+    })
+    .ok()
+    .flatten()
+}
+
+/// Scale a sampled allocation to the allocations it stands for
+///
+/// Allocations smaller than the interval are sampled about once per
+/// `interval` bytes, so each sample represents `interval / size` of them.
+fn scaled(size: usize, interval: usize) -> (i64, i64) {
+    if size >= interval || size == 0 {
+        return (1, size as i64);
+    }
+    let objects = (interval as f64 / size as f64).round() as i64;
+    (objects, objects * size as i64)
+}
+
+fn record_alloc(ptr: *mut u8, size: usize) {
+    let interval = SAMPLE_INTERVAL.load(Ordering::Relaxed);
+    let sampled = UNTIL_SAMPLE.try_with(|until| {
+        let left = until.get();
+        if size >= left {
+            until.set(interval);
+            true
+        } else {
+            until.set(left - size);
+            false
+        }
+    });
+    if sampled != Ok(true) {
+        return;
+    }
+
+    exclusive(|| {
+        let mut ips = [0usize; MAX_DEPTH];
+        let mut depth = 0;
+        // SAFETY: the trace is only used on this thread and `BUSY` keeps the
+        // allocator from re-entering it
+        unsafe {
+            backtrace::trace_unsynchronized(|frame| {
+                ips[depth] = frame.ip() as usize;
+                depth += 1;
+                depth < MAX_DEPTH
+            });
+        }
+        let (objects, bytes) = scaled(size, interval);
+        if let Ok(mut state) = STATE.lock() {
+            if let Some(state) = state.as_mut() {
+                state.record(&ips[..depth], ptr as usize, objects, bytes);
+            }
+        }
+    });
+}
+
+fn record_free(ptr: *mut u8) {
+    if LIVE.load(Ordering::Relaxed) == 0 {
+        return;
+    }
+    exclusive(|| {
+        if let Ok(mut state) = STATE.lock() {
+            if let Some(state) = state.as_mut() {
+                state.free(ptr as usize);
+            }
+        }
+    });
+}
+
+/// Global allocator that samples allocations while a
+/// [`HeapProfilerGuard`] is running, and otherwise only forwards to
+/// [`System`]
+pub struct TrackingAllocator;
+
+unsafe impl GlobalAlloc for TrackingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        INSTALLED.store(true, Ordering::Relaxed);
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() && RUNNING.load(Ordering::Relaxed) {
+            record_alloc(ptr, layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        INSTALLED.store(true, Ordering::Relaxed);
+        let ptr = System.alloc_zeroed(layout);
+        if !ptr.is_null() && RUNNING.load(Ordering::Relaxed) {
+            record_alloc(ptr, layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        // Forget the address before it can be handed out again
+        if RUNNING.load(Ordering::Relaxed) {
+            record_free(ptr);
+        }
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let running = RUNNING.load(Ordering::Relaxed);
+        if running {
+            record_free(ptr);
+        }
+        let new_ptr = System.realloc(ptr, layout, new_size);
+        if !new_ptr.is_null() && running {
+            record_alloc(new_ptr, new_size);
+        }
+        new_ptr
+    }
+}
+
+/// Samples heap allocations until dropped
+///
+/// Like `pprof::ProfilerGuard`, only one guard can run at a time.
+pub struct HeapProfilerGuard {
+    _private: (),
+}
+
+impl HeapProfilerGuard {
+    /// Start sampling about once per `sample_interval` allocated bytes
+    pub fn new(sample_interval: usize) -> Result<Self, HeapError> {
+        if !INSTALLED.load(Ordering::Relaxed) {
+            return Err(HeapError::NotInstalled);
+        }
+        let interval = sample_interval.max(1);
+        let started = exclusive(|| {
+            let mut state = STATE.lock().unwrap_or_else(|e| e.into_inner());
+            if state.is_some() {
+                return false;
+            }
+            *state = Some(HeapState {
+                start: SystemTime::now(),
+                interval,
+                stacks: Vec::new(),
+                stack_ids: HashMap::new(),
+                live: HashMap::new(),
+            });
+            true
+        });
+        if started != Some(true) {
+            return Err(HeapError::AlreadyRunning);
+        }
+        SAMPLE_INTERVAL.store(interval, Ordering::Relaxed);
+        RUNNING.store(true, Ordering::Relaxed);
+        Ok(HeapProfilerGuard { _private: () })
+    }
+
+    /// Build a pprof profile of the allocations sampled so far
+    ///
+    /// `inuse_space` only counts allocations made since the guard started
+    /// that are still live.
+    pub fn report(&self) -> Profile {
+        let snapshot = exclusive(|| {
+            STATE.lock().ok().and_then(|state| {
+                state.as_ref().map(|state| (state.start, state.interval, state.stacks.clone()))
+            })
+        })
+        .flatten();
+        let Some((start, interval, stacks)) = snapshot else {
+            return Profile::default();
+        };
+        build_profile(start, interval, &stacks)
+    }
+}
+
+impl Drop for HeapProfilerGuard {
+    fn drop(&mut self) {
+        RUNNING.store(false, Ordering::Relaxed);
+        exclusive(|| {
+            let mut state = STATE.lock().unwrap_or_else(|e| e.into_inner());
+            *state = None;
+            LIVE.store(0, Ordering::Relaxed);
+        });
+    }
+}
+
+/// Whether a frame belongs to the allocator rather than the caller
+fn is_allocator_frame(name: &str) -> bool {
+    name.contains("TrackingAllocator")
+        || name.contains("__rust_alloc")
+        || name.contains("__rust_realloc")
+        || name.contains("__rg_")
+}
+
+fn build_profile(start: SystemTime, interval: usize, stacks: &[(Vec<usize>, StackStats)]) -> Profile {
+    let mut builder = ProfileBuilder::new(
+        &[("alloc_objects", "count"), ("alloc_space", "bytes"), ("inuse_space", "bytes")],
+        ("space", "bytes"),
+        interval as i64,
+    );
+    builder.default_sample_type("inuse_space");
+    for (ips, stats) in stacks {
+        builder.add_sample(
+            ips,
+            is_allocator_frame,
+            false,
+            vec![stats.alloc_objects, stats.alloc_space, stats.inuse_space],
+            &[],
+        );
+    }
+    builder.finish(start)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flamegraph::{sample_column, FlameGraphData};
+
+    #[global_allocator]
+    static ALLOC: TrackingAllocator = TrackingAllocator;
+
+    const BLOCK: usize = 64 * 1024;
+
+    #[inline(never)]
+    fn allocate_blocks() -> Vec<Vec<u8>> {
+        (0..64).map(|_| vec![1u8; BLOCK]).collect()
+    }
+
+    /// Total of a column over samples whose stack passes through `function`
+    fn total(profile: &Profile, column: &str, function: &str) -> i64 {
+        let column = sample_column(profile, Some(column)).unwrap();
+        let graph = FlameGraphData::from_profile_column(profile, column);
+        fn walk(nodes: &[crate::flamegraph::FlameGraphNode], function: &str) -> u64 {
+            nodes.iter()
+                .map(|n| if n.name.contains(function) { n.value } else { walk(&n.children, function) })
+                .sum()
+        }
+        walk(&graph.children, function) as i64
+    }
+
+    #[test]
+    fn samples_allocations_and_tracks_live_bytes() {
+        let guard = HeapProfilerGuard::new(4096).unwrap();
+        assert_eq!(HeapProfilerGuard::new(4096).err(), Some(HeapError::AlreadyRunning));
+
+        let kept = allocate_blocks();
+        drop(allocate_blocks());
+        let profile = guard.report();
+        drop(kept);
+        drop(guard);
+
+        let types: Vec<_> = crate::flamegraph::sample_types(&profile).into_iter().map(|(ty, _)| ty).collect();
+        assert_eq!(types, vec!["alloc_objects", "alloc_space", "inuse_space"]);
+        assert_eq!(crate::metadata::infer_profile_type(&profile), "heap");
+
+        // Every block is larger than the interval and so always sampled; the
+        // outer vector's sample is rounded to whole objects, so it may add a
+        // little over one interval per call
+        let blocks = (64 * BLOCK) as i64;
+        let alloc = total(&profile, "alloc_space", "allocate_blocks");
+        let inuse = total(&profile, "inuse_space", "allocate_blocks");
+        assert!((2 * blocks..=2 * blocks + 16384).contains(&alloc), "alloc_space {}", alloc);
+        assert!((blocks..=blocks + 8192).contains(&inuse), "inuse_space {}", inuse);
+    }
+
+    #[test]
+    fn scales_small_allocations() {
+        assert_eq!(scaled(1024, 4096), (4, 4096));
+        assert_eq!(scaled(8192, 4096), (1, 8192));
+    }
+}
@@ -0,0 +1,349 @@
+//! Searchable index of stored profiles
+//!
+//! The index lives in the data directory as `index.jsonl`, one
+//! [`IndexEntry`] per line, whichever storage backend holds the profiles.
+//! New profiles are appended as they are stored and the file is rewritten
+//! atomically when profiles are removed. On load, stored profiles that are
+//! missing from the index are added from their metadata, so a lost, stale
+//! or torn index file repairs itself.
+
+use crate::metadata::ProfileMetadata;
+use crate::storage::{self, ProfileStorage};
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::HashSet;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// Default number of profiles returned per page
+pub const DEFAULT_LIMIT: usize = 50;
+/// Upper bound on the page size a caller can request
+pub const MAX_LIMIT: usize = 1000;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct IndexEntry {
+    pub id: String,
+    #[serde(flatten)]
+    pub metadata: ProfileMetadata,
+}
+
+impl IndexEntry {
+    fn end_time_unix_nanos(&self) -> i64 {
+        self.metadata.start_time_unix_nanos.saturating_add(self.metadata.duration_nanos)
+    }
+}
+
+/// A single `key=value` or `key!=value` label selector
+#[derive(Debug, Clone, PartialEq)]
+pub struct LabelMatcher {
+    pub key: String,
+    pub value: String,
+    pub negate: bool,
+}
+
+impl LabelMatcher {
+    /// Parse a comma separated selector list such as `version=1.2,region!=eu`
+    pub fn parse_list(selectors: &str) -> Result<Vec<LabelMatcher>, String> {
+        selectors.split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(|selector| {
+                let (key, value, negate) = if let Some((k, v)) = selector.split_once("!=") {
+                    (k, v, true)
+                } else if let Some((k, v)) = selector.split_once('=') {
+                    (k, v, false)
+                } else {
+                    return Err(format!("invalid label selector: {}", selector));
+                };
+                Ok(LabelMatcher {
+                    key: key.trim().to_string(),
+                    value: value.trim().to_string(),
+                    negate,
+                })
+            })
+            .collect()
+    }
+
+    fn matches(&self, metadata: &ProfileMetadata) -> bool {
+        self.matches_value(metadata.labels.get(&self.key).map(String::as_str))
+    }
+
+    /// Whether a label with the matcher's key and the given value, or no
+    /// such label, satisfies the selector
+    pub fn matches_value(&self, value: Option<&str>) -> bool {
+        let equal = value == Some(self.value.as_str());
+        equal != self.negate
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SortField {
+    #[default]
+    StartTime,
+    Duration,
+    Service,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SortOrder {
+    Asc,
+    #[default]
+    Desc,
+}
+
+/// Filters, sorting and pagination for [`ProfileIndex::query`]
+///
+/// Times are Unix milliseconds; a profile matches the range if its window
+/// overlaps `[from, to]`.
+#[derive(Debug, Clone, Default)]
+pub struct ProfileQuery {
+    pub service: Option<String>,
+    pub profile_type: Option<String>,
+    pub labels: Vec<LabelMatcher>,
+    pub from: Option<i64>,
+    pub to: Option<i64>,
+    pub sort: SortField,
+    pub order: SortOrder,
+    pub offset: usize,
+    pub limit: Option<usize>,
+}
+
+impl ProfileQuery {
+    fn matches(&self, entry: &IndexEntry) -> bool {
+        let metadata = &entry.metadata;
+        self.service.as_ref().is_none_or(|s| &metadata.service_name == s)
+            && self.profile_type.as_ref().is_none_or(|t| &metadata.profile_type == t)
+            && self.labels.iter().all(|m| m.matches(metadata))
+            && self.from.is_none_or(|from| entry.end_time_unix_nanos() >= from.saturating_mul(1_000_000))
+            && self.to.is_none_or(|to| metadata.start_time_unix_nanos <= to.saturating_mul(1_000_000))
+    }
+
+    fn compare(&self, a: &IndexEntry, b: &IndexEntry) -> Ordering {
+        let ordering = match self.sort {
+            SortField::StartTime => a.metadata.start_time_unix_nanos.cmp(&b.metadata.start_time_unix_nanos),
+            SortField::Duration => a.metadata.duration_nanos.cmp(&b.metadata.duration_nanos),
+            SortField::Service => a.metadata.service_name.cmp(&b.metadata.service_name),
+        }
+        .then_with(|| a.id.cmp(&b.id));
+
+        match self.order {
+            SortOrder::Asc => ordering,
+            SortOrder::Desc => ordering.reverse(),
+        }
+    }
+}
+
+/// One page of query results
+#[derive(Serialize, Debug, Clone)]
+pub struct ProfilePage {
+    pub total: usize,
+    pub offset: usize,
+    pub limit: usize,
+    pub profiles: Vec<IndexEntry>,
+}
+
+#[derive(Debug, Default)]
+pub struct ProfileIndex {
+    entries: Vec<IndexEntry>,
+    /// Index file inside the data directory
+    path: PathBuf,
+}
+
+impl ProfileIndex {
+    /// Path of the index file inside `data_dir`
+    pub fn path(data_dir: &Path) -> PathBuf {
+        data_dir.join("index.jsonl")
+    }
+
+    /// Load the index from disk and add any stored profiles it is missing
+    ///
+    /// Unreadable lines are logged and skipped. Profiles without readable
+    /// metadata are indexed with default metadata.
+    pub fn load(storage: &dyn ProfileStorage, data_dir: &Path) -> io::Result<Self> {
+        let mut index = ProfileIndex { entries: Vec::new(), path: Self::path(data_dir) };
+
+        match fs::read_to_string(&index.path) {
+            Ok(contents) => {
+                for (line_no, line) in contents.lines().enumerate() {
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+                    match serde_json::from_str::<IndexEntry>(line) {
+                        Ok(entry) => index.entries.push(entry),
+                        Err(e) => log::warn!("Skipping index line {}: {}", line_no + 1, e),
+                    }
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+
+        let on_disk: HashSet<String> = storage.list()?.into_iter().collect();
+        index.entries.retain(|entry| on_disk.contains(&entry.id));
+
+        let known: HashSet<String> = index.entries.iter().map(|e| e.id.clone()).collect();
+        let mut missing: Vec<&String> = on_disk.iter().filter(|id| !known.contains(*id)).collect();
+        missing.sort();
+        for id in missing {
+            let metadata = storage.metadata(id).unwrap_or_default();
+            log::info!("Indexing profile {} found in storage", id);
+            index.entries.push(IndexEntry { id: id.clone(), metadata });
+        }
+
+        index.save()?;
+        Ok(index)
+    }
+
+    /// Rewrite the whole index file from memory
+    pub fn save(&self) -> io::Result<()> {
+        let mut buf = Vec::new();
+        for entry in &self.entries {
+            serde_json::to_writer(&mut buf, entry)?;
+            buf.push(b'\n');
+        }
+        storage::write_atomic(&self.path, &buf)
+    }
+
+    /// Append the entry of a newly stored profile to the index file at `path`
+    ///
+    /// This only does the file I/O, so it can run without holding the index;
+    /// `push` then makes the entry visible. A line lost to a concurrent
+    /// `save` is recovered by `load`, which indexes every stored profile.
+    pub fn append(path: &Path, entry: &IndexEntry) -> io::Result<()> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?
+            .write_all(&line)
+    }
+
+    /// Add an entry already written with `append`
+    pub fn push(&mut self, entry: IndexEntry) {
+        self.entries.push(entry);
+    }
+
+    /// Drop entries of deleted profiles and rewrite the index file
+    pub fn remove(&mut self, ids: &HashSet<String>) -> io::Result<()> {
+        self.entries.retain(|entry| !ids.contains(&entry.id));
+        self.save()
+    }
+
+    /// Every entry, in insertion order
+    pub fn entries(&self) -> &[IndexEntry] {
+        &self.entries
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Look up a single profile's entry
+    pub fn get(&self, id: &str) -> Option<&IndexEntry> {
+        self.entries.iter().find(|entry| entry.id == id)
+    }
+
+    /// Every entry matching the query's filters, sorted, without pagination
+    pub fn matching(&self, query: &ProfileQuery) -> Vec<&IndexEntry> {
+        let mut matches: Vec<&IndexEntry> = self.entries.iter()
+            .filter(|entry| query.matches(entry))
+            .collect();
+        matches.sort_by(|a, b| query.compare(a, b));
+        matches
+    }
+
+    /// Filter, sort and paginate the indexed profiles
+    pub fn query(&self, query: &ProfileQuery) -> ProfilePage {
+        let matches = self.matching(query);
+        let limit = query.limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT);
+        ProfilePage {
+            total: matches.len(),
+            offset: query.offset,
+            limit,
+            profiles: matches.into_iter()
+                .skip(query.offset)
+                .take(limit)
+                .cloned()
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    fn entry(id: &str, service: &str, start_ms: i64, labels: &[(&str, &str)]) -> IndexEntry {
+        IndexEntry {
+            id: id.to_string(),
+            metadata: ProfileMetadata {
+                service_name: service.to_string(),
+                labels: labels.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect::<BTreeMap<_, _>>(),
+                start_time_unix_nanos: start_ms * 1_000_000,
+                duration_nanos: 10_000_000_000,
+                profile_type: "cpu".to_string(),
+                ..Default::default()
+            },
+        }
+    }
+
+    fn index() -> ProfileIndex {
+        ProfileIndex {
+            entries: vec![
+                entry("a", "api", 1_000, &[("version", "1")]),
+                entry("b", "api", 20_000, &[("version", "2")]),
+                entry("c", "worker", 40_000, &[("version", "2")]),
+                entry("d", "api", 60_000, &[("version", "2"), ("region", "eu")]),
+            ],
+            ..Default::default()
+        }
+    }
+
+    fn ids(page: &ProfilePage) -> Vec<&str> {
+        page.profiles.iter().map(|e| e.id.as_str()).collect()
+    }
+
+    #[test]
+    fn filters_by_service_labels_and_time() {
+        let index = index();
+        let query = ProfileQuery {
+            service: Some("api".to_string()),
+            labels: LabelMatcher::parse_list("version=2, region!=eu").unwrap(),
+            ..Default::default()
+        };
+        assert_eq!(ids(&index.query(&query)), vec!["b"]);
+
+        // "a" ends at 11s and overlaps a range starting at 5s
+        let query = ProfileQuery { from: Some(5_000), to: Some(40_000), ..Default::default() };
+        assert_eq!(ids(&index.query(&query)), vec!["c", "b", "a"]);
+    }
+
+    #[test]
+    fn sorts_and_paginates() {
+        let index = index();
+        let query = ProfileQuery {
+            sort: SortField::Service,
+            order: SortOrder::Asc,
+            offset: 1,
+            limit: Some(2),
+            ..Default::default()
+        };
+        let page = index.query(&query);
+        assert_eq!(page.total, 4);
+        assert_eq!(ids(&page), vec!["b", "d"]);
+    }
+
+    #[test]
+    fn rejects_malformed_selectors() {
+        assert!(LabelMatcher::parse_list("version").is_err());
+    }
+}
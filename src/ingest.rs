@@ -0,0 +1,158 @@
+//! Query parameters of the HTTP `POST /ingest` endpoint
+//!
+//! The endpoint follows Pyroscope's push API so that agents in other
+//! languages can upload pprof data over plain HTTP:
+//!
+//! ```text
+//! POST /ingest?name=checkout.cpu{env=staging,region=eu}&from=1700000000&until=1700000010&spyName=gospy
+//! ```
+//!
+//! `name` is the application name, optionally suffixed with the profile type
+//! and followed by labels in braces. `from` and `until` bound the profiled
+//! window; like Pyroscope, seconds, milliseconds and nanoseconds since the
+//! Unix epoch are all accepted and told apart by magnitude.
+
+use crate::metadata::ProfileMetadata;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+
+#[derive(Deserialize, Debug, Default, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct IngestParams {
+    /// `app[.type][{key=value,...}]`
+    pub name: String,
+    pub from: Option<i64>,
+    pub until: Option<i64>,
+    /// Agent that produced the profile, e.g. `gospy`; stored as the
+    /// `spy_name` label
+    pub spy_name: Option<String>,
+    /// Payload format; only `pprof` is supported
+    pub format: Option<String>,
+}
+
+/// Name suffixes that set the profile type, and the type they map to
+const TYPE_SUFFIXES: &[(&str, &str)] = &[
+    ("cpu", "cpu"),
+    ("wall", "wall"),
+    ("alloc_objects", "heap"),
+    ("alloc_space", "heap"),
+    ("inuse_objects", "heap"),
+    ("inuse_space", "heap"),
+];
+
+impl IngestParams {
+    /// Metadata for the upload, or a message describing the bad parameter
+    pub fn metadata(&self) -> Result<ProfileMetadata, String> {
+        if let Some(format) = self.format.as_deref().filter(|f| *f != "pprof") {
+            return Err(format!("unsupported format: {} (only pprof is accepted)", format));
+        }
+        let (app, mut labels) = parse_name(&self.name)?;
+        let (service_name, profile_type) = match app.rsplit_once('.') {
+            Some((service, suffix)) => match TYPE_SUFFIXES.iter().find(|(s, _)| *s == suffix) {
+                Some((_, ty)) => (service.to_string(), ty.to_string()),
+                None => (app, String::new()),
+            },
+            None => (app, String::new()),
+        };
+        if let Some(spy_name) = &self.spy_name {
+            labels.insert("spy_name".to_string(), spy_name.clone());
+        }
+
+        let start = self.from.map(unix_nanos).unwrap_or_default();
+        let duration = match (self.from, self.until) {
+            (Some(_), Some(until)) => {
+                let end = unix_nanos(until);
+                if end < start {
+                    return Err("until is before from".to_string());
+                }
+                end - start
+            }
+            _ => 0,
+        };
+        Ok(ProfileMetadata {
+            service_name,
+            labels,
+            start_time_unix_nanos: start,
+            duration_nanos: duration,
+            profile_type,
+            ..Default::default()
+        })
+    }
+}
+
+/// Split `app{key=value,...}` into the application name and its labels
+pub fn parse_name(name: &str) -> Result<(String, BTreeMap<String, String>), String> {
+    let name = name.trim();
+    let (app, labels) = match name.split_once('{') {
+        Some((app, rest)) => {
+            let labels = rest.strip_suffix('}')
+                .ok_or_else(|| format!("unterminated labels in name: {}", name))?;
+            (app.trim(), labels)
+        }
+        None => (name, ""),
+    };
+    if app.is_empty() {
+        return Err("name is required".to_string());
+    }
+    let labels = labels.split(',')
+        .map(str::trim)
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| match pair.split_once('=') {
+            Some((key, value)) if !key.trim().is_empty() => {
+                Ok((key.trim().to_string(), value.trim().trim_matches('"').to_string()))
+            }
+            _ => Err(format!("invalid label in name: {}", pair)),
+        })
+        .collect::<Result<_, _>>()?;
+    Ok((app.to_string(), labels))
+}
+
+/// Interpret a timestamp given in seconds, milliseconds or nanoseconds
+fn unix_nanos(value: i64) -> i64 {
+    match value {
+        v if v < 100_000_000_000 => v.saturating_mul(1_000_000_000),
+        v if v < 100_000_000_000_000 => v.saturating_mul(1_000_000),
+        v => v,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_pyroscope_style_names_and_times() {
+        let params = IngestParams {
+            name: "checkout.cpu{env=staging, region=\"eu\"}".to_string(),
+            from: Some(1_700_000_000),
+            until: Some(1_700_000_010_000),
+            spy_name: Some("gospy".to_string()),
+            format: None,
+        };
+        let metadata = params.metadata().unwrap();
+        assert_eq!(metadata.service_name, "checkout");
+        assert_eq!(metadata.profile_type, "cpu");
+        assert_eq!(
+            metadata.labels.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect::<Vec<_>>(),
+            vec![("env", "staging"), ("region", "eu"), ("spy_name", "gospy")]
+        );
+        assert_eq!(metadata.start_time_unix_nanos, 1_700_000_000_000_000_000);
+        assert_eq!(metadata.duration_nanos, 10_000_000_000);
+
+        // Unknown suffixes are part of the name and the type is inferred later
+        let params = IngestParams { name: "api.v2".to_string(), ..Default::default() };
+        let metadata = params.metadata().unwrap();
+        assert_eq!((metadata.service_name.as_str(), metadata.profile_type.as_str()), ("api.v2", ""));
+    }
+
+    #[test]
+    fn rejects_malformed_parameters() {
+        assert!(parse_name("").is_err());
+        assert!(parse_name("app{env=prod").is_err());
+        assert!(parse_name("app{env}").is_err());
+        let params = IngestParams { name: "app".into(), format: Some("folded".into()), ..Default::default() };
+        assert!(params.metadata().is_err());
+        let params = IngestParams { name: "app".into(), from: Some(20), until: Some(10), ..Default::default() };
+        assert!(params.metadata().is_err());
+    }
+}
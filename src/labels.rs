@@ -0,0 +1,315 @@
+//! Scoped labels attached to profile samples
+//!
+//! Code can tag the work it does so that flame graphs can later be split or
+//! filtered by it:
+//!
+//! ```no_run
+//! use profiling::labels::with_labels;
+//!
+//! with_labels(&[("endpoint", "/checkout"), ("request_id", "42")], || {
+//!     // samples taken on this thread in here carry both labels
+//! });
+//! ```
+//!
+//! Labels belong to the current thread and nest: an inner scope adds to, or
+//! overrides, the labels of the scope around it. Since they follow the
+//! thread, async code should not hold a scope across an `.await`.
+//!
+//! Every change is recorded with a timestamp in a per-thread timeline. A
+//! [`Recording`] keeps the timeline from being trimmed while a profile is
+//! taken and looks up the labels in effect for each sample's thread at the
+//! time it was taken. Entering and leaving a scope only locks the calling
+//! thread's own timeline, which other threads touch solely while a
+//! recording reads or trims it; the process-wide registry is locked once
+//! per thread, the first time it sets labels.
+//!
+//! The wall-clock profiler timestamps every sample, so its labels are
+//! exact. pprof's CPU profiler merges identical stacks of a thread and
+//! keeps the first timestamp, so a stack that ran under several label sets
+//! in one window is attributed to the first.
+//!
+//! Profilers also add a `thread` label with the thread name and a
+//! `thread_id` label; user labels with those keys are ignored.
+
+use pprof::protos::{Label, Profile};
+use pprof::Report;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+/// Labels in effect on a thread, by key
+pub type LabelSet = BTreeMap<String, String>;
+
+/// Label keys set by the profilers themselves
+const THREAD: &str = "thread";
+const THREAD_ID: &str = "thread_id";
+
+/// A label set taking effect at a point in time
+type Change = (SystemTime, Option<Arc<LabelSet>>);
+
+/// Label changes of one thread, oldest first
+type Timeline = Arc<Mutex<Vec<Change>>>;
+
+thread_local! {
+    static CURRENT: RefCell<Option<Arc<LabelSet>>> = const { RefCell::new(None) };
+    /// This thread's timeline, shared with the registry so that recordings
+    /// can read it
+    static TIMELINE: Timeline = Registry::timeline(thread_id());
+}
+
+/// Start time of the oldest running recording in nanoseconds since the
+/// epoch, `u64::MAX` when none is running. Changes before it are trimmed by
+/// the thread that makes the next one.
+static HORIZON: AtomicU64 = AtomicU64::new(u64::MAX);
+
+struct Registry {
+    /// Timeline of every thread that has set labels
+    threads: BTreeMap<u64, Timeline>,
+    /// Start time of each running recording
+    recordings: BTreeMap<u64, SystemTime>,
+    next_recording: u64,
+}
+
+static REGISTRY: Mutex<Registry> = Mutex::new(Registry {
+    threads: BTreeMap::new(),
+    recordings: BTreeMap::new(),
+    next_recording: 0,
+});
+
+impl Registry {
+    /// Register the timeline of a new thread, taking over that of an exited
+    /// thread with the same ID so that running recordings keep its history
+    fn timeline(thread: u64) -> Timeline {
+        REGISTRY.lock().unwrap().threads.entry(thread).or_default().clone()
+    }
+
+    /// Publish the start of the oldest running recording as the horizon
+    fn update_horizon(&self) {
+        let horizon = self.recordings.values().min().map_or(u64::MAX, |time| nanos(*time));
+        HORIZON.store(horizon, Ordering::SeqCst);
+    }
+
+    /// Drop changes that no running recording can ask about, and timelines
+    /// of exited threads that nothing needs anymore
+    fn trim(&mut self) {
+        let horizon = HORIZON.load(Ordering::SeqCst);
+        let running = !self.recordings.is_empty();
+        self.threads.retain(|_, timeline| {
+            let mut changes = timeline.lock().unwrap();
+            trim(&mut changes, horizon);
+            // Only the registry holds the timeline once its thread exited
+            let exited = Arc::strong_count(timeline) == 1;
+            !exited || (running && !(changes.len() == 1 && changes[0].1.is_none()))
+        });
+    }
+}
+
+fn nanos(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH).map_or(0, |d| d.as_nanos() as u64)
+}
+
+/// Keep the change in effect at `horizon` and every later one
+fn trim(changes: &mut Vec<Change>, horizon: u64) {
+    let first = changes.iter().rposition(|(time, _)| nanos(*time) <= horizon).unwrap_or(0);
+    changes.drain(..first);
+}
+
+/// Identifier of the calling thread, the same value pprof records as
+/// `Frames::thread_id`
+pub(crate) fn thread_id() -> u64 {
+    // SAFETY: pthread_self has no preconditions and is async-signal-safe
+    unsafe { libc::pthread_self() as u64 }
+}
+
+fn set_current(labels: Option<Arc<LabelSet>>) {
+    CURRENT.with(|current| *current.borrow_mut() = labels.clone());
+    // Read the horizon after taking the timestamp: a recording starting
+    // meanwhile lowers it before taking its own start time, so a change
+    // trimmed against a stale horizon is older than the recording
+    let now = SystemTime::now();
+    let horizon = HORIZON.load(Ordering::SeqCst);
+    TIMELINE.with(|timeline| {
+        let mut changes = timeline.lock().unwrap();
+        trim(&mut changes, horizon);
+        changes.push((now, labels));
+    });
+}
+
+/// Restores the enclosing scope's labels when dropped, see [`scope`]
+#[must_use = "the labels are removed as soon as the guard is dropped"]
+pub struct LabelGuard {
+    previous: Option<Arc<LabelSet>>,
+    /// Labels belong to the thread that set them
+    _not_send: PhantomData<*const ()>,
+}
+
+impl Drop for LabelGuard {
+    fn drop(&mut self) {
+        set_current(self.previous.take());
+    }
+}
+
+/// Apply `labels` to samples of the calling thread until the guard is dropped
+pub fn scope(labels: &[(&str, &str)]) -> LabelGuard {
+    let previous = CURRENT.with(|current| current.borrow().clone());
+    let mut merged = previous.as_deref().cloned().unwrap_or_default();
+    merged.extend(labels.iter()
+        .filter(|(key, _)| *key != THREAD && *key != THREAD_ID)
+        .map(|(key, value)| (key.to_string(), value.to_string())));
+    set_current(Some(Arc::new(merged)));
+    LabelGuard { previous, _not_send: PhantomData }
+}
+
+/// Run `f` with `labels` applied to the calling thread's samples
+pub fn with_labels<R>(labels: &[(&str, &str)], f: impl FnOnce() -> R) -> R {
+    let _guard = scope(labels);
+    f()
+}
+
+/// Labels currently in effect on the calling thread
+pub fn current() -> LabelSet {
+    CURRENT.with(|current| current.borrow().as_deref().cloned().unwrap_or_default())
+}
+
+/// Keeps label history from the moment it starts so that samples taken
+/// since can be labeled
+///
+/// Start one alongside a `pprof::ProfilerGuard` and build the profile with
+/// [`Recording::pprof`] instead of `Report::pprof`.
+pub struct Recording {
+    id: u64,
+}
+
+impl Recording {
+    pub fn start() -> Self {
+        let mut registry = REGISTRY.lock().unwrap();
+        // Stop trimming before taking the start time, see `set_current`
+        HORIZON.store(0, Ordering::SeqCst);
+        let id = registry.next_recording;
+        registry.next_recording += 1;
+        registry.recordings.insert(id, SystemTime::now());
+        registry.update_horizon();
+        Recording { id }
+    }
+
+    /// Labels in effect on `thread` at `time`
+    pub fn labels_at(&self, thread: u64, time: SystemTime) -> Option<Arc<LabelSet>> {
+        let timeline = REGISTRY.lock().unwrap().threads.get(&thread)?.clone();
+        let changes = timeline.lock().unwrap();
+        let idx = changes.partition_point(|(changed, _)| *changed <= time);
+        changes.get(idx.checked_sub(1)?)?.1.clone()
+    }
+
+    /// Convert a CPU report to pprof, adding `thread_id` and user labels to
+    /// each sample
+    pub fn pprof(&self, report: &Report) -> pprof::Result<Profile> {
+        let mut profile = report.pprof()?;
+
+        // `Report::pprof` emits one sample per entry of `report.data`, in
+        // the map's iteration order; check that before relying on it
+        let entries: Vec<_> = report.data.iter().collect();
+        let aligned = entries.len() == profile.sample.len()
+            && entries.iter().zip(&profile.sample)
+                .all(|((_, count), sample)| sample.value.first() == Some(&(**count as i64)));
+        if !aligned {
+            log::warn!("Unexpected pprof sample order, leaving CPU samples without user labels");
+            return Ok(profile);
+        }
+
+        let labels: Vec<Vec<(String, String)>> = entries.iter()
+            .map(|(frames, _)| {
+                let mut labels = vec![(THREAD_ID.to_string(), frames.thread_id.to_string())];
+                if let Some(set) = self.labels_at(frames.thread_id, frames.sample_timestamp) {
+                    labels.extend(set.iter().map(|(key, value)| (key.clone(), value.clone())));
+                }
+                labels
+            })
+            .collect();
+        add_labels(&mut profile, labels);
+        Ok(profile)
+    }
+}
+
+impl Drop for Recording {
+    fn drop(&mut self) {
+        let mut registry = REGISTRY.lock().unwrap();
+        registry.recordings.remove(&self.id);
+        registry.update_horizon();
+        registry.trim();
+    }
+}
+
+/// Append string labels to each sample, interning keys and values
+fn add_labels(profile: &mut Profile, labels: Vec<Vec<(String, String)>>) {
+    let mut strings: HashMap<String, i64> = profile.string_table.iter()
+        .enumerate()
+        .map(|(idx, s)| (s.clone(), idx as i64))
+        .collect();
+    let mut intern = |table: &mut Vec<String>, s: String| -> i64 {
+        *strings.entry(s).or_insert_with_key(|s| {
+            table.push(s.clone());
+            table.len() as i64 - 1
+        })
+    };
+    for (sample, labels) in profile.sample.iter_mut().zip(labels) {
+        for (key, value) in labels {
+            let key = intern(&mut profile.string_table, key);
+            let str = intern(&mut profile.string_table, value);
+            sample.label.push(Label { key, str, ..Default::default() });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn scopes_nest_and_are_recorded_per_thread() {
+        let recording = Recording::start();
+        let thread = thread_id();
+
+        let before = SystemTime::now();
+        std::thread::sleep(Duration::from_millis(2));
+        let (outer, inner) = with_labels(&[("endpoint", "/checkout"), ("thread", "ignored")], || {
+            std::thread::sleep(Duration::from_millis(2));
+            let outer = SystemTime::now();
+            let inner = with_labels(&[("request_id", "42")], || {
+                assert_eq!(current().get("endpoint").map(String::as_str), Some("/checkout"));
+                std::thread::sleep(Duration::from_millis(2));
+                SystemTime::now()
+            });
+            (outer, inner)
+        });
+        std::thread::sleep(Duration::from_millis(2));
+        let after = SystemTime::now();
+
+        assert!(current().is_empty());
+        assert_eq!(recording.labels_at(thread, before), None);
+        let outer = recording.labels_at(thread, outer).unwrap();
+        assert_eq!(*outer, LabelSet::from([("endpoint".to_string(), "/checkout".to_string())]));
+        let inner = recording.labels_at(thread, inner).unwrap();
+        assert_eq!(inner.get("request_id").map(String::as_str), Some("42"));
+        assert_eq!(inner.len(), 2);
+        assert_eq!(recording.labels_at(thread, after), None);
+    }
+
+    #[test]
+    fn recording_sees_labels_of_exited_threads() {
+        let recording = Recording::start();
+        let (thread, labeled) = std::thread::spawn(|| {
+            let _guard = scope(&[("job", "import")]);
+            let labeled = SystemTime::now();
+            std::thread::sleep(Duration::from_millis(2));
+            (thread_id(), labeled)
+        }).join().unwrap();
+
+        let labels = recording.labels_at(thread, labeled).unwrap();
+        assert_eq!(labels.get("job").map(String::as_str), Some("import"));
+        assert_eq!(recording.labels_at(thread, SystemTime::now()), None);
+    }
+}
@@ -2,6 +2,26 @@ pub mod myservice {
     tonic::include_proto!("myservice");
 }
 
+pub mod agent;
+pub mod cache;
+pub mod config;
+pub mod dedup;
+pub mod export;
+pub mod flamegraph;
+pub mod heap;
+pub mod index;
+pub mod ingest;
+pub mod labels;
+pub mod merge;
+pub mod metadata;
+pub mod proto;
+pub mod retention;
+pub mod storage;
+pub mod timeseries;
+mod symbolize;
+pub mod upload;
+pub mod wallclock;
+
 pub mod tasks {
     use std::collections::HashMap;
 
@@ -108,45 +128,3 @@ pub mod tasks {
         result
     }
 }
-
-/// Storage utilities for managing profile data
-pub mod storage {
-    use std::fs;
-    use std::path::PathBuf;
-    use std::io;
-
-    /// Initialize the data directory for storing profiles
-    /// 
-    /// Creates the base directory if it doesn't exist
-    pub fn init_data_dir() -> io::Result<()> {
-        fs::create_dir_all("data")?;
-        Ok(())
-    }
-
-    /// Create a new directory for a specific profile
-    /// 
-    /// # Arguments
-    /// * `profile_id` - Unique identifier for the profile
-    /// 
-    /// # Returns
-    /// * `PathBuf` - Path to the created directory
-    pub fn create_profile_dir(profile_id: &str) -> io::Result<PathBuf> {
-        let dir_path = PathBuf::from("data").join(profile_id);
-        fs::create_dir_all(&dir_path)?;
-        Ok(dir_path)
-    }
-
-    /// Get the path for a profile file
-    /// 
-    /// # Arguments
-    /// * `profile_id` - Unique identifier for the profile
-    /// * `extension` - File extension (e.g., "pb" or "json")
-    /// 
-    /// # Returns
-    /// * `PathBuf` - Full path to the profile file
-    pub fn get_profile_path(profile_id: &str, extension: &str) -> PathBuf {
-        PathBuf::from("data")
-            .join(profile_id)
-            .join(format!("profile.{}", extension))
-    }
-} 
\ No newline at end of file
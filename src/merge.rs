@@ -0,0 +1,315 @@
+//! Merging of pprof profiles
+//!
+//! Every profile has its own string table and its own function, location
+//! and mapping IDs. Merging re-interns all strings into one table, assigns
+//! new IDs to identical functions, mappings and locations, and sums the
+//! values of samples that end up with the same stack and labels.
+
+use pprof::protos::{Function, Label, Line, Location, Mapping, Profile, Sample, ValueType};
+use std::collections::HashMap;
+use std::fmt;
+
+/// Errors raised while merging profiles
+#[derive(Debug, Clone, PartialEq)]
+pub enum MergeError {
+    /// Profiles record different value columns and can't be summed
+    SampleTypeMismatch { expected: Vec<String>, found: Vec<String> },
+}
+
+impl fmt::Display for MergeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MergeError::SampleTypeMismatch { expected, found } => write!(
+                f,
+                "sample types differ: expected [{}], found [{}]",
+                expected.join(", "),
+                found.join(", ")
+            ),
+        }
+    }
+}
+
+impl std::error::Error for MergeError {}
+
+/// Most profiles a single aggregation request may merge
+pub const MAX_MERGED_PROFILES: usize = 2_000;
+
+type MappingKey = (u64, u64, u64, i64, i64);
+type FunctionKey = (i64, i64, i64, i64);
+type LocationKey = (u64, u64, Vec<(u64, i64)>, bool);
+type SampleKey = (Vec<u64>, Vec<(i64, i64, i64, i64)>);
+
+/// Incrementally merges profiles into one
+#[derive(Default)]
+pub struct ProfileMerger {
+    merged: Profile,
+    strings: HashMap<String, i64>,
+    mappings: HashMap<MappingKey, u64>,
+    functions: HashMap<FunctionKey, u64>,
+    locations: HashMap<LocationKey, u64>,
+    samples: HashMap<SampleKey, usize>,
+    /// Sample types as strings, fixed by the first profile added
+    sample_types: Option<Vec<String>>,
+    end_nanos: i64,
+}
+
+impl ProfileMerger {
+    pub fn new() -> Self {
+        let mut merger = ProfileMerger::default();
+        // The string table's first entry must be the empty string
+        merger.intern("");
+        merger
+    }
+
+    /// Number of distinct samples merged so far
+    pub fn sample_count(&self) -> usize {
+        self.merged.sample.len()
+    }
+
+    fn intern(&mut self, s: &str) -> i64 {
+        if let Some(&idx) = self.strings.get(s) {
+            return idx;
+        }
+        let idx = self.merged.string_table.len() as i64;
+        self.merged.string_table.push(s.to_string());
+        self.strings.insert(s.to_string(), idx);
+        idx
+    }
+
+    /// Add one profile to the merge
+    pub fn add(&mut self, profile: &Profile) -> Result<(), MergeError> {
+        let string_at = |idx: i64| profile.string_table.get(idx as usize).map(String::as_str).unwrap_or("");
+        let sample_types: Vec<String> = profile.sample_type.iter()
+            .map(|t| format!("{}/{}", string_at(t.ty), string_at(t.unit)))
+            .collect();
+
+        match &self.sample_types {
+            Some(expected) if *expected != sample_types => {
+                return Err(MergeError::SampleTypeMismatch {
+                    expected: expected.clone(),
+                    found: sample_types,
+                });
+            }
+            Some(_) => {}
+            None => {
+                self.merged.sample_type = profile.sample_type.iter()
+                    .map(|t| ValueType {
+                        ty: self.intern(string_at(t.ty)),
+                        unit: self.intern(string_at(t.unit)),
+                    })
+                    .collect();
+                self.merged.period_type = profile.period_type.as_ref().map(|t| ValueType {
+                    ty: self.intern(string_at(t.ty)),
+                    unit: self.intern(string_at(t.unit)),
+                });
+                self.merged.period = profile.period;
+                self.merged.default_sample_type = self.intern(string_at(profile.default_sample_type));
+                self.merged.drop_frames = self.intern(string_at(profile.drop_frames));
+                self.merged.keep_frames = self.intern(string_at(profile.keep_frames));
+                self.sample_types = Some(sample_types);
+            }
+        }
+
+        // Time window covers every merged profile
+        if profile.time_nanos != 0 {
+            let end = profile.time_nanos.saturating_add(profile.duration_nanos);
+            if self.merged.time_nanos == 0 || profile.time_nanos < self.merged.time_nanos {
+                self.merged.time_nanos = profile.time_nanos;
+            }
+            self.end_nanos = self.end_nanos.max(end);
+            self.merged.duration_nanos = self.end_nanos - self.merged.time_nanos;
+        }
+
+        for &comment in &profile.comment {
+            let comment = self.intern(string_at(comment));
+            if !self.merged.comment.contains(&comment) {
+                self.merged.comment.push(comment);
+            }
+        }
+
+        let mut mapping_ids = HashMap::new();
+        for mapping in &profile.mapping {
+            let filename = self.intern(string_at(mapping.filename));
+            let build_id = self.intern(string_at(mapping.build_id));
+            let key = (mapping.memory_start, mapping.memory_limit, mapping.file_offset, filename, build_id);
+            let next_id = self.merged.mapping.len() as u64 + 1;
+            let id = *self.mappings.entry(key).or_insert_with(|| {
+                self.merged.mapping.push(Mapping {
+                    id: next_id,
+                    filename,
+                    build_id,
+                    ..mapping.clone()
+                });
+                next_id
+            });
+            mapping_ids.insert(mapping.id, id);
+        }
+
+        let mut function_ids = HashMap::new();
+        for function in &profile.function {
+            let name = self.intern(string_at(function.name));
+            let system_name = self.intern(string_at(function.system_name));
+            let filename = self.intern(string_at(function.filename));
+            let key = (name, system_name, filename, function.start_line);
+            let next_id = self.merged.function.len() as u64 + 1;
+            let id = *self.functions.entry(key).or_insert_with(|| {
+                self.merged.function.push(Function {
+                    id: next_id,
+                    name,
+                    system_name,
+                    filename,
+                    start_line: function.start_line,
+                });
+                next_id
+            });
+            function_ids.insert(function.id, id);
+        }
+
+        let mut location_ids = HashMap::new();
+        for location in &profile.location {
+            let mapping_id = mapping_ids.get(&location.mapping_id).copied().unwrap_or(0);
+            let lines: Vec<(u64, i64)> = location.line.iter()
+                .map(|line| (function_ids.get(&line.function_id).copied().unwrap_or(0), line.line))
+                .collect();
+            let key = (mapping_id, location.address, lines, location.is_folded);
+            let next_id = self.merged.location.len() as u64 + 1;
+            let merged = &mut self.merged;
+            let id = *self.locations.entry(key).or_insert_with_key(|(_, _, lines, _)| {
+                merged.location.push(Location {
+                    id: next_id,
+                    mapping_id,
+                    address: location.address,
+                    line: lines.iter()
+                        .map(|&(function_id, line)| Line { function_id, line })
+                        .collect(),
+                    is_folded: location.is_folded,
+                });
+                next_id
+            });
+            location_ids.insert(location.id, id);
+        }
+
+        for sample in &profile.sample {
+            let location_id: Vec<u64> = sample.location_id.iter()
+                .filter_map(|id| location_ids.get(id).copied())
+                .collect();
+            let label: Vec<Label> = sample.label.iter()
+                .map(|label| Label {
+                    key: self.intern(string_at(label.key)),
+                    str: if label.str != 0 { self.intern(string_at(label.str)) } else { 0 },
+                    num: label.num,
+                    num_unit: if label.num_unit != 0 { self.intern(string_at(label.num_unit)) } else { 0 },
+                })
+                .collect();
+            let key = (
+                location_id.clone(),
+                label.iter().map(|l| (l.key, l.str, l.num, l.num_unit)).collect(),
+            );
+
+            match self.samples.get(&key) {
+                Some(&idx) => {
+                    let merged = &mut self.merged.sample[idx];
+                    for (total, value) in merged.value.iter_mut().zip(&sample.value) {
+                        *total = total.saturating_add(*value);
+                    }
+                }
+                None => {
+                    self.samples.insert(key, self.merged.sample.len());
+                    self.merged.sample.push(Sample {
+                        location_id,
+                        value: sample.value.clone(),
+                        label,
+                    });
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Finish merging and return the combined profile
+    pub fn finish(self) -> Profile {
+        self.merged
+    }
+}
+
+/// Merge a set of profiles with identical sample types into one
+pub fn merge_profiles<'a>(profiles: impl IntoIterator<Item = &'a Profile>) -> Result<Profile, MergeError> {
+    let mut merger = ProfileMerger::new();
+    for profile in profiles {
+        merger.add(profile)?;
+    }
+    Ok(merger.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flamegraph::FlameGraphData;
+
+    /// Build a profile from `(stack root first, value)` pairs, with the string
+    /// table in the given order
+    fn profile(strings: &[&str], stacks: &[(&[&str], i64)], time_nanos: i64) -> Profile {
+        let idx = |s: &str| strings.iter().position(|x| *x == s).unwrap() as i64;
+        let mut profile = Profile {
+            string_table: strings.iter().map(|s| s.to_string()).collect(),
+            sample_type: vec![ValueType { ty: idx("samples"), unit: idx("count") }],
+            time_nanos,
+            duration_nanos: 10,
+            ..Default::default()
+        };
+        for name in strings.iter().filter(|s| !["", "samples", "count"].contains(s)) {
+            let id = profile.function.len() as u64 + 100;
+            profile.function.push(Function { id, name: idx(name), ..Default::default() });
+            profile.location.push(Location {
+                id: id * 2,
+                line: vec![Line { function_id: id, line: 0 }],
+                ..Default::default()
+            });
+        }
+        let location_of = |name: &str| {
+            let func = profile.function.iter().find(|f| f.name == idx(name)).unwrap();
+            func.id * 2
+        };
+        profile.sample = stacks.iter()
+            .map(|(stack, value)| Sample {
+                location_id: stack.iter().rev().map(|name| location_of(name)).collect(),
+                value: vec![*value],
+                ..Default::default()
+            })
+            .collect();
+        profile
+    }
+
+    #[test]
+    fn merges_with_remapped_tables() {
+        let a = profile(&["", "samples", "count", "main", "work"], &[(&["main", "work"], 3)], 100);
+        let b = profile(&["", "work", "idle", "main", "count", "samples"],
+            &[(&["main", "work"], 2), (&["main", "idle"], 5)], 50);
+
+        let merged = merge_profiles([&a, &b]).unwrap();
+        assert_eq!(merged.function.len(), 3);
+        assert_eq!(merged.location.len(), 3);
+        assert_eq!(merged.sample.len(), 2);
+        assert_eq!((merged.time_nanos, merged.duration_nanos), (50, 60));
+
+        let graph = FlameGraphData::from_profile(&merged);
+        assert_eq!(graph.value, 10);
+        let main = &graph.children[0];
+        let children: Vec<_> = main.children.iter().map(|c| (c.name.as_str(), c.value)).collect();
+        assert_eq!(children, vec![("idle", 5), ("work", 5)]);
+
+        // Long ranges of large values saturate instead of overflowing
+        let big = profile(&["", "samples", "count", "main"], &[(&["main"], i64::MAX - 1)], 0);
+        let merged = merge_profiles([&big, &big]).unwrap();
+        assert_eq!(merged.sample[0].value, vec![i64::MAX]);
+    }
+
+    #[test]
+    fn rejects_mismatched_sample_types() {
+        let a = profile(&["", "samples", "count", "main"], &[(&["main"], 1)], 0);
+        let mut b = a.clone();
+        b.string_table[2] = "nanoseconds".to_string();
+        assert!(matches!(merge_profiles([&a, &b]), Err(MergeError::SampleTypeMismatch { .. })));
+    }
+}
@@ -0,0 +1,166 @@
+//! Profile metadata
+//!
+//! Describes which service and instance produced a profile and the time
+//! window it covers. The server stores it as `metadata.json` next to
+//! `profile.pb`.
+
+use crate::myservice;
+use pprof::protos::Profile;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct ProfileMetadata {
+    pub service_name: String,
+    pub instance_id: String,
+    pub labels: BTreeMap<String, String>,
+    pub start_time_unix_nanos: i64,
+    pub duration_nanos: i64,
+    pub profile_type: String,
+}
+
+impl ProfileMetadata {
+    /// Fill in the time window and profile type from the profile itself
+    /// when the sender didn't provide them
+    pub fn with_profile_defaults(mut self, profile: &Profile) -> Self {
+        if self.start_time_unix_nanos == 0 {
+            self.start_time_unix_nanos = profile.time_nanos;
+        }
+        if self.duration_nanos == 0 {
+            self.duration_nanos = profile.duration_nanos;
+        }
+        if self.profile_type.is_empty() {
+            self.profile_type = infer_profile_type(profile);
+        }
+        self
+    }
+}
+
+impl From<myservice::ProfileMetadata> for ProfileMetadata {
+    fn from(meta: myservice::ProfileMetadata) -> Self {
+        ProfileMetadata {
+            service_name: meta.service_name,
+            instance_id: meta.instance_id,
+            labels: meta.labels.into_iter().collect(),
+            start_time_unix_nanos: meta.start_time_unix_nanos,
+            duration_nanos: meta.duration_nanos,
+            profile_type: meta.profile_type,
+        }
+    }
+}
+
+impl From<ProfileMetadata> for myservice::ProfileMetadata {
+    fn from(meta: ProfileMetadata) -> Self {
+        myservice::ProfileMetadata {
+            service_name: meta.service_name,
+            instance_id: meta.instance_id,
+            labels: meta.labels.into_iter().collect(),
+            start_time_unix_nanos: meta.start_time_unix_nanos,
+            duration_nanos: meta.duration_nanos,
+            profile_type: meta.profile_type,
+        }
+    }
+}
+
+/// Best-effort identifier for the current process instance
+///
+/// Uses `HOSTNAME` (set for containers and pods), then the kernel hostname,
+/// and falls back to `"unknown"`.
+pub fn default_instance_id() -> String {
+    std::env::var("HOSTNAME")
+        .ok()
+        .or_else(|| std::fs::read_to_string("/proc/sys/kernel/hostname").ok())
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+/// Guess the profile type from its sample and period types
+///
+/// A `cpu` sample type marks a CPU profile, `alloc_*`/`inuse_*` sample
+/// types a heap profile and a `wall` sample type a wall-clock profile;
+/// otherwise the period type's name is used.
+pub fn infer_profile_type(profile: &Profile) -> String {
+    let string_at = |idx: i64| profile.string_table.get(idx as usize).map(String::as_str).unwrap_or("");
+    let sample_types: Vec<&str> = profile.sample_type.iter().map(|t| string_at(t.ty)).collect();
+
+    if sample_types.contains(&"cpu") {
+        "cpu".to_string()
+    } else if sample_types.iter().any(|t| t.starts_with("alloc_") || t.starts_with("inuse_")) {
+        "heap".to_string()
+    } else if sample_types.contains(&"wall") {
+        "wall".to_string()
+    } else {
+        profile.period_type.as_ref()
+            .map(|t| string_at(t.ty))
+            .filter(|t| !t.is_empty())
+            .unwrap_or("unknown")
+            .to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pprof::protos::ValueType;
+
+    fn profile(sample_types: &[&str], period_type: &str) -> Profile {
+        let mut string_table = vec![String::new(), period_type.to_string()];
+        string_table.extend(sample_types.iter().map(|t| t.to_string()));
+        Profile {
+            sample_type: (0..sample_types.len())
+                .map(|i| ValueType { ty: i as i64 + 2, unit: 0 })
+                .collect(),
+            period_type: Some(ValueType { ty: 1, unit: 0 }),
+            string_table,
+            time_nanos: 1_000,
+            duration_nanos: 500,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn defaults_time_window_and_type_from_profile() {
+        let cpu = profile(&["samples", "cpu"], "cpu");
+        let filled = ProfileMetadata { service_name: "api".into(), ..Default::default() }
+            .with_profile_defaults(&cpu);
+        assert_eq!(
+            (filled.start_time_unix_nanos, filled.duration_nanos, filled.profile_type.as_str()),
+            (1_000, 500, "cpu")
+        );
+
+        // Values set by the sender win
+        let given = ProfileMetadata {
+            start_time_unix_nanos: 7,
+            duration_nanos: 3,
+            profile_type: "custom".into(),
+            ..Default::default()
+        };
+        assert_eq!(given.clone().with_profile_defaults(&cpu), given);
+    }
+
+    #[test]
+    fn infers_type_from_sample_types() {
+        assert_eq!(infer_profile_type(&profile(&["samples", "cpu"], "cpu")), "cpu");
+        assert_eq!(infer_profile_type(&profile(&["alloc_objects", "inuse_space"], "space")), "heap");
+        assert_eq!(infer_profile_type(&profile(&["samples", "wall"], "wall")), "wall");
+        assert_eq!(infer_profile_type(&profile(&["contentions"], "contentions")), "contentions");
+        assert_eq!(infer_profile_type(&Profile::default()), "unknown");
+    }
+
+    #[test]
+    fn round_trips_through_proto_message() {
+        let metadata = ProfileMetadata {
+            service_name: "api".into(),
+            instance_id: "host-a".into(),
+            labels: BTreeMap::from([("region".into(), "eu".into()), ("version".into(), "2".into())]),
+            start_time_unix_nanos: 1_000,
+            duration_nanos: 500,
+            profile_type: "cpu".into(),
+        };
+        let proto = myservice::ProfileMetadata::from(metadata.clone());
+        assert_eq!(proto.labels.get("region").map(String::as_str), Some("eu"));
+        assert_eq!(ProfileMetadata::from(proto), metadata);
+    }
+}
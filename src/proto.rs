@@ -1,5 +1,56 @@
+//! Typed ingestion API from `proto/pprof.proto`
+//!
+//! `Pprof.SaveProfile` takes the profile as a structured [`Profile`] message.
+//! The `pprof` crate builds its profiles with an older prost, so its
+//! `pprof::protos::Profile` is a different Rust type with the same wire
+//! format; convert between the two with `TryFrom`.
+
+// Generated code refers to `ProfileMetadata` as `super::myservice`
+use crate::myservice;
+
 pub mod pprof_proto {
     tonic::include_proto!("pprof_proto");
 }
 
-pub use pprof_proto::*; 
\ No newline at end of file
+pub use pprof_proto::*;
+
+use prost::Message;
+
+impl TryFrom<&pprof::protos::Profile> for Profile {
+    type Error = prost::DecodeError;
+
+    fn try_from(profile: &pprof::protos::Profile) -> Result<Self, Self::Error> {
+        use pprof::protos::Message as _;
+        Profile::decode(&profile.encode_to_vec()[..])
+    }
+}
+
+impl TryFrom<&Profile> for pprof::protos::Profile {
+    type Error = crate::upload::DecodeError;
+
+    fn try_from(profile: &Profile) -> Result<Self, Self::Error> {
+        crate::upload::decode_profile(&profile.encode_to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_to_and_from_pprof_profiles() {
+        let profile = pprof::protos::Profile {
+            sample_type: vec![pprof::protos::ValueType { ty: 1, unit: 2 }],
+            sample: vec![pprof::protos::Sample { location_id: vec![1], value: vec![7], label: vec![] }],
+            location: vec![pprof::protos::Location { id: 1, line: vec![pprof::protos::Line { function_id: 1, line: 3 }], ..Default::default() }],
+            function: vec![pprof::protos::Function { id: 1, name: 3, ..Default::default() }],
+            string_table: vec![String::new(), "samples".into(), "count".into(), "main".into()],
+            time_nanos: 42,
+            ..Default::default()
+        };
+        let typed = Profile::try_from(&profile).unwrap();
+        assert_eq!(typed.sample[0].value, vec![7]);
+        assert_eq!(typed.string_table[typed.function[0].name as usize], "main");
+        assert_eq!(pprof::protos::Profile::try_from(&typed).unwrap(), profile);
+    }
+}
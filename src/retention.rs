@@ -0,0 +1,317 @@
+//! Retention and downsampling of stored profiles
+//!
+//! Continuous profiling stores a profile per service every few seconds, so
+//! the server periodically compacts and then expires old ones as set by a
+//! [`RetentionPolicy`]:
+//!
+//! 1. Profiles older than `hourly_after` are merged into one profile per
+//!    service, profile type and hour, and those older than `daily_after`
+//!    into one per day. Aggregates carry a `resolution` label of `hour` or
+//!    `day`, the instance ID and labels their inputs had in common, and the
+//!    time window of all inputs. A new profile of an already compacted
+//!    bucket is merged together with the bucket's aggregate.
+//! 2. Profiles that ended more than `max_age` ago are deleted, then the
+//!    oldest of each service beyond `max_per_service`, then the oldest of
+//!    all until their total size is within `max_bytes`.
+//!
+//! [`plan_compaction`] and [`plan_expiry`] decide what to do from the index
+//! alone; the server carries the plans out. Profiles without a start time
+//! are never compacted or expired by age.
+
+use crate::index::IndexEntry;
+use crate::metadata::ProfileMetadata;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::time::Duration;
+
+/// Label marking an aggregate and the span of time each one covers
+pub const RESOLUTION_LABEL: &str = "resolution";
+
+const HOUR_NANOS: i64 = 3_600_000_000_000;
+const DAY_NANOS: i64 = 24 * HOUR_NANOS;
+
+/// What to compact and when to delete; unset limits don't apply
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RetentionPolicy {
+    /// Delete profiles that ended longer ago than this
+    pub max_age: Option<Duration>,
+    /// Delete the oldest profiles while all of them take up more bytes
+    pub max_bytes: Option<u64>,
+    /// Keep at most this many profiles per service, newest first
+    pub max_per_service: Option<usize>,
+    /// Merge profiles older than this into hourly aggregates
+    pub hourly_after: Option<Duration>,
+    /// Merge profiles older than this into daily aggregates
+    pub daily_after: Option<Duration>,
+}
+
+impl RetentionPolicy {
+    /// Whether the policy ever compacts or deletes anything
+    pub fn is_enabled(&self) -> bool {
+        *self != RetentionPolicy::default()
+    }
+}
+
+/// Span of time a stored profile covers
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Resolution {
+    /// A profile as it was uploaded
+    Raw,
+    Hour,
+    Day,
+}
+
+impl Resolution {
+    /// Resolution recorded in a profile's labels
+    pub fn of(metadata: &ProfileMetadata) -> Self {
+        match metadata.labels.get(RESOLUTION_LABEL).map(String::as_str) {
+            Some("hour") => Resolution::Hour,
+            Some("day") => Resolution::Day,
+            _ => Resolution::Raw,
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            Resolution::Raw => "raw",
+            Resolution::Hour => "hour",
+            Resolution::Day => "day",
+        }
+    }
+
+    fn bucket_nanos(self) -> i64 {
+        match self {
+            Resolution::Raw => 1,
+            Resolution::Hour => HOUR_NANOS,
+            Resolution::Day => DAY_NANOS,
+        }
+    }
+}
+
+/// Profiles to merge into one aggregate
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompactionGroup {
+    /// Profiles to merge and delete afterwards, oldest first
+    pub ids: Vec<String>,
+    /// Metadata of the aggregate
+    pub metadata: ProfileMetadata,
+}
+
+fn end_time(metadata: &ProfileMetadata) -> i64 {
+    metadata.start_time_unix_nanos.saturating_add(metadata.duration_nanos)
+}
+
+fn duration_nanos(duration: Duration) -> i64 {
+    i64::try_from(duration.as_nanos()).unwrap_or(i64::MAX)
+}
+
+/// Whether a profile ended at least `after` before `now`
+fn older_than(metadata: &ProfileMetadata, after: Option<Duration>, now: i64) -> bool {
+    match after {
+        Some(after) => metadata.start_time_unix_nanos > 0
+            && end_time(metadata) <= now.saturating_sub(duration_nanos(after)),
+        None => false,
+    }
+}
+
+/// Group old profiles into the aggregates they should be merged into
+///
+/// # Arguments
+/// * `entries` - Every indexed profile
+/// * `now` - Current time, nanoseconds since the Unix epoch
+pub fn plan_compaction(policy: &RetentionPolicy, entries: &[IndexEntry], now: i64) -> Vec<CompactionGroup> {
+    let mut groups: BTreeMap<(String, String, Resolution, i64), Vec<&IndexEntry>> = BTreeMap::new();
+    for entry in entries {
+        let current = Resolution::of(&entry.metadata);
+        let target = if older_than(&entry.metadata, policy.daily_after, now) {
+            Resolution::Day
+        } else if older_than(&entry.metadata, policy.hourly_after, now) {
+            Resolution::Hour
+        } else {
+            continue;
+        };
+        if current > target {
+            continue;
+        }
+        let start = entry.metadata.start_time_unix_nanos;
+        let bucket = start - start.rem_euclid(target.bucket_nanos());
+        let key = (entry.metadata.service_name.clone(), entry.metadata.profile_type.clone(), target, bucket);
+        groups.entry(key).or_default().push(entry);
+    }
+
+    groups.into_iter()
+        // An aggregate on its own is already where it should be
+        .filter(|((_, _, target, _), members)| {
+            members.len() > 1 || Resolution::of(&members[0].metadata) != *target
+        })
+        .map(|((service_name, profile_type, target, _), mut members)| {
+            members.sort_by_key(|entry| entry.metadata.start_time_unix_nanos);
+            let first = &members[0].metadata;
+            let start = first.start_time_unix_nanos;
+            let end = members.iter().map(|entry| end_time(&entry.metadata)).max().unwrap_or(start);
+            let instance_id = if members.iter().all(|entry| entry.metadata.instance_id == first.instance_id) {
+                first.instance_id.clone()
+            } else {
+                String::new()
+            };
+            let mut labels = first.labels.clone();
+            labels.retain(|key, value| {
+                members.iter().all(|entry| entry.metadata.labels.get(key) == Some(value))
+            });
+            labels.insert(RESOLUTION_LABEL.to_string(), target.label().to_string());
+
+            CompactionGroup {
+                ids: members.iter().map(|entry| entry.id.clone()).collect(),
+                metadata: ProfileMetadata {
+                    service_name,
+                    instance_id,
+                    labels,
+                    start_time_unix_nanos: start,
+                    duration_nanos: end - start,
+                    profile_type,
+                },
+            }
+        })
+        .collect()
+}
+
+/// IDs of profiles to delete, oldest first
+///
+/// # Arguments
+/// * `entries` - Every indexed profile
+/// * `sizes` - Stored size of each profile in bytes; missing ones count as 0
+/// * `now` - Current time, nanoseconds since the Unix epoch
+pub fn plan_expiry(
+    policy: &RetentionPolicy,
+    entries: &[IndexEntry],
+    sizes: &HashMap<String, u64>,
+    now: i64,
+) -> Vec<String> {
+    let mut by_age: Vec<&IndexEntry> = entries.iter().collect();
+    by_age.sort_by_key(|entry| entry.metadata.start_time_unix_nanos);
+
+    let mut expired: HashSet<&str> = by_age.iter()
+        .filter(|entry| older_than(&entry.metadata, policy.max_age, now))
+        .map(|entry| entry.id.as_str())
+        .collect();
+
+    if let Some(max) = policy.max_per_service {
+        let mut kept: HashMap<&str, usize> = HashMap::new();
+        for entry in by_age.iter().rev() {
+            if expired.contains(entry.id.as_str()) {
+                continue;
+            }
+            let count = kept.entry(entry.metadata.service_name.as_str()).or_default();
+            if *count < max {
+                *count += 1;
+            } else {
+                expired.insert(&entry.id);
+            }
+        }
+    }
+
+    if let Some(max_bytes) = policy.max_bytes {
+        let size = |entry: &IndexEntry| sizes.get(&entry.id).copied().unwrap_or_default();
+        let mut total: u64 = by_age.iter()
+            .filter(|entry| !expired.contains(entry.id.as_str()))
+            .map(|entry| size(entry))
+            .sum();
+        for entry in &by_age {
+            if total <= max_bytes {
+                break;
+            }
+            if expired.insert(&entry.id) {
+                total -= size(entry);
+            }
+        }
+    }
+
+    by_age.into_iter()
+        .filter(|entry| expired.contains(entry.id.as_str()))
+        .map(|entry| entry.id.clone())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MINUTE: i64 = 60_000_000_000;
+
+    fn entry(id: &str, service: &str, start: i64, labels: &[(&str, &str)]) -> IndexEntry {
+        IndexEntry {
+            id: id.to_string(),
+            metadata: ProfileMetadata {
+                service_name: service.to_string(),
+                instance_id: "host-a".to_string(),
+                labels: labels.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+                start_time_unix_nanos: start,
+                duration_nanos: 10 * 1_000_000_000,
+                profile_type: "cpu".to_string(),
+            },
+        }
+    }
+
+    #[test]
+    fn compacts_old_profiles_by_hour_and_day() {
+        let now = 10 * DAY_NANOS;
+        let policy = RetentionPolicy {
+            hourly_after: Some(Duration::from_secs(3600)),
+            daily_after: Some(Duration::from_secs(2 * 86400)),
+            ..Default::default()
+        };
+        let hour = now - 3 * HOUR_NANOS;
+        let entries = vec![
+            entry("a", "api", hour + MINUTE, &[("region", "eu"), ("version", "1")]),
+            entry("b", "api", hour + 2 * MINUTE, &[("region", "eu"), ("version", "2")]),
+            // Same hour, other service
+            entry("c", "db", hour + 3 * MINUTE, &[]),
+            // Too recent
+            entry("d", "api", now - MINUTE, &[]),
+            // Old enough for a daily aggregate, merged with the day's
+            // existing aggregate
+            entry("e", "api", now - 5 * DAY_NANOS + MINUTE, &[]),
+            entry("f", "api", now - 5 * DAY_NANOS, &[(RESOLUTION_LABEL, "day")]),
+            // Already a lone daily aggregate
+            entry("g", "api", now - 6 * DAY_NANOS, &[(RESOLUTION_LABEL, "day")]),
+        ];
+
+        let groups = plan_compaction(&policy, &entries, now);
+        let ids: Vec<Vec<&str>> = groups.iter()
+            .map(|group| group.ids.iter().map(String::as_str).collect())
+            .collect();
+        assert_eq!(ids, vec![vec!["a", "b"], vec!["f", "e"], vec!["c"]]);
+
+        let hourly = &groups[0].metadata;
+        assert_eq!(hourly.labels.get("region").map(String::as_str), Some("eu"));
+        assert!(!hourly.labels.contains_key("version"));
+        assert_eq!(Resolution::of(hourly), Resolution::Hour);
+        assert_eq!(hourly.instance_id, "host-a");
+        assert_eq!(hourly.start_time_unix_nanos, hour + MINUTE);
+        assert_eq!(hourly.duration_nanos, MINUTE + 10 * 1_000_000_000);
+        assert_eq!(Resolution::of(&groups[1].metadata), Resolution::Day);
+    }
+
+    #[test]
+    fn expires_by_age_count_and_size() {
+        let now = 10 * DAY_NANOS;
+        let entries = vec![
+            entry("old", "api", now - 8 * DAY_NANOS, &[]),
+            entry("a1", "api", now - 3 * MINUTE, &[]),
+            entry("a2", "api", now - 2 * MINUTE, &[]),
+            entry("a3", "api", now - MINUTE, &[]),
+            entry("b1", "db", now - 4 * MINUTE, &[]),
+            entry("b2", "db", now - MINUTE, &[]),
+        ];
+        let sizes: HashMap<String, u64> = entries.iter().map(|e| (e.id.clone(), 100)).collect();
+
+        let policy = RetentionPolicy { max_age: Some(Duration::from_secs(7 * 86400)), ..Default::default() };
+        assert_eq!(plan_expiry(&policy, &entries, &sizes, now), vec!["old"]);
+
+        let policy = RetentionPolicy { max_per_service: Some(2), ..policy };
+        assert_eq!(plan_expiry(&policy, &entries, &sizes, now), vec!["old", "a1"]);
+
+        // Four profiles remain, 400 bytes; the two oldest go
+        let policy = RetentionPolicy { max_bytes: Some(250), ..policy };
+        assert_eq!(plan_expiry(&policy, &entries, &sizes, now), vec!["old", "b1", "a1", "a2"]);
+    }
+}
@@ -0,0 +1,484 @@
+//! Persistent storage of profiles
+//!
+//! A stored profile has three parts: the raw pprof data, its processed
+//! flame graphs as JSON and its [`ProfileMetadata`]. [`ProfileStorage`]
+//! abstracts where they are kept, and the server picks a [`StorageBackend`]
+//! in its config:
+//!
+//! - `fs` ([`FsStorage`]): one directory per profile in the data directory,
+//!   holding `profile.pb`, `profile.json` and `metadata.json`
+//! - `sled` ([`SledStorage`]): an embedded key-value database in
+//!   `profiles.sled` inside the data directory, with one tree per part,
+//!   written in a single transaction
+//!
+//! Both report missing profiles as [`io::ErrorKind::NotFound`]. They also
+//! keep blobs shared between profiles, such as the symbol tables of
+//! [`DedupStorage`](crate::dedup::DedupStorage), in `.blobs` and in a
+//! `blobs` tree respectively.
+//!
+//! Files are written with [`write_atomic`], so a crash leaves either the
+//! old or the new contents, never a torn file. `profile.pb` is written
+//! last and marks a complete profile; directories without one are ignored.
+
+use crate::metadata::ProfileMetadata;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Data directory used unless configured otherwise, relative to the
+/// working directory
+pub const DEFAULT_DATA_DIR: &str = "data";
+
+/// Where profiles are kept
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackend {
+    /// One directory of files per profile
+    #[default]
+    Fs,
+    /// Embedded sled database
+    Sled,
+}
+
+/// Part of a stored profile, other than its metadata
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProfilePart {
+    /// Encoded pprof data
+    Raw,
+    /// Processed flame graphs, as JSON
+    Graphs,
+}
+
+impl ProfilePart {
+    /// Extension of the part's file in the filesystem layout
+    fn extension(self) -> &'static str {
+        match self {
+            ProfilePart::Raw => "pb",
+            ProfilePart::Graphs => "json",
+        }
+    }
+}
+
+/// Every part of a profile, as written by [`ProfileStorage::put`]
+#[derive(Debug, Clone, Default)]
+pub struct StoredProfile {
+    pub raw: Vec<u8>,
+    pub graphs: Vec<u8>,
+    pub metadata: ProfileMetadata,
+}
+
+/// Storage backend for profiles
+pub trait ProfileStorage: Send + Sync {
+    /// Store all parts of a profile, replacing any stored under `id`
+    fn put(&self, id: &str, profile: &StoredProfile) -> io::Result<()>;
+
+    /// Read one part of a profile
+    fn get(&self, id: &str, part: ProfilePart) -> io::Result<Vec<u8>>;
+
+    /// Read a profile's metadata
+    fn metadata(&self, id: &str) -> io::Result<ProfileMetadata>;
+
+    /// IDs of all stored profiles, sorted for a stable load order
+    fn list(&self) -> io::Result<Vec<String>>;
+
+    /// Bytes taken up by all parts of a profile
+    fn size(&self, id: &str) -> io::Result<u64>;
+
+    /// Remove a profile; removing a missing profile is not an error
+    fn delete(&self, id: &str) -> io::Result<()>;
+
+    /// Store a blob under a key derived from its contents; storing a key
+    /// that already exists leaves it as it is
+    fn put_blob(&self, key: &str, data: &[u8]) -> io::Result<()>;
+
+    /// Read a blob
+    fn get_blob(&self, key: &str) -> io::Result<Vec<u8>>;
+
+    /// Keys of all stored blobs
+    fn list_blobs(&self) -> io::Result<Vec<String>>;
+
+    /// Remove a blob; removing a missing blob is not an error
+    fn delete_blob(&self, key: &str) -> io::Result<()>;
+
+    /// Remove blobs no stored profile refers to, returning how many
+    fn collect_garbage(&self) -> io::Result<usize> {
+        Ok(0)
+    }
+}
+
+/// Shared handle to the configured backend
+pub type SharedStorage = Arc<dyn ProfileStorage>;
+
+/// Create the data directory and open the backend inside it
+pub fn open(backend: StorageBackend, data_dir: &Path) -> io::Result<SharedStorage> {
+    fs::create_dir_all(data_dir)?;
+    Ok(match backend {
+        StorageBackend::Fs => Arc::new(FsStorage::new(data_dir)?),
+        StorageBackend::Sled => Arc::new(SledStorage::open(data_dir.join("profiles.sled"))?),
+    })
+}
+
+/// Replace the file at `path` with `contents` atomically
+///
+/// The contents go to a temporary file in the same directory, which is
+/// flushed to disk and then renamed over `path`. The directory is synced
+/// too so that the rename itself survives a crash.
+pub fn write_atomic(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    let name = path.file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "path has no file name"))?;
+    let tmp = dir.join(format!(".{}.{}.tmp", name.to_string_lossy(), uuid::Uuid::new_v4()));
+
+    let result = (|| {
+        let mut file = fs::File::create(&tmp)?;
+        file.write_all(contents)?;
+        file.sync_all()?;
+        fs::rename(&tmp, path)
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    result?;
+
+    #[cfg(unix)]
+    fs::File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+fn not_found(id: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("profile {} not found", id))
+}
+
+/// Profiles as directories of files
+pub struct FsStorage {
+    root: PathBuf,
+}
+
+impl FsStorage {
+    /// Use `root` as the data directory, creating it if it doesn't exist
+    pub fn new(root: impl Into<PathBuf>) -> io::Result<Self> {
+        let root = root.into();
+        fs::create_dir_all(&root)?;
+        Ok(FsStorage { root })
+    }
+
+    /// Directory of a profile
+    ///
+    /// IDs come from URLs, so anything that isn't a plain file name is
+    /// treated as missing rather than joined onto the path.
+    fn profile_dir(&self, id: &str) -> io::Result<PathBuf> {
+        if id.is_empty() || id == "." || id == ".." || id.contains(['/', '\\']) {
+            return Err(not_found(id));
+        }
+        Ok(self.root.join(id))
+    }
+
+    /// Path of a blob, with keys checked like profile IDs
+    fn blob_path(&self, key: &str) -> io::Result<PathBuf> {
+        self.profile_dir(key)?;
+        Ok(self.root.join(".blobs").join(key))
+    }
+
+    /// Get the path for a profile file
+    ///
+    /// # Arguments
+    /// * `id` - Unique identifier for the profile
+    /// * `part` - Which file of the profile
+    ///
+    /// # Returns
+    /// * `PathBuf` - Full path to `profile.pb` or `profile.json`
+    pub fn profile_path(&self, id: &str, part: ProfilePart) -> io::Result<PathBuf> {
+        Ok(self.profile_dir(id)?.join(format!("profile.{}", part.extension())))
+    }
+
+    /// Get the path for a profile's metadata file
+    pub fn metadata_path(&self, id: &str) -> io::Result<PathBuf> {
+        Ok(self.profile_dir(id)?.join("metadata.json"))
+    }
+}
+
+impl ProfileStorage for FsStorage {
+    fn put(&self, id: &str, profile: &StoredProfile) -> io::Result<()> {
+        fs::create_dir_all(self.profile_dir(id)?)?;
+        write_atomic(&self.metadata_path(id)?, &serde_json::to_vec(&profile.metadata)?)?;
+        write_atomic(&self.profile_path(id, ProfilePart::Graphs)?, &profile.graphs)?;
+        // Written last, so that the profile is only listed once complete
+        write_atomic(&self.profile_path(id, ProfilePart::Raw)?, &profile.raw)
+    }
+
+    fn get(&self, id: &str, part: ProfilePart) -> io::Result<Vec<u8>> {
+        fs::read(self.profile_path(id, part)?)
+    }
+
+    fn metadata(&self, id: &str) -> io::Result<ProfileMetadata> {
+        let bytes = fs::read(self.metadata_path(id)?)?;
+        Ok(serde_json::from_slice(&bytes)?)
+    }
+
+    /// Every subdirectory holding a `profile.pb` is a profile; entries whose
+    /// names aren't valid UTF-8 are skipped, as are hidden ones.
+    fn list(&self) -> io::Result<Vec<String>> {
+        let mut ids = Vec::new();
+        for entry in fs::read_dir(&self.root)? {
+            let entry = entry?;
+            if !entry.file_type()?.is_dir() || !entry.path().join("profile.pb").is_file() {
+                continue;
+            }
+            match entry.file_name().into_string() {
+                Ok(id) if !id.starts_with('.') => ids.push(id),
+                _ => {}
+            }
+        }
+        ids.sort();
+        Ok(ids)
+    }
+
+    fn size(&self, id: &str) -> io::Result<u64> {
+        let mut size = 0;
+        for entry in fs::read_dir(self.profile_dir(id)?)? {
+            size += entry?.metadata()?.len();
+        }
+        Ok(size)
+    }
+
+    fn delete(&self, id: &str) -> io::Result<()> {
+        match fs::remove_dir_all(self.profile_dir(id)?) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    fn put_blob(&self, key: &str, data: &[u8]) -> io::Result<()> {
+        let path = self.blob_path(key)?;
+        if path.is_file() {
+            return Ok(());
+        }
+        fs::create_dir_all(self.root.join(".blobs"))?;
+        write_atomic(&path, data)
+    }
+
+    fn get_blob(&self, key: &str) -> io::Result<Vec<u8>> {
+        fs::read(self.blob_path(key)?)
+    }
+
+    /// Temporary files of interrupted writes are skipped
+    fn list_blobs(&self) -> io::Result<Vec<String>> {
+        let entries = match fs::read_dir(self.root.join(".blobs")) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            entries => entries?,
+        };
+        let mut keys = Vec::new();
+        for entry in entries {
+            match entry?.file_name().into_string() {
+                Ok(key) if !key.starts_with('.') => keys.push(key),
+                _ => {}
+            }
+        }
+        keys.sort();
+        Ok(keys)
+    }
+
+    fn delete_blob(&self, key: &str) -> io::Result<()> {
+        match fs::remove_file(self.blob_path(key)?) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Profiles in an embedded sled database
+pub struct SledStorage {
+    db: sled::Db,
+    raw: sled::Tree,
+    graphs: sled::Tree,
+    metadata: sled::Tree,
+    blobs: sled::Tree,
+}
+
+impl SledStorage {
+    /// Open or create the database at `path`
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let db = sled::open(path).map_err(io::Error::other)?;
+        let tree = |name: &str| db.open_tree(name).map_err(io::Error::other);
+        Ok(SledStorage {
+            raw: tree("raw")?,
+            graphs: tree("graphs")?,
+            metadata: tree("metadata")?,
+            blobs: tree("blobs")?,
+            db,
+        })
+    }
+
+    fn tree(&self, part: ProfilePart) -> &sled::Tree {
+        match part {
+            ProfilePart::Raw => &self.raw,
+            ProfilePart::Graphs => &self.graphs,
+        }
+    }
+
+    fn read(tree: &sled::Tree, id: &str) -> io::Result<Vec<u8>> {
+        match tree.get(id).map_err(io::Error::other)? {
+            Some(value) => Ok(value.to_vec()),
+            None => Err(not_found(id)),
+        }
+    }
+}
+
+impl ProfileStorage for SledStorage {
+    fn put(&self, id: &str, profile: &StoredProfile) -> io::Result<()> {
+        use sled::Transactional;
+
+        let metadata = serde_json::to_vec(&profile.metadata)?;
+        (&self.raw, &self.graphs, &self.metadata)
+            .transaction(|(raw, graphs, meta)| {
+                raw.insert(id, &profile.raw[..])?;
+                graphs.insert(id, &profile.graphs[..])?;
+                meta.insert(id, &metadata[..])?;
+                Ok::<_, sled::transaction::ConflictableTransactionError<()>>(())
+            })
+            .map_err(|e| io::Error::other(format!("{:?}", e)))?;
+        self.db.flush().map_err(io::Error::other)?;
+        Ok(())
+    }
+
+    fn get(&self, id: &str, part: ProfilePart) -> io::Result<Vec<u8>> {
+        Self::read(self.tree(part), id)
+    }
+
+    fn metadata(&self, id: &str) -> io::Result<ProfileMetadata> {
+        Ok(serde_json::from_slice(&Self::read(&self.metadata, id)?)?)
+    }
+
+    fn list(&self) -> io::Result<Vec<String>> {
+        // Keys iterate in byte order, which is also string order
+        self.raw.iter()
+            .keys()
+            .map(|key| {
+                let key = key.map_err(io::Error::other)?;
+                Ok(String::from_utf8_lossy(&key).into_owned())
+            })
+            .collect()
+    }
+
+    fn size(&self, id: &str) -> io::Result<u64> {
+        let mut size = 0;
+        for tree in [&self.raw, &self.graphs, &self.metadata] {
+            if let Some(value) = tree.get(id).map_err(io::Error::other)? {
+                size += value.len() as u64;
+            }
+        }
+        if size == 0 && !self.raw.contains_key(id).map_err(io::Error::other)? {
+            return Err(not_found(id));
+        }
+        Ok(size)
+    }
+
+    fn delete(&self, id: &str) -> io::Result<()> {
+        use sled::Transactional;
+
+        (&self.raw, &self.graphs, &self.metadata)
+            .transaction(|(raw, graphs, meta)| {
+                raw.remove(id)?;
+                graphs.remove(id)?;
+                meta.remove(id)?;
+                Ok::<_, sled::transaction::ConflictableTransactionError<()>>(())
+            })
+            .map_err(|e| io::Error::other(format!("{:?}", e)))?;
+        self.db.flush().map_err(io::Error::other)?;
+        Ok(())
+    }
+
+    fn put_blob(&self, key: &str, data: &[u8]) -> io::Result<()> {
+        // Fails if already stored, with the same contents as the key says
+        let _ = self.blobs.compare_and_swap(key, None as Option<&[u8]>, Some(data))
+            .map_err(io::Error::other)?;
+        self.db.flush().map_err(io::Error::other)?;
+        Ok(())
+    }
+
+    fn get_blob(&self, key: &str) -> io::Result<Vec<u8>> {
+        Self::read(&self.blobs, key)
+    }
+
+    fn list_blobs(&self) -> io::Result<Vec<String>> {
+        self.blobs.iter()
+            .keys()
+            .map(|key| {
+                let key = key.map_err(io::Error::other)?;
+                Ok(String::from_utf8_lossy(&key).into_owned())
+            })
+            .collect()
+    }
+
+    fn delete_blob(&self, key: &str) -> io::Result<()> {
+        self.blobs.remove(key).map_err(io::Error::other)?;
+        self.db.flush().map_err(io::Error::other)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn exercise(storage: &dyn ProfileStorage) {
+        let profile = StoredProfile {
+            raw: b"raw pprof".to_vec(),
+            graphs: b"{}".to_vec(),
+            metadata: ProfileMetadata { service_name: "checkout".into(), ..Default::default() },
+        };
+        storage.put("b", &profile).unwrap();
+        storage.put("a", &StoredProfile::default()).unwrap();
+
+        assert_eq!(storage.list().unwrap(), vec!["a", "b"]);
+        assert_eq!(storage.get("b", ProfilePart::Raw).unwrap(), profile.raw);
+        assert_eq!(storage.get("b", ProfilePart::Graphs).unwrap(), profile.graphs);
+        assert_eq!(storage.metadata("b").unwrap(), profile.metadata);
+        assert!(storage.size("b").unwrap() >= (profile.raw.len() + profile.graphs.len()) as u64);
+
+        storage.delete("b").unwrap();
+        storage.delete("b").unwrap();
+        assert_eq!(storage.list().unwrap(), vec!["a"]);
+
+        storage.put_blob("k", b"symbols").unwrap();
+        storage.put_blob("k", b"symbols").unwrap();
+        assert_eq!(storage.get_blob("k").unwrap(), b"symbols");
+        assert_eq!(storage.list_blobs().unwrap(), vec!["k"]);
+        assert_eq!(storage.list().unwrap(), vec!["a"]);
+        storage.delete_blob("k").unwrap();
+        storage.delete_blob("k").unwrap();
+        assert!(storage.list_blobs().unwrap().is_empty());
+        for missing in ["b", "../a", ""] {
+            let err = storage.get(missing, ProfilePart::Raw).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::NotFound, "{:?}", missing);
+        }
+    }
+
+    #[test]
+    fn backends_store_list_and_delete_profiles() {
+        let dir = std::env::temp_dir().join(format!("profiling-storage-{}", uuid::Uuid::new_v4()));
+        exercise(&FsStorage::new(dir.join("fs")).unwrap());
+        exercise(&SledStorage::open(dir.join("sled")).unwrap());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn atomic_writes_replace_files_without_leftovers() {
+        let dir = std::env::temp_dir().join(format!("profiling-atomic-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("profile.json");
+        write_atomic(&path, b"old").unwrap();
+        write_atomic(&path, b"new").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"new");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        // A profile directory without `profile.pb` was never completed
+        let storage = FsStorage::new(&dir).unwrap();
+        fs::create_dir_all(dir.join("torn")).unwrap();
+        write_atomic(&dir.join("torn").join("metadata.json"), b"{}").unwrap();
+        assert!(storage.list().unwrap().is_empty());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
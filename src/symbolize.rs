@@ -0,0 +1,217 @@
+//! Conversion of raw instruction pointer stacks into pprof profiles
+//!
+//! The heap and wall-clock profilers capture stacks as bare addresses so
+//! that capturing stays cheap and allocation free. This builder resolves
+//! each address once when the report is built and interns functions and
+//! locations the same way pprof-rs does for CPU profiles.
+
+use pprof::protos::{Function, Label, Line, Location, Profile, Sample, ValueType};
+use std::collections::HashMap;
+use std::time::SystemTime;
+
+/// Symbols at one address, innermost (inlined) first
+type Frames = Vec<(String, String, i64)>;
+
+pub(crate) struct ProfileBuilder {
+    profile: Profile,
+    strings: HashMap<String, i64>,
+    /// Keyed by address and whether it is an interrupted leaf
+    frames: HashMap<(usize, bool), Frames>,
+    functions: HashMap<(String, String), u64>,
+    locations: HashMap<(usize, bool), u64>,
+}
+
+impl ProfileBuilder {
+    /// Start a profile with the given `(type, unit)` value columns and
+    /// sampling period
+    pub(crate) fn new(sample_types: &[(&str, &str)], period_type: (&str, &str), period: i64) -> Self {
+        let mut builder = ProfileBuilder {
+            profile: Profile::default(),
+            strings: HashMap::new(),
+            frames: HashMap::new(),
+            functions: HashMap::new(),
+            locations: HashMap::new(),
+        };
+        builder.string("");
+        builder.profile.sample_type = sample_types.iter()
+            .map(|(ty, unit)| ValueType { ty: builder.string(ty), unit: builder.string(unit) })
+            .collect();
+        builder.profile.period_type = Some(ValueType {
+            ty: builder.string(period_type.0),
+            unit: builder.string(period_type.1),
+        });
+        builder.profile.period = period;
+        builder
+    }
+
+    pub(crate) fn string(&mut self, s: &str) -> i64 {
+        if let Some(&idx) = self.strings.get(s) {
+            return idx;
+        }
+        self.profile.string_table.push(s.to_string());
+        let idx = self.profile.string_table.len() as i64 - 1;
+        self.strings.insert(s.to_string(), idx);
+        idx
+    }
+
+    /// Column shown by default, by sample type name
+    pub(crate) fn default_sample_type(&mut self, ty: &str) {
+        self.profile.default_sample_type = self.string(ty);
+    }
+
+    /// Symbols at `ip`
+    ///
+    /// Callers' addresses are return addresses, which may already belong to
+    /// the next line or function, so they are looked up at `ip - 1` like
+    /// `backtrace::resolve_frame` does. An interrupted `leaf` is the exact
+    /// instruction and is looked up as is.
+    fn resolve(&mut self, ip: usize, leaf: bool) -> &Frames {
+        self.frames.entry((ip, leaf)).or_insert_with(|| {
+            // `backtrace::resolve` itself looks up the address before the
+            // one it is given
+            let lookup = if leaf { ip + 1 } else { ip };
+            let mut symbols = Vec::new();
+            backtrace::resolve(lookup as *mut std::ffi::c_void, |symbol| {
+                symbols.push((
+                    symbol.name().map_or_else(|| "unknown".to_string(), |name| format!("{:#}", name)),
+                    symbol.filename().map_or_else(String::new, |f| f.display().to_string()),
+                    symbol.lineno().map_or(0, i64::from),
+                ));
+            });
+            symbols
+        })
+    }
+
+    fn location(&mut self, ip: usize, leaf: bool) -> u64 {
+        if let Some(&id) = self.locations.get(&(ip, leaf)) {
+            return id;
+        }
+        let frames = self.resolve(ip, leaf).clone();
+        let line = frames.into_iter()
+            .map(|(name, filename, line)| {
+                let key = (name, filename);
+                let function_id = match self.functions.get(&key) {
+                    Some(&id) => id,
+                    None => {
+                        let id = self.profile.function.len() as u64 + 1;
+                        let function = Function {
+                            id,
+                            name: self.string(&key.0),
+                            system_name: self.string(&key.0),
+                            filename: self.string(&key.1),
+                            start_line: 0,
+                        };
+                        self.profile.function.push(function);
+                        self.functions.insert(key, id);
+                        id
+                    }
+                };
+                Line { function_id, line }
+            })
+            .collect();
+        let id = self.profile.location.len() as u64 + 1;
+        self.profile.location.push(Location { id, address: ip as u64, line, ..Default::default() });
+        self.locations.insert((ip, leaf), id);
+        id
+    }
+
+    /// Add a sample for a leaf-first stack of addresses
+    ///
+    /// Frames up to and including the last one for which `is_profiler_frame`
+    /// matches a symbol name belong to the profiler itself and are dropped.
+    /// With `interrupted`, the first remaining frame is the instruction a
+    /// signal interrupted rather than a return address.
+    pub(crate) fn add_sample(
+        &mut self,
+        ips: &[usize],
+        is_profiler_frame: impl Fn(&str) -> bool,
+        interrupted: bool,
+        value: Vec<i64>,
+        labels: &[(&str, &str)],
+    ) {
+        let first = ips.iter()
+            .rposition(|ip| self.resolve(*ip, false).iter().any(|(name, _, _)| is_profiler_frame(name)))
+            .map_or(0, |idx| idx + 1);
+        let location_id = ips[first..].iter()
+            .enumerate()
+            .map(|(idx, ip)| self.location(*ip, interrupted && idx == 0))
+            .collect();
+        let label = labels.iter()
+            .map(|(key, value)| Label {
+                key: self.string(key),
+                str: self.string(value),
+                ..Default::default()
+            })
+            .collect();
+        self.profile.sample.push(Sample { location_id, value, label });
+    }
+
+    /// Finish the profile, covering the window from `start` until now
+    pub(crate) fn finish(mut self, start: SystemTime) -> Profile {
+        self.profile.time_nanos = start.duration_since(SystemTime::UNIX_EPOCH).map_or(0, |d| d.as_nanos() as i64);
+        self.profile.duration_nanos = SystemTime::now().duration_since(start).map_or(0, |d| d.as_nanos() as i64);
+        self.profile
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[inline(never)]
+    fn profiler_entry() {}
+
+    #[inline(never)]
+    fn user_leaf() {}
+
+    #[inline(never)]
+    fn user_caller() {}
+
+    #[test]
+    fn trims_profiler_frames_and_interns_symbols() {
+        // Addresses inside each function, like the return addresses of a stack
+        let ips = [profiler_entry as fn(), user_leaf as fn(), user_caller as fn()].map(|f| f as usize + 1);
+        let is_profiler_frame = |name: &str| name.contains("profiler_entry");
+        let mut builder = ProfileBuilder::new(&[("samples", "count")], ("cpu", "nanoseconds"), 10);
+        builder.add_sample(&ips, is_profiler_frame, false, vec![1], &[("task", "a")]);
+        builder.add_sample(&ips, is_profiler_frame, false, vec![2], &[("task", "b")]);
+        builder.add_sample(&ips[1..2], is_profiler_frame, false, vec![3], &[("task", "a")]);
+        let profile = builder.finish(SystemTime::now());
+
+        // The profiler's own frame is gone, and repeated frames share IDs
+        let stacks: Vec<_> = profile.sample.iter().map(|s| s.location_id.clone()).collect();
+        assert_eq!(stacks, [vec![1, 2], vec![1, 2], vec![1]]);
+        assert_eq!(profile.location.len(), 2);
+        assert_eq!(profile.location[0].address, ips[1] as u64);
+        assert_eq!(profile.function.len(), 2);
+        let name = &profile.string_table[profile.function[0].name as usize];
+        assert!(name.ends_with("user_leaf"), "{}", name);
+        assert!(!profile.string_table.iter().any(|s| s.contains("profiler_entry")));
+
+        // Strings are stored once and labels point at the same entries
+        let unique: HashSet<_> = profile.string_table.iter().collect();
+        assert_eq!(unique.len(), profile.string_table.len());
+        assert_eq!(profile.sample[0].label[0].key, profile.sample[1].label[0].key);
+        assert_eq!(profile.sample[0].label[0].str, profile.sample[2].label[0].str);
+        assert_ne!(profile.sample[0].label[0].str, profile.sample[1].label[0].str);
+    }
+
+    #[test]
+    fn resolves_interrupted_leaf_at_its_own_address() {
+        // A signal can interrupt a function at its first instruction, whose
+        // address minus one belongs to whatever precedes it
+        let leaf = user_leaf as fn() as usize;
+        let caller = user_caller as fn() as usize + 1;
+        let mut builder = ProfileBuilder::new(&[("samples", "count")], ("wall", "nanoseconds"), 10);
+        builder.add_sample(&[leaf, caller], |_| false, true, vec![1], &[]);
+        let profile = builder.finish(SystemTime::now());
+
+        let names: Vec<_> = profile.location.iter()
+            .map(|location| &profile.string_table[profile.function[location.line[0].function_id as usize - 1].name as usize])
+            .collect();
+        assert!(names[0].ends_with("user_leaf"), "{:?}", names);
+        assert!(names[1].ends_with("user_caller"), "{:?}", names);
+        assert_eq!(profile.location[0].address, leaf as u64);
+    }
+}
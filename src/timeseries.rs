@@ -0,0 +1,468 @@
+//! Columnar sample rows for time-series queries
+//!
+//! Answering "how much CPU did `fibonacci` use per minute" from pprof data
+//! means decoding every profile in range. Instead, each stored profile also
+//! gets a segment of Arrow IPC files in `timeseries/{id}/` under the data
+//! directory, written when the profile is stored:
+//!
+//! - `samples.arrow`: one row per sample and non-zero value, with columns
+//!   `timestamp` (the profile's start, in nanoseconds), `service`, `labels`
+//!   (profile and sample labels as a JSON object), `stack_id`,
+//!   `sample_type` and `value`. The profile's default sample type is kept
+//!   in the schema metadata as `default_sample_type`.
+//! - `stacks.arrow`: `stack_id` and `stack`, the function names root first
+//!   and joined by `;` as in folded stacks
+//!
+//! Stack IDs are derived from the stack's content, so they are the same in
+//! every segment. `samples.arrow` is written last and marks a complete
+//! segment. [`TimeseriesStore::query`] sums values into fixed-width buckets,
+//! optionally only those of stacks that pass through a function.
+
+use crate::export::ProfileFrames;
+use crate::flamegraph;
+use crate::metadata::ProfileMetadata;
+use crate::storage::write_atomic;
+use arrow_array::{Array, Int64Array, RecordBatch, StringArray, TimestampNanosecondArray, UInt64Array};
+use arrow_ipc::reader::FileReader;
+use arrow_ipc::writer::FileWriter;
+use arrow_schema::{DataType, Field, Schema, TimeUnit};
+use pprof::protos::Profile;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+const DEFAULT_SAMPLE_TYPE_KEY: &str = "default_sample_type";
+
+/// Most buckets a query may return
+pub const MAX_POINTS: usize = 10_000;
+
+/// Sums over buckets of sample rows
+///
+/// Times are Unix nanoseconds; rows count towards the bucket their
+/// profile started in, and those outside `[from, to)` are left out.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TimeseriesQuery {
+    /// Only count stacks with a frame of this function, matching either its
+    /// full name or the last segments of a `::` path
+    pub function: Option<String>,
+    /// Value column to sum; defaults to each profile's default sample type
+    pub sample_type: Option<String>,
+    pub from: i64,
+    pub to: i64,
+    /// Width of each bucket, positive
+    pub step: i64,
+}
+
+impl TimeseriesQuery {
+    /// Query the range `[from, to)` in Unix milliseconds, in buckets of
+    /// `step` seconds
+    ///
+    /// `from` is rounded down to a whole step, so that buckets start e.g. on
+    /// the minute.
+    ///
+    /// # Returns
+    /// * The query, or why the range can't be queried
+    pub fn from_millis(
+        function: Option<String>,
+        sample_type: Option<String>,
+        from: i64,
+        to: i64,
+        step: i64,
+    ) -> Result<Self, String> {
+        if step <= 0 || from >= to {
+            return Err("step must be positive and from before to".to_string());
+        }
+        let from = from - from.rem_euclid(step.saturating_mul(1000));
+        let query = TimeseriesQuery {
+            function,
+            sample_type,
+            from: from.saturating_mul(1_000_000),
+            to: to.saturating_mul(1_000_000),
+            step: step.saturating_mul(1_000_000_000),
+        };
+        if query.bucket_count() > MAX_POINTS {
+            return Err(format!("at most {} points per query, use a larger step", MAX_POINTS));
+        }
+        Ok(query)
+    }
+
+    /// Number of buckets covering `[from, to)`
+    pub fn bucket_count(&self) -> usize {
+        let span = self.to.saturating_sub(self.from).max(0);
+        let buckets = span / self.step + i64::from(span % self.step != 0);
+        usize::try_from(buckets).unwrap_or(usize::MAX)
+    }
+}
+
+/// Total of one bucket
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub struct Point {
+    /// Start of the bucket, Unix milliseconds
+    pub timestamp: i64,
+    pub value: i64,
+}
+
+/// Sample type a service timeline sums unless one is asked for
+///
+/// CPU and wall-clock timelines count samples rather than sum time; other
+/// profile types keep each profile's default sample type.
+pub fn timeline_sample_type(profile_type: &str, sample_type: Option<String>) -> Option<String> {
+    match profile_type {
+        "cpu" | "wall" => sample_type.or_else(|| Some("samples".to_string())),
+        _ => sample_type,
+    }
+}
+
+/// Sum of a series over its whole range
+pub fn total(points: &[Point]) -> i64 {
+    points.iter().fold(0i64, |total, point| total.saturating_add(point.value))
+}
+
+/// Whether a frame's function name is `function`
+fn is_function(name: &str, function: &str) -> bool {
+    name == function || name.strip_suffix(function).is_some_and(|prefix| prefix.ends_with("::"))
+}
+
+fn stack_id(stack: &str) -> u64 {
+    let digest = Sha256::digest(stack.as_bytes());
+    u64::from_be_bytes(digest[..8].try_into().expect("SHA-256 is longer than 8 bytes"))
+}
+
+fn samples_schema(default_sample_type: &str) -> Schema {
+    Schema::new(vec![
+        Field::new("timestamp", DataType::Timestamp(TimeUnit::Nanosecond, None), false),
+        Field::new("service", DataType::Utf8, false),
+        Field::new("labels", DataType::Utf8, false),
+        Field::new("stack_id", DataType::UInt64, false),
+        Field::new("sample_type", DataType::Utf8, false),
+        Field::new("value", DataType::Int64, false),
+    ])
+    .with_metadata(HashMap::from([(DEFAULT_SAMPLE_TYPE_KEY.to_string(), default_sample_type.to_string())]))
+}
+
+fn stacks_schema() -> Schema {
+    Schema::new(vec![
+        Field::new("stack_id", DataType::UInt64, false),
+        Field::new("stack", DataType::Utf8, false),
+    ])
+}
+
+fn encode_batch(batch: &RecordBatch) -> io::Result<Vec<u8>> {
+    let mut writer = FileWriter::try_new(Vec::new(), &batch.schema()).map_err(io::Error::other)?;
+    writer.write(batch).map_err(io::Error::other)?;
+    writer.finish().map_err(io::Error::other)?;
+    writer.into_inner().map_err(io::Error::other)
+}
+
+/// Read every batch of an Arrow IPC file, with the file's schema metadata
+fn read_batches(path: &Path) -> io::Result<(Vec<RecordBatch>, HashMap<String, String>)> {
+    let reader = FileReader::try_new(fs::File::open(path)?, None)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let metadata = reader.schema().metadata().clone();
+    let batches = reader.collect::<Result<_, _>>()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    Ok((batches, metadata))
+}
+
+fn column<'a, T: Array + 'static>(batch: &'a RecordBatch, name: &str) -> io::Result<&'a T> {
+    batch.column_by_name(name)
+        .and_then(|column| column.as_any().downcast_ref::<T>())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("bad or missing column {}", name)))
+}
+
+/// Columnar segments of stored profiles
+pub struct TimeseriesStore {
+    root: PathBuf,
+}
+
+impl TimeseriesStore {
+    /// Keep segments in `timeseries` inside the data directory
+    pub fn new(data_dir: &Path) -> io::Result<Self> {
+        let root = data_dir.join("timeseries");
+        fs::create_dir_all(&root)?;
+        Ok(TimeseriesStore { root })
+    }
+
+    fn samples_path(&self, id: &str) -> PathBuf {
+        self.root.join(id).join("samples.arrow")
+    }
+
+    /// Whether a profile has a complete segment
+    pub fn contains(&self, id: &str) -> bool {
+        self.samples_path(id).is_file()
+    }
+
+    /// Write a profile's segment, replacing any existing one
+    ///
+    /// Profiles without a start time have no place in a time series and are
+    /// skipped.
+    pub fn write(&self, id: &str, profile: &Profile, metadata: &ProfileMetadata) -> io::Result<()> {
+        if metadata.start_time_unix_nanos == 0 {
+            return Ok(());
+        }
+        let sample_types = flamegraph::sample_types(profile);
+        let default_sample_type = sample_types.get(flamegraph::default_sample_column(profile))
+            .map_or("", |(ty, _)| ty);
+        let string = |idx: i64| profile.string_table.get(idx as usize).map(String::as_str).unwrap_or("");
+
+        let frames = ProfileFrames::new(profile);
+        let mut stacks = BTreeMap::new();
+        let (mut stack_ids, mut labels, mut types, mut values) = (Vec::new(), Vec::new(), Vec::new(), Vec::new());
+        for sample in &profile.sample {
+            let stack = frames.stack(sample).iter()
+                .map(|frame| frame.name.replace(';', ":"))
+                .collect::<Vec<_>>()
+                .join(";");
+            let id = stack_id(&stack);
+            stacks.entry(id).or_insert(stack);
+
+            let mut sample_labels = metadata.labels.clone();
+            for label in &sample.label {
+                let value = if label.str != 0 { string(label.str).to_string() } else { label.num.to_string() };
+                sample_labels.insert(string(label.key).to_string(), value);
+            }
+            let sample_labels = serde_json::to_string(&sample_labels)?;
+
+            for (&value, (ty, _)) in sample.value.iter().zip(&sample_types) {
+                if value != 0 {
+                    stack_ids.push(id);
+                    labels.push(sample_labels.clone());
+                    types.push(*ty);
+                    values.push(value);
+                }
+            }
+        }
+
+        let rows = values.len();
+        let samples = RecordBatch::try_new(Arc::new(samples_schema(default_sample_type)), vec![
+            Arc::new(TimestampNanosecondArray::from(vec![metadata.start_time_unix_nanos; rows])),
+            Arc::new(StringArray::from(vec![metadata.service_name.as_str(); rows])),
+            Arc::new(StringArray::from(labels)),
+            Arc::new(UInt64Array::from(stack_ids)),
+            Arc::new(StringArray::from(types)),
+            Arc::new(Int64Array::from(values)),
+        ]).map_err(io::Error::other)?;
+        let stacks = RecordBatch::try_new(Arc::new(stacks_schema()), vec![
+            Arc::new(UInt64Array::from_iter_values(stacks.keys().copied())),
+            Arc::new(StringArray::from_iter_values(stacks.values())),
+        ]).map_err(io::Error::other)?;
+
+        let dir = self.root.join(id);
+        fs::create_dir_all(&dir)?;
+        write_atomic(&dir.join("stacks.arrow"), &encode_batch(&stacks)?)?;
+        // Written last, so that only complete segments are read
+        write_atomic(&self.samples_path(id), &encode_batch(&samples)?)
+    }
+
+    /// Remove a profile's segment; removing a missing one is not an error
+    pub fn delete(&self, id: &str) -> io::Result<()> {
+        match fs::remove_dir_all(self.root.join(id)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// IDs of the stacks in a segment that pass through `function`
+    fn matching_stacks(&self, id: &str, function: &str) -> io::Result<HashSet<u64>> {
+        let (batches, _) = read_batches(&self.root.join(id).join("stacks.arrow"))?;
+        let mut matching = HashSet::new();
+        for batch in &batches {
+            let ids = column::<UInt64Array>(batch, "stack_id")?;
+            let stacks = column::<StringArray>(batch, "stack")?;
+            for (id, stack) in ids.values().iter().zip(stacks.iter()) {
+                if stack.unwrap_or_default().split(';').any(|name| is_function(name, function)) {
+                    matching.insert(*id);
+                }
+            }
+        }
+        Ok(matching)
+    }
+
+    /// Sum the rows of the given profiles' segments into buckets
+    ///
+    /// # Arguments
+    /// * `ids` - Profiles to include, e.g. selected from the index by
+    ///   service and time range; those without a segment are skipped
+    ///
+    /// # Returns
+    /// * One point per bucket from `query.from`, including empty ones
+    pub fn query(&self, ids: &[String], query: &TimeseriesQuery) -> io::Result<Vec<Point>> {
+        let mut totals = vec![0i64; query.bucket_count()];
+        for id in ids {
+            if !self.contains(id) {
+                continue;
+            }
+            let stacks = match &query.function {
+                Some(function) => Some(self.matching_stacks(id, function)?),
+                None => None,
+            };
+            if stacks.as_ref().is_some_and(HashSet::is_empty) {
+                continue;
+            }
+
+            let (batches, metadata) = read_batches(&self.samples_path(id))?;
+            let sample_type = query.sample_type.as_deref()
+                .or_else(|| metadata.get(DEFAULT_SAMPLE_TYPE_KEY).map(String::as_str))
+                .unwrap_or_default();
+            for batch in &batches {
+                let timestamps = column::<TimestampNanosecondArray>(batch, "timestamp")?;
+                let stack_ids = column::<UInt64Array>(batch, "stack_id")?;
+                let types = column::<StringArray>(batch, "sample_type")?;
+                let values = column::<Int64Array>(batch, "value")?;
+                for row in 0..batch.num_rows() {
+                    let timestamp = timestamps.value(row);
+                    if timestamp < query.from || timestamp >= query.to || types.value(row) != sample_type {
+                        continue;
+                    }
+                    if stacks.as_ref().is_some_and(|stacks| !stacks.contains(&stack_ids.value(row))) {
+                        continue;
+                    }
+                    let bucket = ((timestamp - query.from) / query.step) as usize;
+                    totals[bucket] = totals[bucket].saturating_add(values.value(row));
+                }
+            }
+        }
+
+        Ok(totals.into_iter()
+            .enumerate()
+            .map(|(bucket, value)| Point {
+                timestamp: (query.from + bucket as i64 * query.step) / 1_000_000,
+                value,
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pprof::protos::{Function, Label, Line, Location, Sample, ValueType};
+
+    const MINUTE: i64 = 60_000_000_000;
+
+    /// CPU profile with `count` samples of `main;fibonacci;fibonacci` and
+    /// one of `main;idle`, 10ms each
+    fn profile(count: i64) -> Profile {
+        let strings = ["", "samples", "count", "cpu", "nanoseconds", "main", "profiling::tasks::fibonacci", "idle", "thread", "worker"];
+        let location = |id: u64| Location { id, line: vec![Line { function_id: id, line: 0 }], ..Default::default() };
+        Profile {
+            sample_type: vec![ValueType { ty: 1, unit: 2 }, ValueType { ty: 3, unit: 4 }],
+            sample: vec![
+                Sample {
+                    location_id: vec![2, 2, 1],
+                    value: vec![count, count * 10_000_000],
+                    label: vec![Label { key: 8, str: 9, ..Default::default() }],
+                },
+                Sample { location_id: vec![3, 1], value: vec![1, 10_000_000], label: vec![] },
+            ],
+            location: (1..=3).map(location).collect(),
+            function: (1..=3).map(|id| Function { id, name: id as i64 + 4, ..Default::default() }).collect(),
+            string_table: strings.into_iter().map(String::from).collect(),
+            ..Default::default()
+        }
+    }
+
+    fn metadata(start: i64) -> ProfileMetadata {
+        ProfileMetadata {
+            service_name: "api".into(),
+            start_time_unix_nanos: start,
+            profile_type: "cpu".into(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn sums_function_values_per_bucket() {
+        let dir = std::env::temp_dir().join(format!("profiling-timeseries-{}", uuid::Uuid::new_v4()));
+        let store = TimeseriesStore::new(&dir).unwrap();
+        store.write("a", &profile(3), &metadata(MINUTE)).unwrap();
+        store.write("b", &profile(5), &metadata(MINUTE + 10_000_000_000)).unwrap();
+        store.write("c", &profile(7), &metadata(3 * MINUTE)).unwrap();
+        store.write("unplaced", &profile(7), &metadata(0)).unwrap();
+        assert!(store.contains("c") && !store.contains("unplaced"));
+
+        let ids: Vec<String> = ["a", "b", "c", "missing"].iter().map(|id| id.to_string()).collect();
+        let mut query = TimeseriesQuery {
+            function: Some("fibonacci".into()),
+            sample_type: None,
+            from: MINUTE,
+            to: 4 * MINUTE,
+            step: MINUTE,
+        };
+        let values = |points: Vec<Point>| points.iter().map(|p| p.value).collect::<Vec<_>>();
+        assert_eq!(values(store.query(&ids, &query).unwrap()), vec![80_000_000, 0, 70_000_000]);
+        assert_eq!(store.query(&ids, &query).unwrap()[2].timestamp, 3 * MINUTE / 1_000_000);
+
+        query.sample_type = Some("samples".into());
+        query.function = None;
+        assert_eq!(values(store.query(&ids, &query).unwrap()), vec![10, 0, 8]);
+
+        query.function = Some("tasks::fibonacci".into());
+        query.to = 3 * MINUTE;
+        assert_eq!(values(store.query(&ids, &query).unwrap()), vec![8, 0]);
+        query.function = Some("nacci".into());
+        assert_eq!(values(store.query(&ids, &query).unwrap()), vec![0, 0]);
+
+        store.delete("a").unwrap();
+        store.delete("a").unwrap();
+        assert!(!store.contains("a"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn timeline_counts_samples_with_and_without_function() {
+        let dir = std::env::temp_dir().join(format!("profiling-timeline-{}", uuid::Uuid::new_v4()));
+        let store = TimeseriesStore::new(&dir).unwrap();
+        store.write("a", &profile(3), &metadata(MINUTE)).unwrap();
+        store.write("b", &profile(5), &metadata(2 * MINUTE)).unwrap();
+        let ids = vec!["a".to_string(), "b".to_string()];
+
+        let sample_type = timeline_sample_type("cpu", None);
+        assert_eq!(sample_type.as_deref(), Some("samples"));
+        assert_eq!(timeline_sample_type("wall", Some("cpu".into())).as_deref(), Some("cpu"));
+        assert_eq!(timeline_sample_type("heap", None), None);
+
+        let ms = MINUTE / 1_000_000;
+        let query = TimeseriesQuery::from_millis(None, sample_type.clone(), ms, 3 * ms, 60).unwrap();
+        let points = store.query(&ids, &query).unwrap();
+        assert_eq!(points.iter().map(|p| p.value).collect::<Vec<_>>(), vec![4, 6]);
+        assert_eq!(total(&points), 10);
+
+        let query = TimeseriesQuery::from_millis(Some("fibonacci".into()), sample_type, ms, 3 * ms, 60).unwrap();
+        assert_eq!(total(&store.query(&ids, &query).unwrap()), 8);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn ranges_align_to_whole_steps() {
+        let ms = MINUTE / 1_000_000;
+
+        // `from` is rounded down, `to` stays exclusive
+        let query = TimeseriesQuery::from_millis(None, None, ms + 59_999, 3 * ms, 60).unwrap();
+        assert_eq!((query.from, query.to, query.step), (MINUTE, 3 * MINUTE, MINUTE));
+        assert_eq!(query.bucket_count(), 2);
+        let query = TimeseriesQuery::from_millis(None, None, ms, 3 * ms + 1, 60).unwrap();
+        assert_eq!(query.bucket_count(), 3);
+
+        // Rows at a bucket's start count towards it, rows at `to` are left out
+        let dir = std::env::temp_dir().join(format!("profiling-timeline-{}", uuid::Uuid::new_v4()));
+        let store = TimeseriesStore::new(&dir).unwrap();
+        store.write("first", &profile(1), &metadata(MINUTE)).unwrap();
+        store.write("last", &profile(2), &metadata(2 * MINUTE - 1)).unwrap();
+        store.write("after", &profile(4), &metadata(3 * MINUTE)).unwrap();
+        let ids: Vec<String> = ["first", "last", "after"].iter().map(|id| id.to_string()).collect();
+        let query = TimeseriesQuery::from_millis(None, Some("samples".into()), ms, 3 * ms, 60).unwrap();
+        let points = store.query(&ids, &query).unwrap();
+        assert_eq!(points, vec![Point { timestamp: ms, value: 5 }, Point { timestamp: 2 * ms, value: 0 }]);
+        fs::remove_dir_all(dir).unwrap();
+
+        assert!(TimeseriesQuery::from_millis(None, None, ms, ms, 60).is_err());
+        assert!(TimeseriesQuery::from_millis(None, None, 0, ms, 0).is_err());
+        assert!(TimeseriesQuery::from_millis(None, None, 0, MAX_POINTS as i64 * 1000 + 1, 1).is_err());
+        assert!(TimeseriesQuery::from_millis(None, None, 0, MAX_POINTS as i64 * 1000, 1).is_ok());
+    }
+}
@@ -0,0 +1,292 @@
+//! Decoding of uploaded profiles and chunked uploads
+//!
+//! Uploaded pprof data may be plain or gzip-compressed, the form `go tool
+//! pprof` and most language agents write; [`decode_profile`] accepts both.
+//!
+//! Unary requests are capped by tonic's 4 MB message limit. A streamed
+//! upload instead sends the metadata, then the pprof data in chunks, then
+//! an [`UploadCommit`] with the total size and SHA-256 of the data. The
+//! server assembles the chunks with [`UploadAssembler`] and only stores the
+//! profile once the commit matches what arrived.
+
+use crate::metadata::ProfileMetadata;
+use crate::myservice::upload_profile_chunk::Chunk;
+use crate::myservice::{UploadCommit, UploadProfileChunk};
+use flate2::read::MultiGzDecoder;
+use pprof::protos::{Message, Profile};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::io::{self, Read};
+
+/// Data bytes per chunk sent by [`chunks`], well below the message limit
+pub const DEFAULT_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Largest profile the server accepts by default, see `max_upload_bytes`
+pub const DEFAULT_MAX_UPLOAD_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Largest size gzip-compressed profile data may inflate to
+pub const MAX_INFLATED_SIZE: u64 = 512 * 1024 * 1024;
+
+/// Ways a streamed upload can be malformed
+#[derive(Debug, PartialEq)]
+pub enum UploadError {
+    /// Metadata was sent after data or more than once
+    UnexpectedMetadata,
+    /// A chunk arrived after the commit
+    AfterCommit,
+    /// The stream ended without a commit
+    MissingCommit,
+    /// The data exceeds the assembler's size limit
+    TooLarge { limit: u64 },
+    /// The commit's size doesn't match the bytes received
+    SizeMismatch { expected: u64, received: u64 },
+    /// The commit's checksum doesn't match the bytes received
+    ChecksumMismatch { expected: String, received: String },
+}
+
+impl fmt::Display for UploadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UploadError::UnexpectedMetadata => write!(f, "metadata must be the first chunk and sent once"),
+            UploadError::AfterCommit => write!(f, "chunk received after commit"),
+            UploadError::MissingCommit => write!(f, "upload ended without a commit"),
+            UploadError::TooLarge { limit } => write!(f, "upload exceeds {} bytes", limit),
+            UploadError::SizeMismatch { expected, received } => {
+                write!(f, "commit declares {} bytes but {} were received", expected, received)
+            }
+            UploadError::ChecksumMismatch { expected, received } => {
+                write!(f, "checksum mismatch: commit has {}, data hashes to {}", expected, received)
+            }
+        }
+    }
+}
+
+impl std::error::Error for UploadError {}
+
+/// Why uploaded bytes couldn't be decoded into a profile
+#[derive(Debug)]
+pub enum DecodeError {
+    /// The data looks gzip-compressed but doesn't inflate
+    Gzip(io::Error),
+    /// The data inflates to more than [`MAX_INFLATED_SIZE`]
+    TooLarge,
+    /// The (inflated) data isn't a pprof protobuf
+    Protobuf(String),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::Gzip(e) => write!(f, "invalid gzip data: {}", e),
+            DecodeError::TooLarge => write!(f, "profile inflates to more than {} bytes", MAX_INFLATED_SIZE),
+            DecodeError::Protobuf(e) => write!(f, "invalid pprof data: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// Whether `data` starts with the gzip magic bytes
+pub fn is_gzip(data: &[u8]) -> bool {
+    data.starts_with(&[0x1f, 0x8b])
+}
+
+/// Decode pprof data, inflating it first if it is gzip-compressed
+pub fn decode_profile(data: &[u8]) -> Result<Profile, DecodeError> {
+    if !is_gzip(data) {
+        return Profile::decode(data).map_err(|e| DecodeError::Protobuf(e.to_string()));
+    }
+    let mut inflated = Vec::new();
+    MultiGzDecoder::new(data)
+        .take(MAX_INFLATED_SIZE + 1)
+        .read_to_end(&mut inflated)
+        .map_err(DecodeError::Gzip)?;
+    if inflated.len() as u64 > MAX_INFLATED_SIZE {
+        return Err(DecodeError::TooLarge);
+    }
+    Profile::decode(&inflated[..]).map_err(|e| DecodeError::Protobuf(e.to_string()))
+}
+
+/// Lowercase hex SHA-256 of `data`
+pub fn sha256_hex(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+/// Split an encoded profile into the messages of one upload
+///
+/// Messages are built as they are consumed, so streaming them keeps only
+/// one chunk copied at a time on top of `data` itself.
+pub fn chunks<D>(data: D, metadata: ProfileMetadata, chunk_size: usize) -> impl Iterator<Item = UploadProfileChunk>
+where
+    D: AsRef<[u8]>,
+{
+    let bytes = data.as_ref();
+    let chunk_size = chunk_size.max(1);
+    let parts = bytes.len().div_ceil(chunk_size);
+    let metadata = Chunk::Metadata(metadata.into());
+    let commit = Chunk::Commit(UploadCommit {
+        size: bytes.len() as u64,
+        sha256: sha256_hex(bytes),
+    });
+    let data = (0..parts).map(move |part| {
+        let bytes = data.as_ref();
+        let start = part * chunk_size;
+        Chunk::Data(bytes[start..bytes.len().min(start + chunk_size)].to_vec())
+    });
+    std::iter::once(metadata)
+        .chain(data)
+        .chain(std::iter::once(commit))
+        .map(|chunk| UploadProfileChunk { chunk: Some(chunk) })
+}
+
+/// Server side state of one streamed upload
+pub struct UploadAssembler {
+    max_size: u64,
+    metadata: Option<ProfileMetadata>,
+    data: Vec<u8>,
+    hasher: Sha256,
+    started: bool,
+    committed: bool,
+}
+
+impl UploadAssembler {
+    /// Start an upload of at most `max_size` bytes of data
+    pub fn new(max_size: u64) -> Self {
+        UploadAssembler {
+            max_size,
+            metadata: None,
+            data: Vec::new(),
+            hasher: Sha256::new(),
+            started: false,
+            committed: false,
+        }
+    }
+
+    /// Add the next message of the stream
+    pub fn push(&mut self, chunk: UploadProfileChunk) -> Result<(), UploadError> {
+        if self.committed {
+            return Err(UploadError::AfterCommit);
+        }
+        match chunk.chunk {
+            Some(Chunk::Metadata(metadata)) => {
+                if self.started {
+                    return Err(UploadError::UnexpectedMetadata);
+                }
+                self.metadata = Some(metadata.into());
+            }
+            Some(Chunk::Data(data)) => {
+                if self.data.len() as u64 + data.len() as u64 > self.max_size {
+                    return Err(UploadError::TooLarge { limit: self.max_size });
+                }
+                self.hasher.update(&data);
+                self.data.extend_from_slice(&data);
+            }
+            Some(Chunk::Commit(commit)) => {
+                let received = self.data.len() as u64;
+                if commit.size != received {
+                    return Err(UploadError::SizeMismatch { expected: commit.size, received });
+                }
+                let checksum = format!("{:x}", self.hasher.finalize_reset());
+                if !commit.sha256.eq_ignore_ascii_case(&checksum) {
+                    return Err(UploadError::ChecksumMismatch { expected: commit.sha256, received: checksum });
+                }
+                self.committed = true;
+            }
+            None => {}
+        }
+        self.started = true;
+        Ok(())
+    }
+
+    /// The verified data and metadata, once the stream has ended
+    pub fn finish(self) -> Result<(Vec<u8>, ProfileMetadata), UploadError> {
+        if !self.committed {
+            return Err(UploadError::MissingCommit);
+        }
+        Ok((self.data, self.metadata.unwrap_or_default()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metadata() -> ProfileMetadata {
+        ProfileMetadata { service_name: "checkout".into(), ..Default::default() }
+    }
+
+    #[test]
+    fn decodes_plain_and_gzipped_profiles() {
+        let profile = Profile {
+            string_table: vec![String::new(), "samples".into()],
+            period: 10,
+            ..Default::default()
+        };
+        let plain = profile.encode_to_vec();
+        let gzipped = crate::export::to_gzipped_pprof(&profile).unwrap();
+        assert!(is_gzip(&gzipped) && !is_gzip(&plain));
+
+        assert_eq!(decode_profile(&plain).unwrap(), profile);
+        assert_eq!(decode_profile(&gzipped).unwrap(), profile);
+        assert!(matches!(decode_profile(&gzipped[..gzipped.len() / 2]), Err(DecodeError::Gzip(_))));
+    }
+
+    #[test]
+    fn reassembles_chunks_after_commit() {
+        let data: Vec<u8> = (0..10_000u32).map(|i| i as u8).collect();
+        let messages: Vec<_> = chunks(&data, metadata(), 4096).collect();
+        // Metadata, three data chunks, commit
+        assert_eq!(messages.len(), 5);
+
+        let mut upload = UploadAssembler::new(DEFAULT_MAX_UPLOAD_SIZE);
+        for message in messages {
+            upload.push(message).unwrap();
+        }
+        let (received, received_metadata) = upload.finish().unwrap();
+        assert_eq!(received, data);
+        assert_eq!(received_metadata.service_name, "checkout");
+    }
+
+    #[test]
+    fn rejects_corrupt_or_incomplete_uploads() {
+        let mut messages: Vec<_> = chunks(b"profile data", metadata(), 4).collect();
+        let commit = messages.pop().unwrap();
+
+        // Stream ended early
+        let mut upload = UploadAssembler::new(DEFAULT_MAX_UPLOAD_SIZE);
+        for message in messages.clone() {
+            upload.push(message).unwrap();
+        }
+        assert_eq!(upload.finish().err(), Some(UploadError::MissingCommit));
+
+        // A chunk went missing
+        let mut upload = UploadAssembler::new(DEFAULT_MAX_UPLOAD_SIZE);
+        for (i, message) in messages.iter().enumerate() {
+            if i != 2 {
+                upload.push(message.clone()).unwrap();
+            }
+        }
+        assert!(matches!(upload.push(commit.clone()), Err(UploadError::SizeMismatch { expected: 12, received: 8 })));
+
+        // A chunk was corrupted
+        let mut upload = UploadAssembler::new(DEFAULT_MAX_UPLOAD_SIZE);
+        for (i, message) in messages.iter().enumerate() {
+            let mut message = message.clone();
+            if i == 1 {
+                message.chunk = Some(Chunk::Data(b"PROF".to_vec()));
+            }
+            upload.push(message).unwrap();
+        }
+        assert!(matches!(upload.push(commit.clone()), Err(UploadError::ChecksumMismatch { .. })));
+
+        // Metadata after data
+        let mut upload = UploadAssembler::new(DEFAULT_MAX_UPLOAD_SIZE);
+        upload.push(messages[1].clone()).unwrap();
+        assert_eq!(upload.push(messages[0].clone()), Err(UploadError::UnexpectedMetadata));
+
+        // Over the size limit
+        let mut upload = UploadAssembler::new(10);
+        let pushed: Result<Vec<_>, _> = messages.into_iter().map(|message| upload.push(message)).collect();
+        assert_eq!(pushed, Err(UploadError::TooLarge { limit: 10 }));
+    }
+}
@@ -0,0 +1,454 @@
+//! Wall-clock profiler
+//!
+//! `pprof::ProfilerGuard` samples on `SIGPROF`, which only fires for threads
+//! burning CPU, so time spent sleeping, waiting on locks or blocked in I/O
+//! never shows up. [`WallClockGuard`] instead signals every thread of the
+//! process at a fixed frequency, whether it is running or blocked, and
+//! records where each one is. The result is a pprof profile with a `wall`
+//! sample type, stored with profile type `wall`.
+//!
+//! Sampling uses `SIGURG`, whose default action is to be ignored, so a
+//! signal arriving after the guard is dropped is harmless. The handler only
+//! walks the stack into a preallocated slot; a sampler thread aggregates the
+//! slots. Interrupted system calls are restarted (`SA_RESTART`) and the
+//! standard library retries sleeps and lock waits that return early.
+//!
+//! Each sample is labeled with its thread and the [`labels`](crate::labels)
+//! in effect on that thread when it was taken.
+//!
+//! Only Linux is supported, where threads can be listed through `/proc`.
+
+use crate::labels::{self, LabelSet, Recording};
+use crate::symbolize::ProfileBuilder;
+use pprof::protos::Profile;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Deepest stack recorded per sample
+const MAX_DEPTH: usize = 64;
+/// Samples that can be waiting for the sampler thread at once
+const SLOT_COUNT: usize = 1024;
+
+const FREE: u8 = 0;
+const WRITING: u8 = 1;
+const READY: u8 = 2;
+
+/// Stack captured by the signal handler
+struct Slot {
+    state: AtomicU8,
+    tid: AtomicI32,
+    /// `pthread_self` of the sampled thread, which labels are keyed by
+    thread: AtomicU64,
+    /// Unix nanoseconds
+    time: AtomicU64,
+    depth: AtomicUsize,
+    ips: [AtomicUsize; MAX_DEPTH],
+}
+
+/// Allocated on first use, never from the signal handler
+static SLOTS: OnceLock<Box<[Slot]>> = OnceLock::new();
+static NEXT_SLOT: AtomicUsize = AtomicUsize::new(0);
+/// Samples lost because every slot was full
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+/// Set while a guard is running
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// Errors raised when starting the wall-clock profiler
+#[derive(Debug)]
+pub enum WallClockError {
+    /// Another `WallClockGuard` is running
+    AlreadyRunning,
+    /// The frequency is not positive
+    InvalidFrequency(i32),
+    /// The platform can't list or signal threads
+    Unsupported,
+    /// Installing the signal handler or starting the sampler failed
+    Io(io::Error),
+}
+
+impl fmt::Display for WallClockError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WallClockError::AlreadyRunning => write!(f, "wall-clock profiler is already running"),
+            WallClockError::InvalidFrequency(hz) => write!(f, "invalid sampling frequency: {} Hz", hz),
+            WallClockError::Unsupported => write!(f, "wall-clock profiling is only supported on Linux"),
+            WallClockError::Io(e) => write!(f, "io error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for WallClockError {}
+
+impl From<io::Error> for WallClockError {
+    fn from(e: io::Error) -> Self {
+        WallClockError::Io(e)
+    }
+}
+
+extern "C" fn on_signal(_signal: libc::c_int, _info: *mut libc::siginfo_t, _context: *mut libc::c_void) {
+    let Some(slots) = SLOTS.get() else {
+        return;
+    };
+    let errno = io::Error::last_os_error().raw_os_error();
+
+    let slot = &slots[NEXT_SLOT.fetch_add(1, Ordering::Relaxed) % slots.len()];
+    if slot.state.compare_exchange(FREE, WRITING, Ordering::Acquire, Ordering::Relaxed).is_err() {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+    let mut depth = 0;
+    // SAFETY: this is what pprof's own SIGPROF handler does; the closure
+    // doesn't allocate or take locks
+    unsafe {
+        backtrace::trace_unsynchronized(|frame| {
+            slot.ips[depth].store(frame.ip() as usize, Ordering::Relaxed);
+            depth += 1;
+            depth < MAX_DEPTH
+        });
+    }
+    slot.tid.store(current_tid(), Ordering::Relaxed);
+    slot.thread.store(labels::thread_id(), Ordering::Relaxed);
+    let time = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos() as u64);
+    slot.time.store(time, Ordering::Relaxed);
+    slot.depth.store(depth, Ordering::Relaxed);
+    slot.state.store(READY, Ordering::Release);
+
+    // The interrupted code may be about to read errno
+    if let Some(errno) = errno {
+        set_errno(errno);
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn set_errno(errno: i32) {
+    // SAFETY: errno is thread local
+    unsafe { *libc::__errno_location() = errno };
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_errno(_errno: i32) {}
+
+#[cfg(target_os = "linux")]
+fn current_tid() -> i32 {
+    // SAFETY: gettid has no preconditions and is async-signal-safe
+    unsafe { libc::syscall(libc::SYS_gettid) as i32 }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn current_tid() -> i32 {
+    0
+}
+
+/// IDs of every thread in the process
+#[cfg(target_os = "linux")]
+fn list_threads() -> io::Result<HashSet<i32>> {
+    let mut threads = HashSet::new();
+    for entry in std::fs::read_dir("/proc/self/task")? {
+        if let Some(tid) = entry?.file_name().to_str().and_then(|s| s.parse().ok()) {
+            threads.insert(tid);
+        }
+    }
+    Ok(threads)
+}
+
+/// Name of a thread of the process, empty if it already exited
+#[cfg(target_os = "linux")]
+fn thread_name(tid: i32) -> String {
+    std::fs::read_to_string(format!("/proc/self/task/{}/comm", tid))
+        .map(|name| name.trim_end().to_string())
+        .unwrap_or_default()
+}
+
+#[cfg(target_os = "linux")]
+fn signal_thread(tid: i32) {
+    // SAFETY: tgkill only sends a signal; a thread that exited in the
+    // meantime makes it fail with ESRCH, which is fine to ignore
+    unsafe {
+        libc::syscall(libc::SYS_tgkill, libc::getpid(), tid, libc::SIGURG);
+    }
+}
+
+/// Install the handler, returning the action it replaced
+#[cfg(target_os = "linux")]
+fn install_handler() -> io::Result<libc::sigaction> {
+    // SAFETY: the handler is async-signal-safe and both structs are
+    // fully initialized before use
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = on_signal as *const () as usize;
+        action.sa_flags = libc::SA_SIGINFO | libc::SA_RESTART;
+        libc::sigemptyset(&mut action.sa_mask);
+        let mut previous: libc::sigaction = std::mem::zeroed();
+        if libc::sigaction(libc::SIGURG, &action, &mut previous) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(previous)
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn restore_handler(previous: &libc::sigaction) {
+    // SAFETY: `previous` was filled in by `sigaction`
+    unsafe {
+        libc::sigaction(libc::SIGURG, previous, std::ptr::null_mut());
+    }
+}
+
+/// Where and under which labels a sample was taken
+#[derive(PartialEq, Eq, Hash)]
+struct SampleKey {
+    tid: i32,
+    thread: u64,
+    /// Leaf first
+    ips: Vec<usize>,
+    labels: Option<Arc<LabelSet>>,
+}
+
+/// Stack counts gathered so far
+struct Samples {
+    counts: HashMap<SampleKey, i64>,
+    thread_names: HashMap<i32, String>,
+    recording: Recording,
+}
+
+/// Move every captured stack out of the slots
+fn drain(samples: &Mutex<Samples>) {
+    let Some(slots) = SLOTS.get() else {
+        return;
+    };
+    let mut samples = samples.lock().unwrap();
+    for slot in slots.iter() {
+        if slot.state.load(Ordering::Acquire) != READY {
+            continue;
+        }
+        let depth = slot.depth.load(Ordering::Relaxed);
+        let ips = slot.ips[..depth].iter().map(|ip| ip.load(Ordering::Relaxed)).collect();
+        let tid = slot.tid.load(Ordering::Relaxed);
+        let thread = slot.thread.load(Ordering::Relaxed);
+        let time = UNIX_EPOCH + Duration::from_nanos(slot.time.load(Ordering::Relaxed));
+        slot.state.store(FREE, Ordering::Release);
+        let labels = samples.recording.labels_at(thread, time);
+        *samples.counts.entry(SampleKey { tid, thread, ips, labels }).or_default() += 1;
+    }
+}
+
+/// Samples every thread's stack until dropped, see the module docs
+///
+/// Like `pprof::ProfilerGuard`, only one guard can run at a time.
+pub struct WallClockGuard {
+    frequency: i32,
+    start: SystemTime,
+    samples: Arc<Mutex<Samples>>,
+    stop: Arc<AtomicBool>,
+    sampler: Option<JoinHandle<()>>,
+    #[cfg(target_os = "linux")]
+    previous: libc::sigaction,
+}
+
+impl WallClockGuard {
+    /// Start sampling all threads `frequency` times per second
+    #[cfg(target_os = "linux")]
+    pub fn new(frequency: i32) -> Result<Self, WallClockError> {
+        if frequency <= 0 {
+            return Err(WallClockError::InvalidFrequency(frequency));
+        }
+        if ACTIVE.swap(true, Ordering::AcqRel) {
+            return Err(WallClockError::AlreadyRunning);
+        }
+        SLOTS.get_or_init(|| {
+            (0..SLOT_COUNT)
+                .map(|_| Slot {
+                    state: AtomicU8::new(FREE),
+                    tid: AtomicI32::new(0),
+                    thread: AtomicU64::new(0),
+                    time: AtomicU64::new(0),
+                    depth: AtomicUsize::new(0),
+                    ips: std::array::from_fn(|_| AtomicUsize::new(0)),
+                })
+                .collect()
+        });
+        DROPPED.store(0, Ordering::Relaxed);
+
+        let previous = match install_handler() {
+            Ok(previous) => previous,
+            Err(e) => {
+                ACTIVE.store(false, Ordering::Release);
+                return Err(e.into());
+            }
+        };
+
+        let samples = Arc::new(Mutex::new(Samples {
+            counts: HashMap::new(),
+            thread_names: HashMap::new(),
+            recording: Recording::start(),
+        }));
+        let stop = Arc::new(AtomicBool::new(false));
+        let period = Duration::from_secs(1) / frequency as u32;
+        let sampler = {
+            let samples = samples.clone();
+            let stop = stop.clone();
+            thread::Builder::new()
+                .name("wallclock-sampler".into())
+                .spawn(move || {
+                    let own_tid = current_tid();
+                    // Threads seen on the previous tick; only new ones have
+                    // their name read, so a reused tid gets its new name
+                    let mut known = HashSet::new();
+                    while !stop.load(Ordering::Relaxed) {
+                        drain(&samples);
+                        match list_threads() {
+                            Ok(mut threads) => {
+                                threads.remove(&own_tid);
+                                let mut samples = samples.lock().unwrap();
+                                for &tid in &threads {
+                                    signal_thread(tid);
+                                    if !known.contains(&tid) {
+                                        samples.thread_names.insert(tid, thread_name(tid));
+                                    }
+                                }
+                                known = threads;
+                            }
+                            Err(e) => log::warn!("Failed to list threads: {}", e),
+                        }
+                        thread::sleep(period);
+                    }
+                    drain(&samples);
+                })
+        };
+        let sampler = match sampler {
+            Ok(sampler) => sampler,
+            Err(e) => {
+                restore_handler(&previous);
+                ACTIVE.store(false, Ordering::Release);
+                return Err(e.into());
+            }
+        };
+
+        Ok(WallClockGuard {
+            frequency,
+            start: SystemTime::now(),
+            samples,
+            stop,
+            sampler: Some(sampler),
+            previous,
+        })
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn new(_frequency: i32) -> Result<Self, WallClockError> {
+        Err(WallClockError::Unsupported)
+    }
+
+    /// Number of samples lost because the sampler fell behind
+    pub fn dropped(&self) -> u64 {
+        DROPPED.load(Ordering::Relaxed)
+    }
+
+    /// Build a pprof profile of the stacks sampled so far
+    ///
+    /// Each sample stands for one sampling period of wall time on one
+    /// thread, and carries `thread` and `thread_id` labels plus the user
+    /// labels in effect when it was taken.
+    pub fn report(&self) -> Profile {
+        drain(&self.samples);
+        let period = 1_000_000_000 / self.frequency as i64;
+        let mut builder = ProfileBuilder::new(
+            &[("samples", "count"), ("wall", "nanoseconds")],
+            ("wall", "nanoseconds"),
+            period,
+        );
+        builder.default_sample_type("wall");
+
+        let samples = self.samples.lock().unwrap();
+        for (key, count) in &samples.counts {
+            let thread = samples.thread_names.get(&key.tid)
+                .filter(|name| !name.is_empty())
+                .cloned()
+                .unwrap_or_else(|| key.tid.to_string());
+            let thread_id = key.thread.to_string();
+            let mut labels = vec![("thread", thread.as_str()), ("thread_id", thread_id.as_str())];
+            labels.extend(key.labels.iter().flat_map(|set| set.iter()).map(|(k, v)| (k.as_str(), v.as_str())));
+            builder.add_sample(&key.ips, is_profiler_frame, true, vec![*count, count * period], &labels);
+        }
+        builder.finish(self.start)
+    }
+}
+
+impl Drop for WallClockGuard {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(sampler) = self.sampler.take() {
+            if sampler.join().is_err() {
+                log::error!("Wall-clock sampler thread panicked");
+            }
+        }
+        #[cfg(target_os = "linux")]
+        restore_handler(&self.previous);
+        ACTIVE.store(false, Ordering::Release);
+    }
+}
+
+/// Whether a frame belongs to the signal handler rather than the
+/// interrupted thread
+fn is_profiler_frame(name: &str) -> bool {
+    name.contains("wallclock::on_signal") || name.contains("__restore_rt")
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use crate::flamegraph::{group_by_label, sample_column, sample_label, FlameGraphData, FlameGraphNode};
+    use crate::labels::with_labels;
+    use std::sync::mpsc;
+
+    #[inline(never)]
+    fn wait_for_message(rx: mpsc::Receiver<()>) {
+        let _ = rx.recv();
+    }
+
+    fn contains(nodes: &[FlameGraphNode], function: &str) -> bool {
+        nodes.iter().any(|n| n.name.contains(function) || contains(&n.children, function))
+    }
+
+    #[test]
+    fn samples_blocked_threads() {
+        let (tx, rx) = mpsc::channel();
+        let (ready_tx, ready_rx) = mpsc::channel();
+        let blocked = thread::Builder::new()
+            .name("blocked-worker".into())
+            .spawn(move || with_labels(&[("job", "waiting")], || {
+                ready_tx.send(()).unwrap();
+                wait_for_message(rx)
+            }))
+            .unwrap();
+        ready_rx.recv().unwrap();
+
+        let guard = WallClockGuard::new(200).unwrap();
+        assert!(matches!(WallClockGuard::new(200), Err(WallClockError::AlreadyRunning)));
+        thread::sleep(Duration::from_millis(300));
+        let profile = guard.report();
+        drop(guard);
+        tx.send(()).unwrap();
+        blocked.join().unwrap();
+
+        assert_eq!(crate::metadata::infer_profile_type(&profile), "wall");
+        let wall = FlameGraphData::from_profile_column(&profile, sample_column(&profile, None).unwrap());
+        assert_eq!(wall.sample_type, "wall");
+        assert!(contains(&wall.children, "wait_for_message"), "blocked thread was not sampled");
+        assert!(!contains(&wall.children, "on_signal"));
+
+        let by_thread = group_by_label(&profile, 1, "thread", |_| true);
+        assert!(contains(&by_thread["blocked-worker"].children, "wait_for_message"));
+        let blocked = profile.sample.iter()
+            .filter(|sample| sample_label(&profile, sample, "thread").as_deref() == Some("blocked-worker"));
+        for sample in blocked {
+            assert_eq!(sample_label(&profile, sample, "job").as_deref(), Some("waiting"));
+            assert!(sample_label(&profile, sample, "thread_id").is_some());
+        }
+    }
+}